@@ -7,7 +7,7 @@ extern crate quickcheck;
 #[path = "../src/bit_iterator.rs"] mod bit_iterator;
 #[path = "../src/packet.rs"] mod packet;
 use test::Bencher;
-use packet::{Packet, Encodable, Decodable};
+use packet::{Packet, PacketRef, Encodable, Decodable};
 
 #[bench]
 fn bench_decode(b: &mut Bencher) {
@@ -27,3 +27,23 @@ fn bench_encode(b: &mut Bencher) {
         let _ = test::black_box(packet.to_bytes());
     });
 }
+
+#[bench]
+fn bench_decode_in_place(b: &mut Bencher) {
+    let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+               0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79,
+               0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a];
+    b.iter(|| { let _ = PacketRef::from_bytes(&buf); });
+}
+
+#[bench]
+fn bench_encode_into(b: &mut Bencher) {
+    let payload = [1, 2, 3, 4, 5, 6];
+    let mut packet = Packet::new();
+    packet.payload = payload.to_vec();
+    let mut buf = [0; 1500];
+
+    b.iter(|| {
+        let _ = test::black_box(packet.encode_into(&mut buf));
+    });
+}
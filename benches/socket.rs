@@ -4,9 +4,12 @@ extern crate test;
 extern crate utp;
 
 use test::Bencher;
-use utp::UtpSocket;
+use utp::{Transport, UtpSocket};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 macro_rules! iotry {
     ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{}", e) })
@@ -106,3 +109,61 @@ fn bench_transfer_one_megabyte(b: &mut Bencher) {
     });
     b.bytes = len as u64;
 }
+
+/// A UDP socket sending and receiving one datagram per system call, as `Transport`'s default
+/// `send_batch` and `recv_batch` do.
+struct Unbatched(UdpSocket);
+
+impl Transport for Unbatched {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.0.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.peek_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+}
+
+#[bench]
+fn bench_transfer_one_megabyte_unbatched(b: &mut Bencher) {
+    let len = 1024 * 1024;
+    let server_addr = next_test_ip4();
+    let mut buf = [0; 1500];
+    let data = (0..len).map(|x| x as u8).collect::<Vec<u8>>();
+    let data_arc = Arc::new(data);
+
+    b.iter(|| {
+        let data = data_arc.clone();
+        let udp = iotry!(UdpSocket::bind(server_addr));
+        let mut server = iotry!(UtpSocket::from_transport(Unbatched(udp)));
+
+        thread::spawn(move || {
+            let udp = Unbatched(iotry!(UdpSocket::bind(("127.0.0.1", 0))));
+            let mut client = iotry!(UtpSocket::connect_with_transport(udp, server_addr));
+            iotry!(client.send_to(&data[..]));
+            iotry!(client.close());
+        });
+
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok(_) => (),
+                Err(e) => panic!("{}", e)
+            }
+        }
+        iotry!(server.close());
+    });
+    b.bytes = len as u64;
+}
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Decodes arbitrary datagrams, and checks that valid packets survive a round trip
+fuzz_target!(|data: &[u8]| {
+    utp::fuzzing::packet_codec(data);
+});
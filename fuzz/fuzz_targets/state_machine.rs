@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Feeds a connection with a sequence of packets from a misbehaving remote peer, checking that it
+// never panics, delivers data in order, and keeps track of the bytes in flight
+fuzz_target!(|data: &[u8]| {
+    utp::fuzzing::state_machine(data);
+});
@@ -0,0 +1,477 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use error::{Error, Result};
+use extension::{ExtensionHandler, ExtensionRegistry};
+use packet::{PacketRef, PacketType};
+use rate::RateLimiter;
+use socket::{UtpSocket, resolve, resolve_all};
+use happy_eyeballs;
+use transport::{Transport, Queue};
+use rand;
+
+/// How long the dispatcher waits for a datagram before checking whether the context is still in
+/// use.
+const DISPATCH_POLL_INTERVAL: u64 = 100; // milliseconds
+/// Largest datagram the dispatcher receives whole.
+const MAX_DATAGRAM: usize = 1 << 16;
+/// Most connection requests waiting to be accepted, beyond which new ones are dropped.
+const ACCEPT_BACKLOG: usize = 128;
+/// Most datagrams waiting to be received by a connection, beyond which new ones are dropped (like
+/// a full UDP receive buffer would).
+const CONNECTION_QUEUE_LEN: usize = 1024;
+
+/// A single local port shared by every connection accepted or opened through it.
+///
+/// Sockets created by `UtpSocket::connect` or accepted by `UtpListener` each get a UDP socket of
+/// their own, so remote peers see a different port for every connection. A context instead owns
+/// one UDP socket, and a background thread hands each incoming datagram to the connection it
+/// belongs to, according to its source address and connection id. Connection requests are left
+/// for `accept`. Like any other socket, those connections turn into streams with `into()`.
+///
+/// The UDP socket is closed once the context and every connection it created are dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::UtpContext;
+/// use std::thread;
+///
+/// let context = UtpContext::bind("0.0.0.0:6881").unwrap();
+///
+/// // Outgoing connections come from port 6881, where incoming ones are accepted too
+/// let mut socket = context.connect("192.0.2.1:6881").unwrap();
+/// thread::spawn(move || {
+///     socket.send_to(b"hello").unwrap();
+/// });
+///
+/// let (mut socket, _src) = context.accept().unwrap();
+/// ```
+pub struct UtpContext {
+    shared: Arc<Shared>,
+
+    /// Handlers of the extensions negotiated with every connection
+    extensions: ExtensionRegistry,
+
+    /// Bandwidth limits shared by every connection, if any
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// The part of a context shared with its connections and the dispatcher thread.
+struct Shared {
+    transport: Arc<dyn Transport>,
+    routes: Mutex<Routes>,
+
+    /// Signalled when a connection request arrives
+    syn_ready: Condvar,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// Datagrams waiting for each connection, by remote peer address and connection id
+    connections: HashMap<(SocketAddr, u16), Arc<Queue>>,
+
+    /// Address each connection opened by the context was sent to, by connection id, until the
+    /// reply to its request arrives. The reply may come from another address (e.g., a
+    /// `UtpListener` answers from a new port), so only the connection id tells it apart.
+    connecting: HashMap<u16, SocketAddr>,
+
+    /// Connection requests waiting to be accepted, with the connection id they ask for
+    syns: VecDeque<(Vec<u8>, SocketAddr, u16)>,
+}
+
+impl UtpContext {
+    /// Creates a context with a UDP socket bound to the given address.
+    ///
+    /// If more than one valid address is specified, only the first will be used.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpContext> {
+        let addr = try!(resolve(addr));
+        let socket = try!(UdpSocket::bind(addr));
+        UtpContext::from_transport(socket)
+    }
+
+    /// Creates a context on top of the given datagram transport, instead of binding a new UDP
+    /// socket.
+    ///
+    /// The context takes over the transport's read timeout, and must be the only one receiving
+    /// from it.
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Result<UtpContext> {
+        try!(transport.set_read_timeout(Some(Duration::from_millis(DISPATCH_POLL_INTERVAL))));
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let shared = Arc::new(Shared {
+            transport: transport.clone(),
+            routes: Mutex::new(Routes::default()),
+            syn_ready: Condvar::new(),
+        });
+
+        let weak = Arc::downgrade(&shared);
+        try!(thread::Builder::new()
+             .name("utp-context".to_string())
+             .spawn(move || dispatch(transport, weak)));
+
+        Ok(UtpContext {
+            shared,
+            extensions: ExtensionRegistry::default(),
+            rate_limiter: None,
+        })
+    }
+
+    /// Opens a connection to a remote peer from the context's port.
+    ///
+    /// Every address the host resolves to is tried, as in `UtpSocket::connect`.
+    pub fn connect<A: ToSocketAddrs>(&self, other: A) -> Result<UtpSocket> {
+        let addrs = try!(resolve_all(other));
+        let local_addr = try!(self.local_addr());
+        let shared = self.shared.clone();
+        let extensions = self.extensions.clone();
+        let rate_limiter = self.rate_limiter.clone();
+
+        happy_eyeballs::connect(addrs, move |addr, abandoned| {
+            // The reply may come from another address, so the connection id must not be used
+            // by any other connection
+            let (transport, connection_id) = {
+                let mut routes = shared.lock();
+                let connection_id = loop {
+                    let id = rand::random::<u16>();
+                    if !routes.connecting.contains_key(&id) &&
+                        !routes.connections.keys().any(|&(_, other)| other == id) {
+                        break id;
+                    }
+                };
+                routes.connecting.insert(connection_id, addr);
+                (shared.register(&mut routes, addr, connection_id), connection_id)
+            };
+
+            let mut socket = UtpSocket::with_connection_id(Arc::new(transport), local_addr,
+                                                           connection_id);
+            socket.set_extensions(extensions.clone());
+            socket.set_rate_limiter(rate_limiter.clone());
+            socket.connect_unless(addr, abandoned)
+        })
+    }
+
+    /// Accepts a new incoming connection on the context's port, blocking until one arrives.
+    ///
+    /// Returns the connected socket along with the remote peer's address.
+    pub fn accept(&self) -> Result<(UtpSocket, SocketAddr)> {
+        loop {
+            let (syn, src, transport) = {
+                let mut routes = self.shared.lock();
+                let (syn, src, connection_id) = loop {
+                    match routes.syns.pop_front() {
+                        Some(request) => break request,
+                        None => {
+                            routes = self.shared.syn_ready.wait(routes)
+                                .unwrap_or_else(|e| e.into_inner());
+                        }
+                    }
+                };
+                // A connection of ours may have taken the connection id in the meantime
+                if routes.connections.contains_key(&(src, connection_id)) {
+                    continue;
+                }
+                let transport = self.shared.register(&mut routes, src, connection_id);
+                (syn, src, transport)
+            };
+
+            let packet = try!(PacketRef::from_bytes(&syn));
+            let socket = try!(UtpSocket::accept(Arc::new(transport), &packet, src,
+                                                &self.extensions, self.rate_limiter.clone()));
+            return Ok((socket, src));
+        }
+    }
+
+    /// Adds a handler for an extension to negotiate with the remote peer of every connection
+    /// opened or accepted from now on. See `UtpSocket::register_extension`.
+    pub fn register_extension<H: ExtensionHandler + 'static>(&mut self, handler: H) {
+        self.extensions.register(Arc::new(handler));
+    }
+
+    /// Makes the connections opened or accepted from now on share the limits of `limiter` (see
+    /// `UtpSocket::set_rate_limiter`), or not share any limits if `None`.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// Returns the local address shared by every connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.shared.transport.local_addr().map_err(Error::from)
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Routes> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Creates the transport of a connection with the remote peer at `addr`, which receives the
+    /// datagrams carrying `connection_id`.
+    fn register(self: &Arc<Self>, routes: &mut Routes, addr: SocketAddr, connection_id: u16)
+                -> ConnectionTransport {
+        let incoming = Arc::new(Queue::new());
+        routes.connections.insert((addr, connection_id), incoming.clone());
+        ConnectionTransport {
+            shared: self.clone(),
+            key: (addr, connection_id),
+            incoming,
+            read_timeout: Mutex::new(None),
+        }
+    }
+
+    /// Hands a datagram over to the connection it belongs to, or queues it for `accept` if it's a
+    /// request for a new connection. Anything else is dropped.
+    fn route(&self, datagram: &[u8], src: SocketAddr) {
+        let packet = match PacketRef::from_bytes(datagram) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Ignoring invalid packet from {}: {}", src, e);
+                return;
+            }
+        };
+
+        // A connection request carries the id of the connection's packets in the other direction
+        let connection_id = match packet.get_type() {
+            PacketType::Syn => packet.connection_id().wrapping_add(1),
+            _ => packet.connection_id(),
+        };
+
+        let mut routes = self.lock();
+        if packet.get_type() != PacketType::Syn {
+            // The reply to a connection request moves the connection to its source address
+            let requested = routes.connecting.get(&connection_id).cloned();
+            match requested {
+                Some(dst) if dst == src => {
+                    routes.connecting.remove(&connection_id);
+                },
+                Some(dst) if !routes.connections.contains_key(&(src, connection_id)) => {
+                    routes.connecting.remove(&connection_id);
+                    if let Some(queue) = routes.connections.remove(&(dst, connection_id)) {
+                        debug!("Connection {} to {} answered from {}", connection_id, dst, src);
+                        routes.connections.insert((src, connection_id), queue);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        if let Some(queue) = routes.connections.get(&(src, connection_id)) {
+            if queue.len() < CONNECTION_QUEUE_LEN {
+                queue.push(datagram.to_vec(), src);
+            }
+            return;
+        }
+
+        // Retransmitted requests are only accepted once
+        if packet.get_type() == PacketType::Syn && routes.syns.len() < ACCEPT_BACKLOG &&
+            !routes.syns.iter().any(|&(_, from, id)| from == src && id == connection_id) {
+            routes.syns.push_back((datagram.to_vec(), src, connection_id));
+            self.syn_ready.notify_one();
+        } else {
+            debug!("Dropping {:?} packet from {} for unknown connection {}",
+                   packet.get_type(), src, connection_id);
+        }
+    }
+}
+
+/// Receives datagrams from the context's transport and routes them, until the context and its
+/// connections are gone.
+fn dispatch(transport: Arc<dyn Transport>, shared: Weak<Shared>) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let received = transport.recv_from(&mut buf);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        match received {
+            Ok((len, src)) => shared.route(&buf[..len], src),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            Err(e) => debug!("Failed to receive datagram: {}", e),
+        }
+    }
+}
+
+/// The transport of a connection opened or accepted by a context, which sends through the
+/// context's transport and receives the datagrams routed to the connection.
+struct ConnectionTransport {
+    shared: Arc<Shared>,
+    key: (SocketAddr, u16),
+    incoming: Arc<Queue>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl ConnectionTransport {
+    fn read_timeout(&self) -> Option<Duration> {
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Transport for ConnectionTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.shared.transport.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.incoming.receive(buf, true, self.read_timeout())
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.incoming.receive(buf, false, self.read_timeout())
+    }
+
+    fn send_batch(&self, bufs: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+        self.shared.transport.send_batch(bufs, addr)
+    }
+
+    fn recv_batch(&self, bufs: &mut [&mut [u8]], received: &mut [(usize, SocketAddr)])
+                  -> io::Result<usize> {
+        self.incoming.receive_batch(bufs, received, self.read_timeout())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.transport.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Zero timeout"));
+        }
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    fn now(&self) -> Instant {
+        self.shared.transport.now()
+    }
+
+    fn now_microseconds(&self) -> u32 {
+        self.shared.transport.now_microseconds()
+    }
+}
+
+impl Drop for ConnectionTransport {
+    fn drop(&mut self) {
+        // The route may have moved to another address since it was registered
+        let mut routes = self.shared.lock();
+        let (addr, connection_id) = self.key;
+        if routes.connecting.get(&connection_id) == Some(&addr) {
+            routes.connecting.remove(&connection_id);
+        }
+        let incoming = &self.incoming;
+        routes.connections.retain(|_, queue| !Arc::ptr_eq(queue, incoming));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::net::{Shutdown, SocketAddr};
+    use socket::{UtpListener, UtpSocket};
+    use super::UtpContext;
+
+    macro_rules! iotry {
+        ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{:?}", e) })
+    }
+
+    /// Reads everything the remote peer sends, until it closes the connection.
+    fn read_to_end(socket: &mut UtpSocket) -> Vec<u8> {
+        let mut buf = [0; 1500];
+        let mut received = Vec::new();
+        loop {
+            match iotry!(socket.recv_from(&mut buf)) {
+                (0, _src) => return received,
+                (read, _src) => received.extend_from_slice(&buf[..read]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_connections_share_one_port() {
+        let server = iotry!(UtpContext::bind("127.0.0.1:0"));
+        let client = iotry!(UtpContext::bind("127.0.0.1:0"));
+        let server_addr = iotry!(server.local_addr());
+        let client_addr = iotry!(client.local_addr());
+
+        // Two connections from the same port, told apart by their connection id, while the
+        // client accepts a connection on that port too
+        let child = thread::spawn(move || {
+            let mut first = iotry!(client.connect(server_addr));
+            let mut second = iotry!(client.connect(server_addr));
+            iotry!(second.send_to(b"second"));
+            iotry!(first.send_to(b"first"));
+            iotry!(first.close());
+            iotry!(second.close());
+
+            let (mut incoming, src) = iotry!(client.accept());
+            assert_eq!(src, server_addr);
+            assert_eq!(read_to_end(&mut incoming), b"reverse");
+            iotry!(incoming.close());
+        });
+
+        let mut accepted: Vec<(UtpSocket, SocketAddr)> =
+            (0..2).map(|_| iotry!(server.accept())).collect();
+        let mut received = Vec::new();
+        for &mut (ref mut socket, src) in accepted.iter_mut() {
+            assert_eq!(src, client_addr);
+            received.push(read_to_end(socket));
+            iotry!(socket.close());
+        }
+        received.sort();
+        assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+
+        let mut outgoing = iotry!(server.connect(client_addr));
+        iotry!(outgoing.send_to(b"reverse"));
+        iotry!(outgoing.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_to_listener() {
+        // A listener answers from a new port for every connection it accepts
+        let listener = iotry!(UtpListener::bind("127.0.0.1:0"));
+        let listener_addr = iotry!(listener.local_addr());
+        let child = thread::spawn(move || {
+            let (mut socket, _src) = iotry!(listener.accept());
+            assert_eq!(read_to_end(&mut socket), b"hello");
+            iotry!(socket.send_to(b"world"));
+            iotry!(socket.close());
+        });
+
+        let context = iotry!(UtpContext::bind("127.0.0.1:0"));
+        let mut socket = iotry!(context.connect(listener_addr));
+        iotry!(socket.send_to(b"hello"));
+        iotry!(socket.shutdown(Shutdown::Write));
+        assert_eq!(read_to_end(&mut socket), b"world");
+        iotry!(socket.close());
+        child.join().unwrap();
+
+        drop(socket);
+        let routes = context.shared.lock();
+        assert!(routes.connections.is_empty() && routes.connecting.is_empty());
+    }
+
+    #[test]
+    fn test_routes_are_removed_with_connections() {
+        let server = iotry!(UtpContext::bind("127.0.0.1:0"));
+        let client = iotry!(UtpContext::bind("127.0.0.1:0"));
+        let server_addr = iotry!(server.local_addr());
+
+        let child = thread::spawn(move || {
+            let mut socket = iotry!(client.connect(server_addr));
+            iotry!(socket.close());
+            drop(socket);
+            assert!(client.shared.lock().connections.is_empty());
+        });
+
+        let (mut socket, _src) = iotry!(server.accept());
+        assert_eq!(server.shared.lock().connections.len(), 1);
+        assert!(read_to_end(&mut socket).is_empty());
+        iotry!(socket.close());
+        drop(socket);
+        assert!(server.shared.lock().connections.is_empty());
+        child.join().unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::result;
+use packet::{PacketType, ParseError};
+
+/// A specialized `Result` type for uTP operations.
+pub type Result<T> = result::Result<T, Error>;
+
+/// An error on a uTP socket, stream or listener.
+///
+/// It converts into an `io::Error` with a matching `io::ErrorKind` (see `Error::kind`), keeping
+/// the original error as its inner error.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A time limit was reached.
+    TimedOut(Timeout),
+    /// The remote peer reset the connection.
+    ConnectionReset,
+    /// The remote peer refused the connection, replying to the connection request with a reset.
+    ConnectionRefused,
+    /// The network reported the remote peer as unreachable (e.g., with an ICMP port or host
+    /// unreachable message), which abandons the connection request or closes the connection.
+    ///
+    /// The inner error tells why (e.g., `io::ErrorKind::ConnectionRefused` when nothing listens
+    /// on the remote port). These errors are only reported on Linux.
+    Unreachable(io::Error),
+    /// The socket isn't connected, or the connection is closed.
+    NotConnected,
+    /// The socket is already connected, so it can't open another connection.
+    AlreadyConnected,
+    /// A datagram that isn't a valid uTP packet was received where one was expected.
+    InvalidPacket(ParseError),
+    /// The remote peer sent a packet of a type that isn't allowed at this point of the connection
+    /// (e.g., data in reply to a connection request).
+    ProtocolViolation(PacketType),
+    /// A message is larger than the maximum message size (see `UtpSocket::set_max_message_size`).
+    MessageTooLarge(usize),
+    /// The stream ended in the middle of a message.
+    TruncatedMessage,
+    /// An address couldn't be resolved, or resolved to no address at all.
+    AddressResolution(io::Error),
+    /// Connecting to every address a host resolved to failed, with the error of each attempt in
+    /// the order they were made (see `UtpSocket::connect`).
+    AllAttemptsFailed(Vec<(SocketAddr, Error)>),
+    /// The underlying transport failed.
+    Io(io::Error),
+}
+
+/// The time limit reached by an operation that failed with `Error::TimedOut`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Timeout {
+    /// The remote peer couldn't be reached while opening a connection.
+    Connect,
+    /// Nothing was heard from the remote peer for too long (see `UtpSocket::set_idle_timeout`).
+    Idle,
+    /// The connection couldn't be closed within the linger timeout (see `UtpSocket::set_linger`).
+    Linger,
+    /// The remote peer stopped acknowledging the packets sent to it, however many times they
+    /// were sent again.
+    Retransmit,
+}
+
+impl Error {
+    /// Returns the `io::ErrorKind` this error converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::TimedOut(_) => io::ErrorKind::TimedOut,
+            Error::ConnectionReset => io::ErrorKind::ConnectionReset,
+            Error::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            Error::Unreachable(ref e) => e.kind(),
+            Error::NotConnected => io::ErrorKind::NotConnected,
+            Error::AlreadyConnected => io::ErrorKind::AlreadyExists,
+            Error::InvalidPacket(_) | Error::ProtocolViolation(_) => io::ErrorKind::InvalidData,
+            Error::MessageTooLarge(_) => io::ErrorKind::InvalidData,
+            Error::TruncatedMessage => io::ErrorKind::UnexpectedEof,
+            Error::AddressResolution(_) => io::ErrorKind::InvalidInput,
+            Error::Io(ref e) => e.kind(),
+            // Timeouts say the least about why connecting failed
+            Error::AllAttemptsFailed(ref errors) => {
+                errors.iter().map(|(_, e)| e.kind())
+                    .find(|&kind| kind != io::ErrorKind::TimedOut)
+                    .unwrap_or(io::ErrorKind::TimedOut)
+            },
+        }
+    }
+
+    /// Returns an error for an address that resolved to nothing.
+    pub(crate) fn no_address() -> Error {
+        Error::AddressResolution(io::Error::new(io::ErrorKind::InvalidInput,
+                                                "No address to connect to"))
+    }
+}
+
+/// Returns whether a transport error means that the remote peer can't be reached.
+pub(crate) fn is_unreachable(error: &io::Error) -> bool {
+    matches!(error.kind(),
+             io::ErrorKind::ConnectionRefused | io::ErrorKind::HostUnreachable |
+             io::ErrorKind::NetworkUnreachable)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TimedOut(Timeout::Connect) => write!(f, "The remote peer could not be reached"),
+            Error::TimedOut(Timeout::Idle) => {
+                write!(f, "Nothing was heard from the remote peer for too long")
+            },
+            Error::TimedOut(Timeout::Linger) => {
+                write!(f, "Timed out while closing the connection")
+            },
+            Error::TimedOut(Timeout::Retransmit) => {
+                write!(f, "The remote peer stopped acknowledging packets")
+            },
+            Error::ConnectionReset => write!(f, "Connection reset by remote peer"),
+            Error::ConnectionRefused => write!(f, "Connection refused by remote peer"),
+            Error::Unreachable(ref e) => write!(f, "The remote peer is unreachable: {}", e),
+            Error::NotConnected => write!(f, "The socket is closed"),
+            Error::AlreadyConnected => write!(f, "The socket is already connected"),
+            Error::InvalidPacket(ref e) => write!(f, "Invalid packet: {}", e),
+            Error::ProtocolViolation(ty) => {
+                write!(f, "The remote peer sent an unexpected {:?} packet", ty)
+            },
+            Error::MessageTooLarge(len) => write!(f, "Message of {} bytes is too large", len),
+            Error::TruncatedMessage => write!(f, "The stream ended in the middle of a message"),
+            Error::AddressResolution(ref e) => write!(f, "Could not resolve address: {}", e),
+            Error::Io(ref e) => e.fmt(f),
+            Error::AllAttemptsFailed(ref errors) => {
+                try!(write!(f, "Every connection attempt failed"));
+                for (i, &(addr, ref e)) in errors.iter().enumerate() {
+                    try!(write!(f, "{} {}: {}", if i == 0 { ":" } else { ";" }, addr, e));
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::InvalidPacket(ref e) => Some(e),
+            Error::AddressResolution(ref e) | Error::Unreachable(ref e) | Error::Io(ref e) => {
+                Some(e)
+            },
+            Error::AllAttemptsFailed(ref errors) => errors.first().map(|(_, e)| e as _),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Error {
+        Error::InvalidPacket(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use packet::{PacketType, ParseError};
+    use super::{Error, Timeout, is_unreachable};
+
+    #[test]
+    fn test_into_io_error() {
+        let e = io::Error::from(Error::TimedOut(Timeout::Idle));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        match e.into_inner().unwrap().downcast::<Error>() {
+            Ok(e) => assert!(matches!(*e, Error::TimedOut(Timeout::Idle))),
+            Err(e) => panic!("Expected utp::Error, got {:?}", e),
+        }
+
+        // Transport errors come out unchanged
+        let e = io::Error::from(Error::Io(io::Error::new(io::ErrorKind::WouldBlock, "wait")));
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(e.to_string(), "wait");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        assert_eq!(Error::ConnectionReset.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(Error::ConnectionRefused.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(Error::from(ParseError::InvalidPacketType).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Error::ProtocolViolation(PacketType::Data).kind(),
+                   io::ErrorKind::InvalidData);
+        assert_eq!(Error::no_address().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_unreachable_transport_errors() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(is_unreachable(&refused));
+        let e = Error::Unreachable(refused);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::ConnectionRefused);
+
+        let e = Error::Unreachable(io::Error::from(io::ErrorKind::HostUnreachable));
+        assert_eq!(e.kind(), io::ErrorKind::HostUnreachable);
+        assert!(!is_unreachable(&io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+
+    #[test]
+    fn test_all_attempts_failed() {
+        let e = Error::AllAttemptsFailed(vec![
+            ("[::1]:1".parse().unwrap(), Error::TimedOut(Timeout::Connect)),
+            ("127.0.0.1:1".parse().unwrap(), Error::ConnectionRefused),
+        ]);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(e.to_string(), "Every connection attempt failed: [::1]:1: The remote peer \
+                                   could not be reached; 127.0.0.1:1: Connection refused by \
+                                   remote peer");
+
+        let e = Error::AllAttemptsFailed(vec![
+            ("[::1]:1".parse().unwrap(), Error::TimedOut(Timeout::Connect)),
+        ]);
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+use packet::{Extension, ExtensionType, PacketRef};
+
+/// Size of the extension bits bitmask, in bytes.
+const EXTENSION_BITS_LEN: usize = 8;
+
+/// A header extension negotiated during the connection handshake.
+///
+/// The peer opening the connection offers the extension in its SYN packet, and the remote peer
+/// may answer in its reply. The answer is the extension's negotiated value on both ends, and an
+/// extension that isn't answered isn't in use.
+///
+/// Handlers are registered with `UtpSocket::register_extension` or
+/// `UtpListener::register_extension`, and the outcome is available from
+/// `UtpSocket::negotiated_extensions` once connected.
+///
+/// # Examples
+///
+/// ```
+/// use utp::ExtensionHandler;
+/// use utp::packet::ExtensionType;
+///
+/// /// Agrees on the smallest of both peers' maximum message sizes.
+/// struct MaxMessageSize(u32);
+///
+/// impl ExtensionHandler for MaxMessageSize {
+///     fn extension_type(&self) -> ExtensionType {
+///         ExtensionType::Other(0x40)
+///     }
+///
+///     fn offer(&self) -> Option<Vec<u8>> {
+///         Some(self.0.to_be_bytes().to_vec())
+///     }
+///
+///     fn answer(&self, offer: &[u8]) -> Option<Vec<u8>> {
+///         if offer.len() != 4 {
+///             return None;
+///         }
+///         let theirs = u32::from_be_bytes([offer[0], offer[1], offer[2], offer[3]]);
+///         Some(self.0.min(theirs).to_be_bytes().to_vec())
+///     }
+/// }
+/// ```
+pub trait ExtensionHandler: Send + Sync {
+    /// Returns the type of the extension handled.
+    fn extension_type(&self) -> ExtensionType;
+
+    /// Returns the data offered to the remote peer when opening a connection, or `None` not to
+    /// offer the extension.
+    ///
+    /// The data must be a non-zero multiple of 4 bytes long, up to 252 bytes.
+    fn offer(&self) -> Option<Vec<u8>>;
+
+    /// Answers the remote peer's offer, returning the data to reply with, or `None` to decline.
+    ///
+    /// The data must be a non-zero multiple of 4 bytes long, up to 252 bytes.
+    fn answer(&self, offer: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// The extension bits extension (type 2) from BEP 29, a bitmask of the optional features supported
+/// by each peer.
+///
+/// Each peer sends its own bits, and the features in use are those both of them support, i.e.,
+/// the bitwise AND of both bitmasks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtensionBits {
+    bits: [u8; EXTENSION_BITS_LEN],
+}
+
+impl ExtensionBits {
+    /// Creates a handler advertising the given features.
+    pub fn new(bits: [u8; EXTENSION_BITS_LEN]) -> ExtensionBits {
+        ExtensionBits { bits }
+    }
+
+    /// Returns the features advertised.
+    pub fn bits(&self) -> [u8; EXTENSION_BITS_LEN] {
+        self.bits
+    }
+}
+
+impl ExtensionHandler for ExtensionBits {
+    fn extension_type(&self) -> ExtensionType {
+        ExtensionType::ExtensionBits
+    }
+
+    fn offer(&self) -> Option<Vec<u8>> {
+        Some(self.bits.to_vec())
+    }
+
+    fn answer(&self, offer: &[u8]) -> Option<Vec<u8>> {
+        if offer.len() != EXTENSION_BITS_LEN {
+            return None;
+        }
+        Some(self.bits.iter().zip(offer).map(|(ours, theirs)| ours & theirs).collect())
+    }
+}
+
+/// The extension handlers of a socket or listener.
+#[derive(Clone, Default)]
+pub(crate) struct ExtensionRegistry {
+    handlers: Vec<Arc<dyn ExtensionHandler>>,
+}
+
+/// Returns the extension type of a handler, as parsed from a packet, where `Other` types of known
+/// extensions are never seen (e.g., `Other(2)` is `ExtensionBits`).
+fn handled_type(handler: &Arc<dyn ExtensionHandler>) -> ExtensionType {
+    ExtensionType::from(u8::from(handler.extension_type()))
+}
+
+impl ExtensionRegistry {
+    /// Adds a handler, replacing any previous one for the same extension type.
+    pub(crate) fn register(&mut self, handler: Arc<dyn ExtensionHandler>) {
+        let ty = handled_type(&handler);
+        self.handlers.retain(|h| handled_type(h) != ty);
+        self.handlers.push(handler);
+    }
+
+    /// Returns the extensions to offer in a SYN packet.
+    pub(crate) fn offers(&self) -> Vec<Extension> {
+        self.handlers.iter()
+            .filter_map(|h| h.offer().and_then(|data| Extension::new(h.extension_type(), data).ok()))
+            .collect()
+    }
+
+    /// Answers the extensions offered in the remote peer's SYN packet, returning the negotiated
+    /// ones.
+    pub(crate) fn answer(&self, syn: &PacketRef) -> Vec<Extension> {
+        syn.extensions()
+            .filter_map(|offer| {
+                self.handlers.iter()
+                    .find(|h| handled_type(h) == offer.get_type())
+                    .and_then(|h| h.answer(offer.data()))
+                    .and_then(|data| Extension::new(offer.get_type(), data).ok())
+            })
+            .collect()
+    }
+
+    /// Returns the extensions the remote peer answered in its reply to our SYN packet.
+    pub(crate) fn answered(&self, reply: &PacketRef) -> Vec<Extension> {
+        reply.extensions()
+            .filter(|answer| self.handlers.iter().any(|h| handled_type(h) == answer.get_type()))
+            .map(|answer| answer.to_extension())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use packet::{Encodable, Extension, ExtensionType, Packet, PacketRef, PacketType};
+    use super::{ExtensionBits, ExtensionHandler, ExtensionRegistry};
+
+    /// Answers every offer with its own data, reversed.
+    struct Mirror(u8);
+
+    impl ExtensionHandler for Mirror {
+        fn extension_type(&self) -> ExtensionType {
+            ExtensionType::Other(self.0)
+        }
+
+        fn offer(&self) -> Option<Vec<u8>> {
+            Some(vec![1, 2, 3, self.0])
+        }
+
+        fn answer(&self, offer: &[u8]) -> Option<Vec<u8>> {
+            Some(offer.iter().rev().cloned().collect())
+        }
+    }
+
+    fn packet_with(extensions: Vec<Extension>) -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::Syn);
+        packet.extensions = extensions;
+        packet.to_bytes()
+    }
+
+    #[test]
+    fn test_extension_bits_answer() {
+        let handler = ExtensionBits::new([0xff, 0x0f, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(handler.answer(&[0x11, 0xff, 0, 0, 0, 0, 0, 3]),
+                   Some(vec![0x11, 0x0f, 0, 0, 0, 0, 0, 1]));
+        assert_eq!(handler.answer(&[0xff; 4]), None);
+    }
+
+    #[test]
+    fn test_registry_negotiation() {
+        let mut initiator = ExtensionRegistry::default();
+        initiator.register(Arc::new(ExtensionBits::new([0xff; 8])));
+        initiator.register(Arc::new(Mirror(0x40)));
+        initiator.register(Arc::new(Mirror(0x41)));
+
+        // The remote peer only knows about one of the application's extensions
+        let mut acceptor = ExtensionRegistry::default();
+        acceptor.register(Arc::new(ExtensionBits::new([0x01; 8])));
+        acceptor.register(Arc::new(Mirror(0x41)));
+
+        let syn = packet_with(initiator.offers());
+        let answers = acceptor.answer(&PacketRef::from_bytes(&syn).unwrap());
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].get_type(), ExtensionType::ExtensionBits);
+        assert_eq!(answers[0].data(), &[0x01; 8]);
+        assert_eq!(answers[1].get_type(), ExtensionType::Other(0x41));
+        assert_eq!(answers[1].data(), &[0x41, 3, 2, 1]);
+
+        let reply = packet_with(answers.clone());
+        assert_eq!(initiator.answered(&PacketRef::from_bytes(&reply).unwrap()), answers);
+    }
+
+    #[test]
+    fn test_registry_replaces_handler() {
+        let mut registry = ExtensionRegistry::default();
+        registry.register(Arc::new(ExtensionBits::new([0xff; 8])));
+        registry.register(Arc::new(ExtensionBits::new([0x01; 8])));
+        let offers = registry.offers();
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].data(), &[0x01; 8]);
+    }
+
+    #[test]
+    fn test_registry_other_types_of_known_extensions() {
+        // `Other(2)` and `Other(1)` are the extension bits and selective acknowledgement types
+        let mut registry = ExtensionRegistry::default();
+        registry.register(Arc::new(Mirror(2)));
+        registry.register(Arc::new(Mirror(1)));
+        let offer = Extension::new(ExtensionType::ExtensionBits, vec![1, 2, 3, 4]).unwrap();
+        let sack = Extension::new(ExtensionType::SelectiveAck, vec![0; 4]).unwrap();
+        let syn = packet_with(vec![offer, sack]);
+        let answers = registry.answer(&PacketRef::from_bytes(&syn).unwrap());
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].get_type(), ExtensionType::ExtensionBits);
+        assert_eq!(answers[0].data(), &[4, 3, 2, 1]);
+        assert_eq!(answers[1].get_type(), ExtensionType::SelectiveAck);
+
+        let reply = packet_with(answers.clone());
+        assert_eq!(registry.answered(&PacketRef::from_bytes(&reply).unwrap()), answers);
+
+        // A handler for the same extension under its own name replaces it
+        registry.register(Arc::new(ExtensionBits::new([0x01; 8])));
+        let offers = registry.offers();
+        assert_eq!(offers.len(), 2);
+        assert_eq!(offers[1].get_type(), ExtensionType::ExtensionBits);
+        assert_eq!(offers[1].data(), &[0x01; 8]);
+    }
+}
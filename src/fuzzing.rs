@@ -0,0 +1,301 @@
+//! Drivers shared by the property tests and the fuzz targets in `fuzz/`.
+//!
+//! This module isn't part of the public API. It is only built for tests, or with `--cfg fuzzing`
+//! (which `cargo fuzz` sets).
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use packet::{Packet, PacketRef, PacketType, Encodable, Decodable};
+use socket::UtpSocket;
+use transport::{Transport, MemoryTransport};
+
+/// Largest payload the local end sends at once.
+const MAX_SEND: usize = 200;
+
+/// Bytes the local end may have in flight without ever waiting for an acknowledgement (i.e., the
+/// smallest congestion window).
+const MAX_IN_FLIGHT: u32 = 2 * 1400;
+
+/// Decodes a packet, checking that encoding it again yields a packet with the same contents.
+pub fn packet_codec(data: &[u8]) {
+    let packet = match PacketRef::from_bytes(data) {
+        Ok(packet) => packet,
+        Err(_) => {
+            assert!(Packet::from_bytes(data).is_err());
+            return;
+        }
+    };
+    assert_eq!(packet.len(), data.len());
+
+    let owned = packet.to_packet();
+    let mut buf = vec![0; owned.len()];
+    assert_eq!(owned.encode_into(&mut buf), buf.len());
+    assert_eq!(buf, owned.to_bytes());
+
+    // Every extension is kept, so the packet is encoded exactly as it was received
+    assert_eq!(buf, data);
+}
+
+/// Runs a connection against a remote peer whose packets are described by `data`.
+///
+/// The first two bytes are the peer's initial sequence number, and the rest is read as a
+/// sequence of events (see `Event::parse`). Panics if the connection misbehaves.
+pub fn state_machine(data: &[u8]) {
+    if data.len() < 2 {
+        return;
+    }
+    let mut harness = Harness::new((data[0] as u16) << 8 | data[1] as u16);
+    let mut input = &data[2..];
+    while let Some(event) = Event::parse(&mut input) {
+        harness.handle(&event);
+    }
+}
+
+/// Something the remote peer or the local application does.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// The remote peer sends a packet.
+    Packet {
+        /// Packet type
+        kind: PacketType,
+        /// Sequence number, relative to the last one acknowledged by the local end
+        seq_offset: i8,
+        /// Acknowledgement number, relative to the next one the local end will send
+        ack_offset: i8,
+        /// Connection id, or `None` for the connection's own
+        connection_id: Option<u16>,
+        /// Timestamp
+        timestamp: u32,
+        /// Advertised window size
+        wnd_size: u32,
+        /// Selective acknowledgement bitmask, if any
+        sack: Option<Vec<u8>>,
+    },
+    /// The local application sends some data.
+    Send(usize),
+}
+
+impl Event {
+    /// Reads the next event from the front of `input`, returning `None` when it runs out.
+    pub fn parse(input: &mut &[u8]) -> Option<Event> {
+        fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if input.len() < len {
+                return None;
+            }
+            let (head, tail) = input.split_at(len);
+            *input = tail;
+            Some(head)
+        }
+        fn u16_at(bytes: &[u8]) -> u16 {
+            (bytes[0] as u16) << 8 | bytes[1] as u16
+        }
+        fn u32_at(bytes: &[u8]) -> u32 {
+            (u16_at(bytes) as u32) << 16 | u16_at(&bytes[2..]) as u32
+        }
+        // Mostly offsets close to zero, where packets are likely to be accepted
+        fn offset(byte: u8, range: u8) -> i8 {
+            if byte < 0xc0 { (byte % range) as i8 - (range / 2) as i8 } else { (byte << 2) as i8 }
+        }
+
+        let header = match take(input, 1) {
+            Some(header) => header[0],
+            None => return None,
+        };
+        // Events that end the connection are rare, so that it lives long enough to be interesting
+        let kind = match header {
+            0x00..=0x7f => PacketType::Data,
+            0x80..=0xbf => {
+                return take(input, 1).map(|len| Event::Send(len[0] as usize % MAX_SEND + 1));
+            },
+            0xc0..=0xf7 => PacketType::State,
+            0xf8..=0xfb => PacketType::Fin,
+            0xfc..=0xfd => PacketType::Syn,
+            _ => PacketType::Reset,
+        };
+        take(input, 16).and_then(|fields| {
+            let sack_len = (fields[15] as usize % 4) * 4;
+            take(input, sack_len).map(|sack| Event::Packet {
+                kind,
+                seq_offset: offset(fields[0], 4) + 1,
+                ack_offset: offset(fields[1], 16),
+                connection_id: if fields[2] < 0xf0 { None } else { Some(u16_at(&fields[3..])) },
+                timestamp: u32_at(&fields[5..]),
+                wnd_size: u32_at(&fields[9..]),
+                sack: if sack.is_empty() { None } else { Some(sack.to_vec()) },
+            })
+        })
+    }
+}
+
+/// Returns the payload of the remote peer's data packet with the given sequence number.
+///
+/// Every packet carries a different amount of data, so that data delivered out of order or more
+/// than once can't go unnoticed.
+fn payload(seq_nr: u16) -> Vec<u8> {
+    (0..seq_nr % 7 + 1).map(|i| (seq_nr as u8).wrapping_add(i as u8)).collect()
+}
+
+/// A connection accepted by the local end, with a scripted remote peer.
+pub struct Harness {
+    socket: UtpSocket,
+    peer: MemoryTransport,
+    addr: SocketAddr,
+    connection_id: u16,
+    /// Sequence number of the remote peer's first data packet
+    first_seq_nr: u16,
+    /// Data delivered to the local application so far
+    delivered: Vec<u8>,
+}
+
+impl Harness {
+    /// Creates a connection whose remote peer starts with the given sequence number.
+    pub fn new(seq_nr: u16) -> Harness {
+        let addr = "127.0.0.1:1000".parse().unwrap();
+        let peer_addr = "127.0.0.1:2000".parse().unwrap();
+        let (local, peer) = MemoryTransport::pair(addr, peer_addr);
+        let mut socket = UtpSocket::from_transport(local).unwrap();
+        // Dropping the socket mustn't wait for the scripted peer
+        socket.set_linger(Some(Duration::from_secs(0)));
+
+        let connection_id = seq_nr.rotate_left(8);
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(connection_id);
+        syn.set_seq_nr(seq_nr);
+
+        let mut harness = Harness {
+            socket,
+            peer,
+            addr,
+            connection_id: connection_id.wrapping_add(1),
+            first_seq_nr: seq_nr.wrapping_add(1),
+            delivered: Vec::new(),
+        };
+        harness.deliver(&syn);
+        harness
+    }
+
+    /// Applies an event to the connection, checking its invariants afterwards.
+    pub fn handle(&mut self, event: &Event) {
+        match *event {
+            Event::Packet { kind, seq_offset, ack_offset, connection_id, timestamp, wnd_size,
+                            ref sack } => {
+                let (seq_nr, ack_nr) = self.socket.sequence_numbers();
+                let mut packet = match kind {
+                    PacketType::Data => {
+                        let seq_nr = ack_nr.wrapping_add(seq_offset as u16);
+                        let mut packet = Packet::with_payload(&payload(seq_nr)[..]);
+                        packet.set_seq_nr(seq_nr);
+                        packet
+                    },
+                    _ => {
+                        let mut packet = Packet::new();
+                        packet.set_type(kind);
+                        packet.set_seq_nr(ack_nr.wrapping_add(seq_offset as u16));
+                        packet
+                    },
+                };
+                packet.set_ack_nr(seq_nr.wrapping_add(ack_offset as u16));
+                packet.set_connection_id(connection_id.unwrap_or(self.connection_id));
+                packet.set_timestamp_microseconds(timestamp);
+                packet.set_wnd_size(wnd_size);
+                if let Some(ref sack) = *sack {
+                    packet.set_sack(sack.clone());
+                }
+                self.deliver(&packet);
+            },
+            Event::Send(len) => {
+                // Never send more than fits in the congestion window, as the scripted peer may
+                // not acknowledge anything
+                if self.socket.bytes_in_flight() + (len + 20) as u32 <= MAX_IN_FLIGHT {
+                    let _ = self.socket.send_to(&vec![0; len][..]);
+                }
+            },
+        }
+
+        self.socket.bytes_in_flight();
+        self.read();
+    }
+
+    /// Returns the data delivered to the local application so far.
+    pub fn delivered(&self) -> &[u8] {
+        &self.delivered[..]
+    }
+
+    /// Hands a packet from the remote peer to the local end.
+    fn deliver(&mut self, packet: &Packet) {
+        self.peer.send_to(&packet.to_bytes()[..], self.addr).unwrap();
+        // Errors (e.g., the connection being reset) are legitimate outcomes
+        let _ = self.socket.handle_next_packet();
+    }
+
+    /// Reads every byte available to the local application, checking that the data is a prefix
+    /// of the remote peer's stream.
+    fn read(&mut self) {
+        // A small buffer, so that packets are read in several pieces
+        let mut buf = [0; 5];
+        while let Ok(Some(read)) = self.socket.recv_buffered(&mut buf) {
+            if read == 0 {
+                break;
+            }
+            self.delivered.extend_from_slice(&buf[..read]);
+        }
+
+        let mut expected = Vec::new();
+        let mut seq_nr = self.first_seq_nr;
+        while expected.len() < self.delivered.len() {
+            expected.extend(payload(seq_nr));
+            seq_nr = seq_nr.wrapping_add(1);
+        }
+        assert!(self.delivered[..] == expected[..self.delivered.len()],
+                "delivered data isn't a prefix of the stream");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck::{QuickCheck, Gen, TestResult};
+    use packet::PacketType;
+    use super::{Harness, Event, payload, packet_codec, state_machine};
+
+    #[test]
+    fn test_packet_codec_roundtrip() {
+        fn run(data: Vec<u8>) -> TestResult {
+            packet_codec(&data[..]);
+            TestResult::passed()
+        }
+        QuickCheck::new().tests(10000).quickcheck(run as fn(Vec<u8>) -> TestResult)
+    }
+
+    #[test]
+    fn test_state_machine_survives_arbitrary_packets() {
+        fn run(data: Vec<u8>) -> TestResult {
+            state_machine(&data[..]);
+            TestResult::passed()
+        }
+        QuickCheck::new().tests(2000).rng(Gen::new(2000))
+            .quickcheck(run as fn(Vec<u8>) -> TestResult)
+    }
+
+    #[test]
+    fn test_state_machine_delivers_in_order() {
+        // A window of packets in reverse order, then more packets in order (sequence numbers are
+        // relative to the last acknowledged one)
+        for &first in [0u16, 1000, 65530].iter() {
+            let mut harness = Harness::new(first);
+            for offset in (1..20).rev().chain(vec![1; 19]) {
+                harness.handle(&Event::Packet {
+                    kind: PacketType::Data,
+                    seq_offset: offset,
+                    ack_offset: 0,
+                    connection_id: None,
+                    timestamp: 0,
+                    wnd_size: 0,
+                    sack: None,
+                });
+            }
+            let expected = (1..39).fold(0, |acc, i| acc + payload(first.wrapping_add(i)).len());
+            assert_eq!(harness.delivered().len(), expected);
+        }
+    }
+}
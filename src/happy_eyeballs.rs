@@ -0,0 +1,226 @@
+//! Connection attempts to every address of a host, raced as in Happy Eyeballs (RFC 8305).
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use error::{Error, Result};
+
+/// How long an attempt goes unanswered before the next one starts, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: u64 = 250; // milliseconds
+
+/// Orders addresses for connection attempts, alternating between address families starting with
+/// that of the first address, and leaving out duplicates.
+fn sort_addresses(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let mut unique: Vec<SocketAddr> = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+
+    let preferred_v6 = unique.first().map(|addr| addr.is_ipv6()).unwrap_or(true);
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        unique.into_iter().partition(|addr| addr.is_ipv6() == preferred_v6);
+    let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Runs `attempt` for every address, starting the next one whenever the last one fails or goes
+/// unanswered for `CONNECTION_ATTEMPT_DELAY`, and returns the first success.
+///
+/// Each attempt runs in a thread of its own and is passed a flag, set once another attempt has
+/// succeeded, upon which it should give up. A single address is tried in the calling thread, and
+/// its error is returned as is. Otherwise, the errors of all attempts are returned together as
+/// `Error::AllAttemptsFailed`.
+pub(crate) fn connect<T, F>(addrs: Vec<SocketAddr>, attempt: F) -> Result<T>
+    where T: Send + 'static, F: Fn(SocketAddr, &AtomicBool) -> Result<T> + Send + Sync + 'static {
+    race(addrs, Some(Duration::from_millis(CONNECTION_ATTEMPT_DELAY)), attempt)
+}
+
+/// Runs `attempt` for every address like `connect`, but only starts the next one once the last
+/// one has failed (e.g., when attempts can't share their local address).
+pub(crate) fn connect_in_turn<T, F>(addrs: Vec<SocketAddr>, attempt: F) -> Result<T>
+    where T: Send + 'static, F: Fn(SocketAddr, &AtomicBool) -> Result<T> + Send + Sync + 'static {
+    race(addrs, None, attempt)
+}
+
+/// Runs `attempt` for every address, starting the next one after `delay` without an outcome (or
+/// never, if `None`) or as soon as the last one fails.
+fn race<T, F>(addrs: Vec<SocketAddr>, delay: Option<Duration>, attempt: F) -> Result<T>
+    where T: Send + 'static, F: Fn(SocketAddr, &AtomicBool) -> Result<T> + Send + Sync + 'static {
+    let addrs = sort_addresses(addrs);
+    match addrs.len() {
+        0 => return Err(Error::no_address()),
+        1 => return attempt(addrs[0], &AtomicBool::new(false)),
+        _ => (),
+    }
+
+    let attempt = Arc::new(attempt);
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let mut errors: Vec<Option<Error>> = addrs.iter().map(|_| None).collect();
+    let mut started = 0;
+    let mut running = 0;
+
+    loop {
+        if started < addrs.len() {
+            let (index, addr) = (started, addrs[started]);
+            let (attempt, abandoned, tx) = (attempt.clone(), abandoned.clone(), tx.clone());
+            let spawned = thread::Builder::new()
+                .name("utp-connect".to_string())
+                .spawn(move || {
+                    let _ = tx.send((index, attempt(addr, &abandoned)));
+                });
+            started += 1;
+            match spawned {
+                Ok(_) => running += 1,
+                Err(e) => {
+                    errors[index] = Some(Error::from(e));
+                    continue;
+                },
+            }
+        } else if running == 0 {
+            break;
+        }
+
+        let result = match delay {
+            Some(delay) if started < addrs.len() => {
+                match rx.recv_timeout(delay) {
+                    Ok(result) => result,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => unreachable!(),
+                }
+            },
+            // The sender kept for later attempts keeps the channel open
+            _ => rx.recv().unwrap(),
+        };
+
+        running -= 1;
+        match result {
+            (_, Ok(connection)) => {
+                abandoned.store(true, Ordering::SeqCst);
+                return Ok(connection);
+            },
+            (index, Err(e)) => {
+                debug!("Connecting to {} failed: {}", addrs[index], e);
+                errors[index] = Some(e);
+            },
+        }
+    }
+
+    let errors = addrs.into_iter().zip(errors)
+        .filter_map(|(addr, e)| e.map(|e| (addr, e)))
+        .collect();
+    Err(Error::AllAttemptsFailed(errors))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use error::{Error, Timeout};
+    use super::{connect, connect_in_turn, sort_addresses};
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_address_families_alternate() {
+        let sorted = sort_addresses(addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1",
+                                            "[::1]:1", "10.0.0.2:1"]));
+        assert_eq!(sorted, addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]));
+
+        let sorted = sort_addresses(addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"]));
+        assert_eq!(sorted, addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"]));
+    }
+
+    #[test]
+    fn test_first_success_wins() {
+        // The first address never answers, so the second one is tried after a while
+        let slow_gave_up = Arc::new(AtomicBool::new(false));
+        let gave_up = slow_gave_up.clone();
+        let start = Instant::now();
+        let result = connect(addrs(&["[::1]:1", "127.0.0.1:1"]), move |addr, abandoned| {
+            if addr.is_ipv4() {
+                return Ok(addr);
+            }
+            while !abandoned.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            gave_up.store(true, Ordering::SeqCst);
+            Err(Error::TimedOut(Timeout::Connect))
+        });
+        assert_eq!(result.unwrap(), "127.0.0.1:1".parse().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !slow_gave_up.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "The slow attempt wasn't abandoned");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_failure_starts_next_attempt() {
+        let start = Instant::now();
+        let result = connect(addrs(&["[::1]:1", "127.0.0.1:1"]), |addr, _| {
+            if addr.is_ipv6() { Err(Error::ConnectionRefused) } else { Ok(addr) }
+        });
+        assert_eq!(result.unwrap(), "127.0.0.1:1".parse().unwrap());
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_attempts_in_turn() {
+        // The slow attempt is never raced, and the next one starts once it fails
+        let start = Instant::now();
+        let result = connect_in_turn(addrs(&["[::1]:1", "127.0.0.1:1"]), |addr, _| {
+            if addr.is_ipv4() {
+                return Ok(addr);
+            }
+            thread::sleep(Duration::from_millis(400));
+            Err(Error::TimedOut(Timeout::Connect))
+        });
+        assert_eq!(result.unwrap(), "127.0.0.1:1".parse().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_all_attempts_failed() {
+        let result = connect(addrs(&["127.0.0.1:1", "[::1]:1", "127.0.0.2:1"]), |addr, _| {
+            if addr.is_ipv6() {
+                Err(Error::TimedOut(Timeout::Connect))
+            } else {
+                Err::<(), _>(Error::ConnectionRefused)
+            }
+        });
+        match result {
+            Err(Error::AllAttemptsFailed(errors)) => {
+                let failed: Vec<SocketAddr> = errors.iter().map(|&(addr, _)| addr).collect();
+                assert_eq!(failed, addrs(&["127.0.0.1:1", "[::1]:1", "127.0.0.2:1"]));
+                assert!(matches!(errors[1].1, Error::TimedOut(Timeout::Connect)));
+            },
+            other => panic!("Expected AllAttemptsFailed, got {:?}", other),
+        }
+
+        // A single address fails with its own error
+        match connect(addrs(&["127.0.0.1:1"]), |_, _| Err::<(), _>(Error::ConnectionRefused)) {
+            Err(Error::ConnectionRefused) => (),
+            other => panic!("Expected ConnectionRefused, got {:?}", other),
+        }
+    }
+}
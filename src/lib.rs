@@ -55,20 +55,39 @@
 //! ```
 
 #![deny(missing_docs)]
+#![forbid(unsafe_code)]
 
 extern crate rand;
 extern crate time;
 extern crate num;
 #[macro_use] extern crate log;
+#[cfg(target_os = "linux")] extern crate nix;
 #[cfg(test)] extern crate quickcheck;
 
 // Public API
+pub use error::{Error, Result, Timeout};
 pub use socket::UtpSocket;
 pub use socket::UtpListener;
-pub use stream::UtpStream;
+pub use stream::{UtpStream, ReadHalf, WriteHalf};
+pub use transport::{Transport, MemoryTransport};
+pub use sim::{SimNetwork, SimTransport, Impairments};
+pub use extension::{ExtensionHandler, ExtensionBits};
+pub use rate::RateLimiter;
+pub use context::UtpContext;
 
 mod util;
+mod error;
 mod bit_iterator;
-mod packet;
+pub mod packet;
 mod socket;
 mod stream;
+mod transport;
+mod sim;
+mod extension;
+mod rate;
+mod context;
+mod happy_eyeballs;
+
+#[cfg(any(test, fuzzing))]
+#[doc(hidden)]
+pub mod fuzzing;
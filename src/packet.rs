@@ -1,31 +1,79 @@
-#![allow(dead_code)]
+//! Encoding and decoding of uTP packets.
+//!
+//! This is the codec used by `UtpSocket`, made available to tools that need to work with
+//! individual packets, such as packet sniffers, test peers or proxies.
+//!
+//! `Packet` owns its contents and can be modified, while `PacketRef` is a read-only view parsed
+//! in place over a received datagram. Header fields are always exposed in native byte order.
+//!
+//! # Examples
+//!
+//! ```
+//! use utp::packet::{PacketBuilder, PacketRef, PacketType, Encodable};
+//!
+//! let packet = PacketBuilder::new(PacketType::Data)
+//!     .connection_id(42)
+//!     .seq_nr(2)
+//!     .ack_nr(1)
+//!     .payload(b"hello")
+//!     .build();
+//! let bytes = packet.to_bytes();
+//!
+//! let decoded = PacketRef::from_bytes(&bytes).unwrap();
+//! assert_eq!(decoded.get_type(), PacketType::Data);
+//! assert_eq!(decoded.connection_id(), 42);
+//! assert_eq!(decoded.payload(), b"hello");
+//! ```
 
 use std::error::Error;
-use std::mem::transmute;
 use std::fmt;
-use std::ops::Deref;
-use bit_iterator::BitIterator;
 
+pub use bit_iterator::BitIterator;
+
+/// Size of a packet header in bytes.
 pub const HEADER_SIZE: usize = 20;
 
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << i * 8)
-    })
-}
+/// Largest extension payload, as its length must fit in a byte and be a multiple of 4.
+const MAX_EXTENSION_LEN: usize = 252;
 
 macro_rules! make_getter {
-    ($name:ident, $t:ty, $m:ident) => {
+    ($name:ident, $t:ty, $doc:expr) => {
+        #[doc = $doc]
         pub fn $name(&self) -> $t {
-            $m::from_be(self.header.$name)
+            self.header.$name
         }
     }
 }
 
 macro_rules! make_setter {
-    ($fn_name:ident, $field:ident, $t: ty) => {
+    ($fn_name:ident, $field:ident, $t: ty, $doc:expr) => {
+        #[doc = $doc]
         pub fn $fn_name(&mut self, new: $t) {
-            self.header.$field = new.to_be();
+            self.header.$field = new;
+        }
+    }
+}
+
+macro_rules! make_getters {
+    () => {
+        make_getter!(seq_nr, u16, "Returns the packet's sequence number.");
+        make_getter!(ack_nr, u16, "Returns the sequence number of the last packet acknowledged.");
+        make_getter!(connection_id, u16, "Returns the packet's connection id.");
+        make_getter!(wnd_size, u32, "Returns the sender's receive window size, in bytes.");
+        make_getter!(timestamp_microseconds, u32,
+                     "Returns the time the packet was sent at, in microseconds.");
+        make_getter!(timestamp_difference_microseconds, u32,
+                     "Returns the sender's last measured one-way delay, in microseconds.");
+
+        /// Returns the packet's type.
+        #[inline]
+        pub fn get_type(&self) -> PacketType {
+            self.header.get_type()
+        }
+
+        /// Returns the protocol version of the packet (always 1).
+        pub fn version(&self) -> u8 {
+            self.header.get_version()
         }
     }
 }
@@ -38,7 +86,7 @@ pub trait Encodable {
 }
 
 /// A trait for objects that can be decoded from slices of bytes.
-pub trait Decodable {
+pub trait Decodable: Sized {
     /// Decodes a slice of bytes and returns an equivalent object.
     ///
     /// If the slice of bytes represents a valid instance of the type, it returns `Ok`, containing
@@ -47,11 +95,19 @@ pub trait Decodable {
     fn from_bytes(&[u8]) -> Result<Self, ParseError>;
 }
 
-#[derive(Debug)]
+/// The reason a slice of bytes isn't a valid packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ParseError {
+    /// An extension's length is zero, not a multiple of 4, or goes past the end of the packet.
     InvalidExtensionLength,
+    /// An extension has type 0, which is reserved for the end of the chain of extensions.
+    InvalidExtensionType,
+    /// The packet is shorter than its header, or than its extensions claim.
     InvalidPacketLength,
+    /// The packet type is unknown.
     InvalidPacketType,
+    /// The packet isn't a version 1 packet.
     UnsupportedVersion
 }
 
@@ -66,6 +122,7 @@ impl Error for ParseError {
         use self::ParseError::*;
         match *self {
             InvalidExtensionLength => "Invalid extension length (must be a non-zero multiple of 4)",
+            InvalidExtensionType => "Invalid extension type",
             InvalidPacketLength => "The packet is too small",
             InvalidPacketType => "Invalid packet type",
             UnsupportedVersion => "Unsupported packet version",
@@ -73,41 +130,116 @@ impl Error for ParseError {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// The type of a packet.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum PacketType {
+    /// Regular data packet.
     Data  = 0,
+    /// Last packet of a stream (`ST_FIN`).
     Fin   = 1,
+    /// Acknowledgement, carrying no data (`ST_STATE`).
     State = 2,
+    /// Forceful termination of a connection.
     Reset = 3,
+    /// Connection request.
     Syn   = 4,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+impl PacketType {
+    /// Returns the packet type with the given number, as found in a packet's header.
+    fn from_u8(n: u8) -> Result<PacketType, ParseError> {
+        match n {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(ParseError::InvalidPacketType),
+        }
+    }
+}
+
+/// The type of a header extension.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
 pub enum ExtensionType {
-    SelectiveAck = 1,
+    /// Selective acknowledgement, a bitmask of the packets received past the acknowledged one.
+    SelectiveAck,
+    /// Extension bits, an 8-byte bitmask of the optional features a peer supports.
+    ExtensionBits,
+    /// Any other extension, with its raw type (never 0, which ends the chain of extensions).
+    Other(u8),
+}
+
+impl From<u8> for ExtensionType {
+    fn from(ty: u8) -> ExtensionType {
+        match ty {
+            1 => ExtensionType::SelectiveAck,
+            2 => ExtensionType::ExtensionBits,
+            ty => ExtensionType::Other(ty),
+        }
+    }
 }
 
-#[derive(Clone)]
+impl From<ExtensionType> for u8 {
+    fn from(ty: ExtensionType) -> u8 {
+        match ty {
+            ExtensionType::SelectiveAck => 1,
+            ExtensionType::ExtensionBits => 2,
+            ExtensionType::Other(ty) => ty,
+        }
+    }
+}
+
+/// A header extension.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Extension {
     ty: ExtensionType,
-    pub data: Vec<u8>,
+    data: Vec<u8>,
 }
 
 impl Extension {
+    /// Creates an extension of the given type.
+    ///
+    /// Fails with `ParseError::InvalidExtensionLength` unless the length of `data` is a non-zero
+    /// multiple of 4, up to 252 bytes, and with `ParseError::InvalidExtensionType` for type 0.
+    pub fn new(ty: ExtensionType, data: Vec<u8>) -> Result<Extension, ParseError> {
+        if u8::from(ty) == 0 {
+            return Err(ParseError::InvalidExtensionType);
+        }
+        if data.is_empty() || !data.len().is_multiple_of(4) || data.len() > MAX_EXTENSION_LEN {
+            return Err(ParseError::InvalidExtensionLength);
+        }
+        // Known types given as `Other` are normalized, so that they compare equal
+        Ok(Extension { ty: ExtensionType::from(u8::from(ty)), data })
+    }
+
+    /// Returns the length of the extension's encoding, not counting the type of the next
+    /// extension.
+    // Never zero, as the encoding includes the payload's length
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         1 + self.data.len()
     }
 
+    /// Returns the extension's type.
     pub fn get_type(&self) -> ExtensionType {
         self.ty
     }
 
+    /// Returns the extension's payload.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns an iterator over the bits of the extension's payload, as used by selective
+    /// acknowledgements.
     pub fn iter(&self) -> BitIterator {
         BitIterator::from_bytes(&self.data)
     }
 }
 
 impl Encodable for Extension {
+    /// Returns the extension's length followed by its payload.
     fn to_bytes(&self) -> Vec<u8> {
         let mut data = Vec::with_capacity(self.data.len() + 1);
         data.push(self.data.len() as u8);
@@ -116,9 +248,73 @@ impl Encodable for Extension {
     }
 }
 
+/// An extension borrowed from the bytes of a `PacketRef`.
+#[derive(Clone, Copy)]
+pub struct ExtensionRef<'a> {
+    ty: ExtensionType,
+    data: &'a [u8],
+}
+
+impl<'a> ExtensionRef<'a> {
+    /// Returns the length of the extension's encoding, not counting the type of the next
+    /// extension.
+    // Never zero, as the encoding includes the payload's length
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + self.data.len()
+    }
+
+    /// Returns the extension's type.
+    pub fn get_type(&self) -> ExtensionType {
+        self.ty
+    }
+
+    /// Returns the extension's payload.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns an iterator over the bits of the extension's payload, as used by selective
+    /// acknowledgements.
+    pub fn iter(&self) -> BitIterator<'a> {
+        BitIterator::from_bytes(self.data)
+    }
+
+    /// Copies the extension into an owned `Extension`.
+    pub fn to_extension(&self) -> Extension {
+        Extension { ty: self.ty, data: self.data.to_vec() }
+    }
+}
+
+/// Iterator over the extensions of a `PacketRef`, in the order they're encoded.
+pub struct Extensions<'a> {
+    next: u8,
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = ExtensionRef<'a>;
+
+    fn next(&mut self) -> Option<ExtensionRef<'a>> {
+        // The extension chain was validated when the packet was parsed
+        if self.next == 0 || self.buf.len() < 2 {
+            return None;
+        }
+        let kind = self.next;
+        let len = self.buf[1] as usize;
+        let data = &self.buf[2..2 + len];
+        self.next = self.buf[0];
+        self.buf = &self.buf[2 + len..];
+
+        Some(ExtensionRef { ty: ExtensionType::from(kind), data })
+    }
+}
+
+/// A packet header, with fields in native byte order.
 #[derive(Clone, Copy)]
 struct PacketHeader {
-    type_ver: u8, // type: u4, ver: u4
+    ty: PacketType,
+    version: u8,
     extension: u8,
     connection_id: u16,
     timestamp_microseconds: u32,
@@ -131,40 +327,48 @@ struct PacketHeader {
 impl PacketHeader {
     /// Sets the type of packet to the specified type.
     pub fn set_type(&mut self, t: PacketType) {
-        let version = 0x0F & self.type_ver;
-        self.type_ver = (t as u8) << 4 | version;
+        self.ty = t;
     }
 
     /// Returns the packet's type.
     pub fn get_type(&self) -> PacketType {
-        unsafe { transmute(self.type_ver >> 4) }
+        self.ty
     }
 
     /// Returns the packet's version.
     pub fn get_version(&self) -> u8 {
-        self.type_ver & 0x0F
+        self.version
     }
 
     /// Returns the packet header's length.
     pub fn len(&self) -> usize {
         return HEADER_SIZE;
     }
-}
 
-impl Deref for PacketHeader {
-    type Target = [u8];
+    /// Returns the packet header as bytes, with fields in network (big-endian) byte order.
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[0] = (self.ty as u8) << 4 | self.version;
+        buf[1] = self.extension;
+        buf[2..4].copy_from_slice(&self.connection_id.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp_microseconds.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.timestamp_difference_microseconds.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.wnd_size.to_be_bytes());
+        buf[16..18].copy_from_slice(&self.seq_nr.to_be_bytes());
+        buf[18..20].copy_from_slice(&self.ack_nr.to_be_bytes());
+        buf
+    }
+}
 
-    /// Returns the packet header as a slice of bytes.
-    fn deref(&self) -> &[u8] {
-        let buf: &[u8; HEADER_SIZE] = unsafe { transmute(self) };
-        return &buf[..];
+impl Encodable for PacketHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.encode().to_vec()
     }
 }
 
 impl Decodable for PacketHeader {
     /// Reads a byte buffer and returns the corresponding packet header.
-    /// It assumes the fields are in network (big-endian) byte order,
-    /// preserving it.
+    /// It assumes the fields are in network (big-endian) byte order.
     fn from_bytes(buf: &[u8]) -> Result<PacketHeader, ParseError> {
         // Check length
         if buf.len() < HEADER_SIZE {
@@ -177,24 +381,19 @@ impl Decodable for PacketHeader {
         }
 
         // Check packet type
-        let _ = match buf[0] >> 4 {
-            0 => PacketType::Data,
-            1 => PacketType::Fin,
-            2 => PacketType::State,
-            3 => PacketType::Reset,
-            4 => PacketType::Syn,
-            _ => return Err(ParseError::InvalidPacketType)
-        };
+        let ty = try!(PacketType::from_u8(buf[0] >> 4));
 
         Ok(PacketHeader {
-            type_ver: buf[0],
+            ty,
+            version: buf[0] & 0x0F,
             extension: buf[1],
-            connection_id: u8_to_unsigned_be!(buf, 2, 3, u16),
-            timestamp_microseconds: u8_to_unsigned_be!(buf, 4, 7, u32),
-            timestamp_difference_microseconds: u8_to_unsigned_be!(buf, 8, 11, u32),
-            wnd_size: u8_to_unsigned_be!(buf, 12, 15, u32),
-            seq_nr: u8_to_unsigned_be!(buf, 16, 17, u16),
-            ack_nr: u8_to_unsigned_be!(buf, 18, 19, u16),
+            connection_id: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp_microseconds: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            timestamp_difference_microseconds:
+                u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            wnd_size: u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            seq_nr: u16::from_be_bytes([buf[16], buf[17]]),
+            ack_nr: u16::from_be_bytes([buf[18], buf[19]]),
         })
     }
 }
@@ -202,7 +401,8 @@ impl Decodable for PacketHeader {
 impl Default for PacketHeader {
     fn default() -> PacketHeader {
         PacketHeader {
-            type_ver: (PacketType::Data as u8) << 4 | 1,
+            ty: PacketType::Data,
+            version: 1,
             extension: 0,
             connection_id: 0,
             timestamp_microseconds: 0,
@@ -221,21 +421,27 @@ impl fmt::Debug for PacketHeader {
                 timestamp_difference_microseconds: {}, wnd_size: {}, \
                 seq_nr: {}, ack_nr: {})",
                 self.get_type(),
-                u8::from_be(self.get_version()),
-                u8::from_be(self.extension),
-                u16::from_be(self.connection_id),
-                u32::from_be(self.timestamp_microseconds),
-                u32::from_be(self.timestamp_difference_microseconds),
-                u32::from_be(self.wnd_size),
-                u16::from_be(self.seq_nr),
-                u16::from_be(self.ack_nr),
+                self.get_version(),
+                self.extension,
+                self.connection_id,
+                self.timestamp_microseconds,
+                self.timestamp_difference_microseconds,
+                self.wnd_size,
+                self.seq_nr,
+                self.ack_nr,
         )
     }
 }
 
+/// A packet, owning its extensions and payload.
+///
+/// Fields are set with `set_*` methods or with a `PacketBuilder`. Encoding is always valid, as the
+/// chain of extensions in the header is derived from `extensions`.
 pub struct Packet {
     header: PacketHeader,
+    /// Header extensions, in the order they're encoded
     pub extensions: Vec<Extension>,
+    /// Data carried by the packet
     pub payload: Vec<u8>,
 }
 
@@ -254,100 +460,119 @@ impl Packet {
         let mut header = PacketHeader::default();
         header.set_type(PacketType::Data);
 
-        let elts = payload.len();
-        let mut v = Vec::with_capacity(elts);
-
-        unsafe {
-            use std::ptr;
-            v.set_len(elts);
-            ptr::copy_nonoverlapping(payload.as_ptr(), v.as_mut_ptr(), elts);
-        }
-
         Packet {
-            header: header,
+            header,
             extensions: Vec::new(),
-            payload: v,
+            payload: payload.to_vec(),
         }
     }
 
+    /// Sets the packet's type.
     #[inline]
     pub fn set_type(&mut self, t: PacketType) {
         self.header.set_type(t);
     }
 
-    #[inline]
-    pub fn get_type(&self) -> PacketType {
-        self.header.get_type()
-    }
+    make_getters!();
 
-    make_getter!(seq_nr, u16, u16);
-    make_getter!(ack_nr, u16, u16);
-    make_getter!(connection_id, u16, u16);
-    make_getter!(wnd_size, u32, u32);
-    make_getter!(timestamp_microseconds, u32, u32);
-    make_getter!(timestamp_difference_microseconds, u32, u32);
-
-    make_setter!(set_seq_nr, seq_nr, u16);
-    make_setter!(set_ack_nr, ack_nr, u16);
-    make_setter!(set_connection_id, connection_id, u16);
-    make_setter!(set_wnd_size, wnd_size, u32);
-    make_setter!(set_timestamp_microseconds, timestamp_microseconds, u32);
-    make_setter!(set_timestamp_difference_microseconds, timestamp_difference_microseconds, u32);
+    make_setter!(set_seq_nr, seq_nr, u16, "Sets the packet's sequence number.");
+    make_setter!(set_ack_nr, ack_nr, u16,
+                 "Sets the sequence number of the last packet acknowledged.");
+    make_setter!(set_connection_id, connection_id, u16, "Sets the packet's connection id.");
+    make_setter!(set_wnd_size, wnd_size, u32, "Sets the receive window size, in bytes.");
+    make_setter!(set_timestamp_microseconds, timestamp_microseconds, u32,
+                 "Sets the time the packet is sent at, in microseconds.");
+    make_setter!(set_timestamp_difference_microseconds, timestamp_difference_microseconds, u32,
+                 "Sets the last measured one-way delay from the remote peer, in microseconds.");
 
     /// Sets Selective ACK field in packet header and adds appropriate data.
     ///
+    /// # Panics
+    ///
     /// The length of the SACK extension is expressed in bytes, which
-    /// must be a multiple of 4 and at least 4.
+    /// must be a multiple of 4 and at least 4 (and at most 252).
     pub fn set_sack(&mut self, bv: Vec<u8>) {
-        // The length of the SACK extension is expressed in bytes, which
-        // must be a multiple of 4 and at least 4.
-        assert!(bv.len() >= 4);
-        assert!(bv.len() % 4 == 0);
-
-        let extension = Extension {
-            ty: ExtensionType::SelectiveAck,
-            data: bv,
-        };
+        let extension = Extension::new(ExtensionType::SelectiveAck, bv)
+            .expect("invalid selective acknowledgement length");
         self.extensions.push(extension);
-        self.header.extension |= ExtensionType::SelectiveAck as u8;
+        self.header.extension = u8::from(self.extensions[0].ty);
     }
 
+    /// Returns the bitmask of the packet's selective acknowledgement extension, if any.
+    pub fn sack(&self) -> Option<&[u8]> {
+        self.extensions.iter()
+            .find(|e| e.get_type() == ExtensionType::SelectiveAck)
+            .map(|e| e.data())
+    }
+
+    /// Returns the bitmask of the packet's extension bits extension, if any.
+    pub fn extension_bits(&self) -> Option<&[u8]> {
+        self.extensions.iter()
+            .find(|e| e.get_type() == ExtensionType::ExtensionBits)
+            .map(|e| e.data())
+    }
+
+    /// Returns the length of the encoded packet in bytes.
+    // Never zero, as every packet has a header
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         let ext_len = self.extensions.iter().fold(0, |acc, ext| acc + ext.len() + 1);
         self.header.len() + self.payload.len() + ext_len
     }
-}
 
-impl Encodable for Packet {
-    fn to_bytes(&self) -> Vec<u8> {
-        use std::ptr;
-        let mut buf: Vec<u8> = Vec::with_capacity(self.len());
+    /// Writes the packet into `buf`, returning the number of bytes written (i.e., `self.len()`).
+    ///
+    /// Unlike `to_bytes`, this doesn't allocate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than the packet. See `try_encode_into` for a variant that
+    /// doesn't.
+    pub fn encode_into(&self, buf: &mut [u8]) -> usize {
+        let len = self.len();
+        let buf = &mut buf[..len];
 
-        // Copy header
-        unsafe {
-            ptr::copy(self.header.as_ptr(), buf.as_mut_ptr(), self.header.len());
-            buf.set_len(self.header.len());
-        }
+        // Copy header, pointing to the first extension actually present
+        buf[..HEADER_SIZE].copy_from_slice(&self.header.encode());
+        buf[1] = self.extensions.first().map(|e| u8::from(e.ty)).unwrap_or(0);
 
-        // Copy extensions
-        let mut extensions = self.extensions.iter().peekable();
-        while let Some(extension) = extensions.next() {
-            // next extension id
-            match extensions.peek() {
-                None => buf.push(0u8),
-                Some(next) => buf.push(next.ty as u8),
-            }
-            buf.extend(extension.to_bytes());
+        // Copy extensions, each preceded by the next one's type and its own length
+        let mut idx = HEADER_SIZE;
+        for (i, extension) in self.extensions.iter().enumerate() {
+            let end = idx + 2 + extension.data.len();
+            buf[idx] = self.extensions.get(i + 1).map(|e| u8::from(e.ty)).unwrap_or(0);
+            buf[idx + 1] = extension.data.len() as u8;
+            buf[idx + 2..end].copy_from_slice(&extension.data);
+            idx = end;
         }
 
         // Copy payload
-        unsafe {
-            let buf_len = buf.len();
-            ptr::copy(self.payload.as_ptr(), buf.as_mut_ptr().offset(buf.len() as isize), self.payload.len());
-            buf.set_len(buf_len + self.payload.len());
+        buf[idx..].copy_from_slice(&self.payload);
+
+        len
+    }
+
+    /// Writes the packet into `buf` like `encode_into`, or returns `None` without writing
+    /// anything if `buf` is shorter than the packet.
+    pub fn try_encode_into(&self, buf: &mut [u8]) -> Option<usize> {
+        if buf.len() < self.len() {
+            return None;
         }
+        Some(self.encode_into(buf))
+    }
+}
 
-        return buf;
+impl Default for Packet {
+    fn default() -> Packet {
+        Packet::new()
+    }
+}
+
+impl Encodable for Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.len()];
+        self.encode_into(&mut buf);
+        buf
     }
 }
 
@@ -358,9 +583,39 @@ impl Decodable for Packet {
     /// all except the initial 20 bytes corresponding to the header as payload.
     /// It's the caller's responsability to use an appropriately sized buffer.
     fn from_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
+        PacketRef::from_bytes(buf).map(|packet| packet.to_packet())
+    }
+}
+
+/// A packet parsed in place, borrowing its extensions and payload from the decoded bytes.
+///
+/// Unlike `Packet::from_bytes`, parsing doesn't allocate.
+///
+/// # Examples
+///
+/// ```
+/// use utp::packet::{PacketRef, PacketType};
+///
+/// let datagram = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
+///                 0x26, 0x21, 0x00, 0x10, 0x00, 0x00, 0x3a, 0xf2, 0x6c, 0x79];
+/// let packet = PacketRef::from_bytes(&datagram).unwrap();
+/// assert_eq!(packet.get_type(), PacketType::State);
+/// assert_eq!(packet.seq_nr(), 15090);
+/// assert_eq!(packet.ack_nr(), 27769);
+/// ```
+#[derive(Clone, Copy)]
+pub struct PacketRef<'a> {
+    header: PacketHeader,
+    /// Extensions (including unknown ones), as found in the packet
+    extensions: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parses a packet from a byte slice, which must contain a whole datagram.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<PacketRef<'a>, ParseError> {
         let header = try!(PacketHeader::from_bytes(buf));
 
-        let mut extensions = Vec::new();
         let mut idx = HEADER_SIZE;
         let mut kind = header.extension;
 
@@ -368,7 +623,7 @@ impl Decodable for Packet {
             return Err(ParseError::InvalidExtensionLength);
         }
 
-        // Consume known extensions and skip over unknown ones
+        // Validate the chain of extensions, without looking into them
         while idx < buf.len() && kind != 0 {
             if buf.len() < idx + 2 {
                 return Err(ParseError::InvalidPacketLength);
@@ -381,18 +636,10 @@ impl Decodable for Packet {
             // - non-zero,
             // - multiple of 4,
             // - does not exceed packet length
-            if len == 0 || len % 4 != 0 || payload_start > buf.len() {
+            if len == 0 || !len.is_multiple_of(4) || payload_start > buf.len() {
                 return Err(ParseError::InvalidExtensionLength);
             }
 
-            if kind == ExtensionType::SelectiveAck as u8 { // or more generally, a known kind
-                let extension = Extension {
-                    ty: ExtensionType::SelectiveAck,
-                    data: buf[extension_start..payload_start].to_vec(),
-                };
-                extensions.push(extension);
-            }
-
             kind = buf[idx];
             idx += len + 2;
         }
@@ -401,24 +648,152 @@ impl Decodable for Packet {
             return Err(ParseError::InvalidPacketLength);
         }
 
-        let mut payload;
-        if idx < buf.len() {
-            let payload_length = buf.len() - idx;
-            payload = Vec::with_capacity(payload_length);
-            unsafe {
-                use std::ptr;
-                ptr::copy(buf.as_ptr().offset(idx as isize), payload.as_mut_ptr(), payload_length);
-                payload.set_len(payload_length);
-            }
-        } else {
-            payload = Vec::new();
+        Ok(PacketRef {
+            header,
+            extensions: &buf[HEADER_SIZE..idx],
+            payload: &buf[idx..],
+        })
+    }
+
+    make_getters!();
+
+    /// Returns the packet's payload.
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Returns an iterator over the packet's extensions, including unknown ones.
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions { next: self.header.extension, buf: self.extensions }
+    }
+
+    /// Returns the bitmask of the packet's selective acknowledgement extension, if any.
+    pub fn sack(&self) -> Option<&'a [u8]> {
+        self.extensions()
+            .find(|e| e.get_type() == ExtensionType::SelectiveAck)
+            .map(|e| e.data())
+    }
+
+    /// Returns the length of the packet in bytes.
+    // Never zero, as every packet has a header
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        HEADER_SIZE + self.extensions.len() + self.payload.len()
+    }
+
+    /// Returns the bitmask of the packet's extension bits extension, if any.
+    pub fn extension_bits(&self) -> Option<&'a [u8]> {
+        self.extensions()
+            .find(|e| e.get_type() == ExtensionType::ExtensionBits)
+            .map(|e| e.data())
+    }
+
+    /// Copies the packet into `packet`, along with all of its extensions, reusing the memory
+    /// `packet` already holds for its payload.
+    pub(crate) fn copy_into(&self, packet: &mut Packet) {
+        packet.header = self.header;
+        packet.extensions.clear();
+        packet.extensions.extend(self.extensions().map(|e| e.to_extension()));
+        packet.payload.clear();
+        packet.payload.extend_from_slice(self.payload);
+    }
+
+    /// Copies the packet into an owned `Packet`, along with all of its extensions.
+    pub fn to_packet(&self) -> Packet {
+        Packet {
+            header: self.header,
+            extensions: self.extensions().map(|e| e.to_extension()).collect(),
+            payload: self.payload.to_vec(),
         }
+    }
+}
 
-        Ok(Packet {
-            header: header,
-            extensions: extensions,
-            payload: payload,
-        })
+impl<'a> fmt::Debug for PacketRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.header.fmt(f)
+    }
+}
+
+/// Builds a `Packet` field by field.
+///
+/// Fields that aren't set are zero, and the packet has no extensions or payload.
+///
+/// # Examples
+///
+/// ```
+/// use utp::packet::{PacketBuilder, PacketType};
+///
+/// let ack = PacketBuilder::new(PacketType::State)
+///     .connection_id(1000)
+///     .seq_nr(10)
+///     .ack_nr(41)
+///     .wnd_size(1 << 20)
+///     .build();
+/// assert_eq!(ack.ack_nr(), 41);
+/// ```
+pub struct PacketBuilder {
+    packet: Packet,
+}
+
+impl PacketBuilder {
+    /// Starts building a packet of the given type.
+    pub fn new(ty: PacketType) -> PacketBuilder {
+        let mut packet = Packet::new();
+        packet.set_type(ty);
+        PacketBuilder { packet }
+    }
+
+    /// Sets the connection id.
+    pub fn connection_id(mut self, connection_id: u16) -> PacketBuilder {
+        self.packet.set_connection_id(connection_id);
+        self
+    }
+
+    /// Sets the sequence number.
+    pub fn seq_nr(mut self, seq_nr: u16) -> PacketBuilder {
+        self.packet.set_seq_nr(seq_nr);
+        self
+    }
+
+    /// Sets the sequence number of the last packet acknowledged.
+    pub fn ack_nr(mut self, ack_nr: u16) -> PacketBuilder {
+        self.packet.set_ack_nr(ack_nr);
+        self
+    }
+
+    /// Sets the receive window size, in bytes.
+    pub fn wnd_size(mut self, wnd_size: u32) -> PacketBuilder {
+        self.packet.set_wnd_size(wnd_size);
+        self
+    }
+
+    /// Sets the time the packet is sent at, in microseconds.
+    pub fn timestamp_microseconds(mut self, timestamp: u32) -> PacketBuilder {
+        self.packet.set_timestamp_microseconds(timestamp);
+        self
+    }
+
+    /// Sets the last measured one-way delay from the remote peer, in microseconds.
+    pub fn timestamp_difference_microseconds(mut self, difference: u32) -> PacketBuilder {
+        self.packet.set_timestamp_difference_microseconds(difference);
+        self
+    }
+
+    /// Appends a header extension.
+    pub fn extension(mut self, extension: Extension) -> PacketBuilder {
+        self.packet.extensions.push(extension);
+        self
+    }
+
+    /// Sets the payload.
+    pub fn payload(mut self, payload: &[u8]) -> PacketBuilder {
+        self.packet.payload = payload.to_vec();
+        self
+    }
+
+    /// Returns the packet.
+    pub fn build(self) -> Packet {
+        self.packet
     }
 }
 
@@ -525,11 +900,16 @@ mod tests {
         assert_eq!(packet.seq_nr(), 43859);
         assert_eq!(packet.ack_nr(), 15093);
         assert!(packet.payload.is_empty());
-        assert!(packet.extensions.len() == 1);
+        assert!(packet.extensions.len() == 2);
         assert!(packet.extensions[0].ty == ExtensionType::SelectiveAck);
         assert!(packet.extensions[0].data == vec!(0,0,0,0));
         assert!(packet.extensions[0].len() == 1 + packet.extensions[0].data.len());
         assert!(packet.extensions[0].len() == 5);
+        assert!(packet.extensions[1].ty == ExtensionType::Other(0xff));
+        assert!(packet.extensions[1].data == vec!(0,0,0,0));
+
+        // Unknown extensions survive a roundtrip
+        assert_eq!(packet.to_bytes(), &buf[..]);
     }
 
     #[test]
@@ -540,12 +920,12 @@ mod tests {
         let window_size: u32 = 1048576;
         let mut pkt = Packet::new();
         pkt.set_type(Data);
-        pkt.header.timestamp_microseconds = timestamp.to_be();
-        pkt.header.timestamp_difference_microseconds = timestamp_diff.to_be();
-        pkt.header.connection_id = connection_id.to_be();
-        pkt.header.seq_nr = seq_nr.to_be();
-        pkt.header.ack_nr = ack_nr.to_be();
-        pkt.header.wnd_size = window_size.to_be();
+        pkt.header.timestamp_microseconds = timestamp;
+        pkt.header.timestamp_difference_microseconds = timestamp_diff;
+        pkt.header.connection_id = connection_id;
+        pkt.header.seq_nr = seq_nr;
+        pkt.header.ack_nr = ack_nr;
+        pkt.header.wnd_size = window_size;
         pkt.payload = payload.clone();
         let header = pkt.header;
         let buf = [0x01, 0x00, 0x41, 0xa8, 0x00, 0xe9, 0x03, 0x89,
@@ -575,12 +955,12 @@ mod tests {
         let (connection_id, seq_nr, ack_nr): (u16, u16, u16) = (16808, 15090, 17096);
         let window_size: u32 = 1048576;
         let mut pkt = Packet::with_payload(&payload[..]);
-        pkt.header.timestamp_microseconds = timestamp.to_be();
-        pkt.header.timestamp_difference_microseconds = timestamp_diff.to_be();
-        pkt.header.connection_id = connection_id.to_be();
-        pkt.header.seq_nr = seq_nr.to_be();
-        pkt.header.ack_nr = ack_nr.to_be();
-        pkt.header.wnd_size = window_size.to_be();
+        pkt.header.timestamp_microseconds = timestamp;
+        pkt.header.timestamp_difference_microseconds = timestamp_diff;
+        pkt.header.connection_id = connection_id;
+        pkt.header.seq_nr = seq_nr;
+        pkt.header.ack_nr = ack_nr;
+        pkt.header.wnd_size = window_size;
         pkt.payload = payload.clone();
         let header = pkt.header;
         let buf = [0x01, 0x00, 0x41, 0xa8, 0x00, 0xe9, 0x03, 0x89,
@@ -607,13 +987,13 @@ mod tests {
     fn test_packet_encode_with_multiple_extensions() {
         let mut packet = Packet::new();
         let extension = super::Extension { ty: ExtensionType::SelectiveAck, data: vec!(1,2,3,4) };
-        packet.header.extension = extension.ty as u8;
+        packet.header.extension = u8::from(extension.ty);
         packet.extensions.push(extension.clone());
         packet.extensions.push(extension.clone());
         let bytes = packet.to_bytes();
         assert_eq!(bytes.len(), HEADER_SIZE + (extension.len() + 1) * 2);
-        assert_eq!(bytes[1], extension.ty as u8);
-        assert_eq!(bytes[HEADER_SIZE], extension.ty as u8);
+        assert_eq!(bytes[1], u8::from(extension.ty));
+        assert_eq!(bytes[HEADER_SIZE], u8::from(extension.ty));
         assert_eq!(bytes[HEADER_SIZE + 1], extension.data.len() as u8);
         assert_eq!(bytes[HEADER_SIZE + extension.len() + 1], 0);
         assert_eq!(bytes[HEADER_SIZE + extension.len() + 2], extension.data.len() as u8);
@@ -628,6 +1008,125 @@ mod tests {
         assert_eq!(&Packet::from_bytes(&buf).unwrap().to_bytes()[..], &buf[..]);
     }
 
+    #[test]
+    fn test_packet_ref_decode() {
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0xff, 0x04, 0x01, 0x00, 0x00, 0x00,
+                   0x00, 0x04, 0x00, 0x00, 0x00, 0x00, // Imaginary extension
+                   0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x0a];
+        let packet = PacketRef::from_bytes(&buf).unwrap();
+        assert_eq!(packet.get_type(), State);
+        assert_eq!(packet.connection_id(), 16807);
+        assert_eq!(packet.wnd_size(), 1500);
+        assert_eq!(packet.seq_nr(), 43859);
+        assert_eq!(packet.ack_nr(), 15093);
+        assert_eq!(packet.len(), buf.len());
+
+        // The payload is borrowed, not copied
+        assert_eq!(packet.payload(), &buf[32..]);
+        assert_eq!(packet.payload().as_ptr(), buf[32..].as_ptr());
+
+        let extensions: Vec<ExtensionRef> = packet.extensions().collect();
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
+        assert_eq!(extensions[0].data(), &[1, 0, 0, 0]);
+        assert_eq!(extensions[0].iter().count_ones(), 1);
+        assert_eq!(extensions[1].get_type(), ExtensionType::Other(0xff));
+        assert_eq!(packet.sack(), Some(&[1, 0, 0, 0][..]));
+
+        // Owned copies keep the unknown extension
+        let owned = packet.to_packet();
+        assert_eq!(owned.payload, &buf[32..]);
+        assert_eq!(owned.extensions.len(), 2);
+        assert_eq!(owned.to_bytes(), &buf[..]);
+    }
+
+    #[test]
+    fn test_packet_ref_decode_errors() {
+        assert!(PacketRef::from_bytes(&[]).is_err());
+        let buf = [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(PacketRef::from_bytes(&buf).is_err());
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut packet = Packet::with_payload(b"Hello\n");
+        packet.set_seq_nr(15090);
+        packet.set_sack(vec![1, 2, 3, 4]);
+        let mut buf = [0xff; 100];
+        let len = packet.encode_into(&mut buf);
+        assert_eq!(len, packet.len());
+        assert_eq!(&buf[..len], &packet.to_bytes()[..]);
+        assert!(buf[len..].iter().all(|&b| b == 0xff));
+
+        let decoded = PacketRef::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(decoded.seq_nr(), 15090);
+        assert_eq!(decoded.payload(), b"Hello\n");
+        assert_eq!(decoded.extensions().next().unwrap().data(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_encode_into_short_buffer() {
+        let packet = Packet::with_payload(b"Hello\n");
+        let mut buf = [0; HEADER_SIZE];
+        packet.encode_into(&mut buf);
+    }
+
+    #[test]
+    fn test_try_encode_into() {
+        let packet = Packet::with_payload(b"Hello\n");
+        let mut buf = [0; HEADER_SIZE];
+        assert_eq!(packet.try_encode_into(&mut buf), None);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        let mut buf = [0; 100];
+        assert_eq!(packet.try_encode_into(&mut buf), Some(packet.len()));
+        assert_eq!(&buf[..packet.len()], &packet.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_packet_builder() {
+        let sack = Extension::new(ExtensionType::SelectiveAck, vec![1, 0, 0, 0]).unwrap();
+        let packet = PacketBuilder::new(Data)
+            .connection_id(16808)
+            .seq_nr(15090)
+            .ack_nr(27769)
+            .wnd_size(1048576)
+            .timestamp_microseconds(2570047530)
+            .timestamp_difference_microseconds(2672436769)
+            .extension(sack)
+            .payload(b"Hello\n")
+            .build();
+
+        let bytes = packet.to_bytes();
+        let decoded = PacketRef::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.get_type(), Data);
+        assert_eq!(decoded.version(), 1);
+        assert_eq!(decoded.connection_id(), 16808);
+        assert_eq!(decoded.seq_nr(), 15090);
+        assert_eq!(decoded.ack_nr(), 27769);
+        assert_eq!(decoded.wnd_size(), 1048576);
+        assert_eq!(decoded.timestamp_microseconds(), 2570047530);
+        assert_eq!(decoded.timestamp_difference_microseconds(), 2672436769);
+        assert_eq!(decoded.sack(), Some(&[1, 0, 0, 0][..]));
+        assert_eq!(decoded.payload(), b"Hello\n");
+        assert_eq!(packet.sack(), decoded.sack());
+    }
+
+    #[test]
+    fn test_extension_length() {
+        for &len in [0, 3, 5, 256].iter() {
+            assert_eq!(Extension::new(ExtensionType::SelectiveAck, vec![0; len]),
+                       Err(ParseError::InvalidExtensionLength));
+        }
+        for &len in [4, 8, 252].iter() {
+            let extension = Extension::new(ExtensionType::SelectiveAck, vec![0; len]).unwrap();
+            assert_eq!(extension.data().len(), len);
+        }
+    }
+
     #[test]
     fn test_decode_evil_sequence() {
         let buf = [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
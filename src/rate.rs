@@ -0,0 +1,244 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Longest burst a token bucket allows, as a fraction of a second's worth of its rate.
+const BURST_FRACTION: u64 = 10; // a tenth of a second
+/// Smallest burst a token bucket with a non-zero rate allows, so that a full packet always fits.
+const MIN_BURST: u64 = 3000; // bytes
+
+/// Bandwidth limits that any number of sockets (and listeners, for the sockets they accept) can
+/// share, capping the total rate at which they send and receive data.
+///
+/// Each direction is a token bucket holding up to a tenth of a second's worth of data, so rates
+/// are enforced smoothly rather than per second. Limits apply to packet payloads, not to headers
+/// or acknowledgements, and can be changed at any time through any clone of the limiter.
+///
+/// Uploads are throttled by holding data in the send buffer. Downloads are throttled by shrinking
+/// the receive window advertised to remote peers, which stop sending while it's closed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::{RateLimiter, UtpListener};
+///
+/// let limiter = RateLimiter::new();
+/// limiter.set_upload_limit(Some(100 * 1024));
+/// limiter.set_download_limit(Some(500 * 1024));
+///
+/// let mut listener = UtpListener::bind("0.0.0.0:6881").unwrap();
+/// listener.set_rate_limiter(Some(limiter.clone()));
+///
+/// // Later on, from any thread
+/// limiter.set_upload_limit(None);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl RateLimiter {
+    /// Creates a limiter without any limit.
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Sets the most data sent per second, in bytes, or removes the limit if `None`.
+    pub fn set_upload_limit(&self, limit: Option<u64>) {
+        self.lock().upload.set_rate(limit)
+    }
+
+    /// Returns the most data sent per second. See `set_upload_limit`.
+    pub fn upload_limit(&self) -> Option<u64> {
+        self.lock().upload.rate
+    }
+
+    /// Sets the most data received per second, in bytes, or removes the limit if `None`.
+    pub fn set_download_limit(&self, limit: Option<u64>) {
+        self.lock().download.set_rate(limit)
+    }
+
+    /// Returns the most data received per second. See `set_download_limit`.
+    pub fn download_limit(&self) -> Option<u64> {
+        self.lock().download.rate
+    }
+
+    /// Returns how long to wait before sending more data.
+    pub(crate) fn upload_delay(&self, now: Instant) -> Duration {
+        self.lock().upload.delay(now)
+    }
+
+    /// Accounts for `len` bytes sent, which may take the limiter into debt.
+    pub(crate) fn consume_upload(&self, now: Instant, len: usize) {
+        self.lock().upload.consume(now, len)
+    }
+
+    /// Returns how much data may be received right now, or `None` if downloads aren't limited.
+    pub(crate) fn download_allowance(&self, now: Instant) -> Option<u64> {
+        self.lock().download.allowance(now)
+    }
+
+    /// Returns how long to wait before receiving more data.
+    pub(crate) fn download_delay(&self, now: Instant) -> Duration {
+        self.lock().download.delay(now)
+    }
+
+    /// Accounts for `len` bytes received, which may take the limiter into debt.
+    pub(crate) fn consume_download(&self, now: Instant, len: usize) {
+        self.lock().download.consume(now, len)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A token bucket, refilled at a fixed rate in bytes per second.
+///
+/// A packet goes through as long as there's any token left, even if it's larger than what's left,
+/// and the debt is paid off before the next one does. This keeps the average rate right without
+/// splitting packets.
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Tokens added per second, or `None` for no limit
+    rate: Option<u64>,
+    /// Tokens available, negative while in debt
+    tokens: i64,
+    /// When tokens were last added
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+        // Start with a full bucket, and forget about any debt run up under the old rate
+        self.tokens = self.burst() as i64;
+        self.updated = None;
+    }
+
+    /// Returns the most tokens the bucket holds.
+    fn burst(&self) -> u64 {
+        match self.rate {
+            Some(0) | None => 0,
+            Some(rate) => (rate / BURST_FRACTION).max(MIN_BURST),
+        }
+    }
+
+    /// Adds the tokens accumulated since the last update.
+    fn refill(&mut self, now: Instant) {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated);
+            let added = (elapsed.as_secs() * rate)
+                .saturating_add(elapsed.subsec_nanos() as u64 * rate / 1_000_000_000);
+            self.tokens = self.tokens.saturating_add(added as i64).min(self.burst() as i64);
+        }
+        // A clock going backwards (e.g., sockets on different transports sharing the limiter)
+        // only delays the next refill
+        if self.updated.map(|updated| now > updated).unwrap_or(true) {
+            self.updated = Some(now);
+        }
+    }
+
+    fn allowance(&mut self, now: Instant) -> Option<u64> {
+        match self.rate {
+            Some(_) => {
+                self.refill(now);
+                Some(self.tokens.max(0) as u64)
+            },
+            None => None,
+        }
+    }
+
+    fn delay(&mut self, now: Instant) -> Duration {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return Duration::from_secs(0),
+        };
+        self.refill(now);
+        if self.tokens > 0 {
+            return Duration::from_secs(0);
+        }
+        if rate == 0 {
+            // Nothing ever goes through, so check back every now and then for a new limit
+            return Duration::from_secs(1);
+        }
+        let missing = (1 - self.tokens) as u64;
+        Duration::from_secs(missing / rate) +
+            Duration::from_nanos((missing % rate) * 1_000_000_000 / rate)
+    }
+
+    fn consume(&mut self, now: Instant, len: usize) {
+        if self.rate.is_none() {
+            return;
+        }
+        self.refill(now);
+        self.tokens = self.tokens.saturating_sub(len as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::RateLimiter;
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        limiter.consume_upload(now, 1 << 30);
+        assert_eq!(limiter.upload_delay(now), Duration::from_secs(0));
+        limiter.consume_download(now, 1 << 30);
+        assert_eq!(limiter.download_allowance(now), None);
+    }
+
+    #[test]
+    fn test_debt_is_paid_off_at_the_limit() {
+        let limiter = RateLimiter::new();
+        limiter.set_upload_limit(Some(100_000));
+        let now = Instant::now();
+
+        // The burst goes through right away, and then the bucket runs a packet into debt
+        limiter.consume_upload(now, 9_000);
+        assert_eq!(limiter.upload_delay(now), Duration::from_secs(0));
+        limiter.consume_upload(now, 2_000);
+        assert_eq!(limiter.upload_delay(now), Duration::from_nanos(10_010_000));
+
+        // Tokens come back at the set rate, up to the burst
+        assert_eq!(limiter.upload_delay(now + Duration::from_millis(20)), Duration::from_secs(0));
+        limiter.set_download_limit(Some(100_000));
+        limiter.consume_download(now, 5_000);
+        assert_eq!(limiter.download_allowance(now), Some(5_000));
+        assert_eq!(limiter.download_allowance(now + Duration::from_secs(1)), Some(10_000));
+    }
+
+    #[test]
+    fn test_limits_change_at_runtime() {
+        let limiter = RateLimiter::new();
+        let shared = limiter.clone();
+        shared.set_download_limit(Some(1000));
+        assert_eq!(limiter.download_limit(), Some(1000));
+
+        let now = Instant::now();
+        limiter.consume_download(now, 10_000);
+        assert_eq!(limiter.download_allowance(now), Some(0));
+        assert!(limiter.download_delay(now) > Duration::from_secs(7));
+
+        // A new limit starts afresh
+        shared.set_download_limit(Some(1 << 20));
+        assert_eq!(limiter.download_allowance(now), Some((1 << 20) / 10));
+        shared.set_download_limit(Some(0));
+        assert_eq!(limiter.download_allowance(now + Duration::from_secs(60)), Some(0));
+        shared.set_download_limit(None);
+        assert_eq!(limiter.download_allowance(now), None);
+        assert_eq!(limiter.download_delay(now), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,561 @@
+use std::cmp::{min, max};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Result, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use transport::Transport;
+
+/// Impairments applied to datagrams travelling over a simulated link.
+///
+/// The default is a perfect link, with no delay, loss or bandwidth limit.
+#[derive(Clone, Debug, Default)]
+pub struct Impairments {
+    /// One-way propagation delay.
+    pub delay: Duration,
+
+    /// Maximum random delay added on top of `delay`. Datagrams may be reordered as a result.
+    pub jitter: Duration,
+
+    /// Probability of losing a datagram, between 0 and 1.
+    pub loss: f64,
+
+    /// Probability of delivering a datagram twice, between 0 and 1.
+    pub duplication: f64,
+
+    /// Bandwidth of the link's bottleneck in bytes per second, or `None` if unlimited.
+    pub bandwidth: Option<u64>,
+
+    /// Size in bytes of the queue in front of the bottleneck, or `None` if unlimited. Datagrams
+    /// arriving at a full queue are dropped.
+    pub queue_limit: Option<usize>,
+}
+
+/// One direction of the path between two endpoints.
+struct Link {
+    impairments: Impairments,
+    rng: XorShiftRng,
+    /// When the bottleneck finishes transmitting the datagrams queued so far
+    busy_until: Duration,
+}
+
+impl Link {
+    fn new(seed: u64, from: SocketAddr, to: SocketAddr, impairments: Impairments) -> Link {
+        // Every link gets its own random sequence, so that traffic on one link doesn't change
+        // what happens on another
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in format!("{}>{}", from, to).bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        let seed = [seed as u32, (seed >> 32) as u32, hash as u32, (hash >> 32) as u32 | 1];
+        Link {
+            impairments,
+            rng: XorShiftRng::from_seed(seed),
+            busy_until: Duration::from_secs(0),
+        }
+    }
+
+    /// Sends a datagram of `len` bytes at time `now`, returning the arrival time of every copy
+    /// delivered.
+    fn transmit(&mut self, now: Duration, len: usize) -> Vec<Duration> {
+        // Always draw the same random numbers, so that changing one impairment doesn't affect
+        // the others
+        let lost = self.rng.gen::<f64>() < self.impairments.loss;
+        let duplicated = self.rng.gen::<f64>() < self.impairments.duplication;
+        let jitters = [self.rng.gen::<f64>(), self.rng.gen::<f64>()];
+
+        let mut departure = now;
+        if let Some(bandwidth) = self.impairments.bandwidth {
+            let start = max(now, self.busy_until);
+            let queued = (start - now).as_nanos() * bandwidth as u128 / 1_000_000_000;
+            if self.impairments.queue_limit.map(|limit| queued + len as u128 > limit as u128)
+                .unwrap_or(false) {
+                return vec!();
+            }
+            let transmission = len as u128 * 1_000_000_000 / max(bandwidth, 1) as u128;
+            self.busy_until = start + Duration::from_nanos(transmission as u64);
+            departure = self.busy_until;
+        }
+
+        if lost {
+            return vec!();
+        }
+
+        let copies = if duplicated { 2 } else { 1 };
+        jitters[..copies].iter().map(|&j| {
+            let jitter = (self.impairments.jitter.as_nanos() as f64 * j) as u64;
+            departure + self.impairments.delay + Duration::from_nanos(jitter)
+        }).collect()
+    }
+}
+
+struct Endpoint {
+    /// Datagrams on their way, by arrival time and order of sending
+    inbox: BTreeMap<(Duration, u64), (Vec<u8>, SocketAddr)>,
+    read_timeout: Option<Duration>,
+}
+
+impl Endpoint {
+    fn next_arrival(&self) -> Option<Duration> {
+        self.inbox.keys().next().map(|&(arrival, _)| arrival)
+    }
+}
+
+struct State {
+    /// Virtual time elapsed since the network was created
+    now: Duration,
+    seed: u64,
+    next_id: u64,
+    default_impairments: Impairments,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    /// Endpoint and deadline of every thread waiting for a datagram
+    waiters: HashMap<u64, (SocketAddr, Option<Duration>)>,
+}
+
+impl State {
+    fn link(&mut self, from: SocketAddr, to: SocketAddr) -> &mut Link {
+        let (seed, impairments) = (self.seed, self.default_impairments.clone());
+        self.links.entry((from, to)).or_insert_with(|| Link::new(seed, from, to, impairments))
+    }
+
+    /// Moves the clock forward to the next event, if every endpoint is waiting for one.
+    fn advance(&mut self) -> bool {
+        // Time stands still while any endpoint is busy
+        for addr in self.endpoints.keys() {
+            if !self.waiters.values().any(|&(a, _)| a == *addr) {
+                return false;
+            }
+        }
+
+        let mut next = None;
+        for &(addr, deadline) in self.waiters.values() {
+            for event in self.endpoints[&addr].next_arrival().into_iter().chain(deadline) {
+                // Someone still has to wake up to what already happened
+                if event <= self.now {
+                    return false;
+                }
+                next = Some(next.map_or(event, |n| min(n, event)));
+            }
+        }
+
+        match next {
+            Some(next) => {
+                self.now = next;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    epoch: Instant,
+}
+
+impl Shared {
+    fn lock<'a>(&'a self) -> MutexGuard<'a, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A simulated network of datagram endpoints, running on a virtual clock.
+///
+/// Endpoints are created with `bind` and may be used as the transport of a `UtpSocket` (see
+/// `UtpSocket::from_transport`). Every link between two endpoints applies its own `Impairments`,
+/// with random decisions drawn from a generator seeded by the network's seed.
+///
+/// The clock only moves forward when every endpoint is waiting for a datagram, at which point it
+/// jumps straight to the next arrival or read timeout. Long transfers and timeouts therefore take
+/// little real time, and runs with the same seed see the same network behavior. An endpoint that
+/// is neither waiting nor dropped stops the clock for everyone, so sockets should be dropped as
+/// soon as they are no longer used.
+///
+/// # Examples
+///
+/// ```
+/// use utp::{SimNetwork, Impairments, UtpSocket};
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let network = SimNetwork::new(42);
+/// let (a, b) = ("10.0.0.1:1000".parse().unwrap(), "10.0.0.2:2000".parse().unwrap());
+/// network.set_default_impairments(Impairments {
+///     delay: Duration::from_millis(50),
+///     ..Impairments::default()
+/// });
+///
+/// let mut server = UtpSocket::from_transport(network.bind(a).unwrap()).unwrap();
+/// let client = network.bind(b).unwrap();
+/// let child = thread::spawn(move || {
+///     let mut client = UtpSocket::connect_with_transport(client, a).unwrap();
+///     client.send_to(b"hello").unwrap();
+///     client.close().unwrap();
+/// });
+///
+/// let mut buf = [0; 1500];
+/// let (read, _src) = server.recv_from(&mut buf).unwrap();
+/// assert_eq!(&buf[..read], b"hello");
+///
+/// // Wait for the end of the stream, then let the clock run without us
+/// server.recv_from(&mut buf).unwrap();
+/// server.close().unwrap();
+/// drop(server);
+/// child.join().unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SimNetwork {
+    shared: Arc<Shared>,
+}
+
+impl SimNetwork {
+    /// Creates an empty network, whose random decisions are seeded by `seed`.
+    pub fn new(seed: u64) -> SimNetwork {
+        let state = State {
+            now: Duration::from_secs(0),
+            seed,
+            next_id: 0,
+            default_impairments: Impairments::default(),
+            links: HashMap::new(),
+            endpoints: HashMap::new(),
+            waiters: HashMap::new(),
+        };
+        let shared = Shared { state: Mutex::new(state), changed: Condvar::new(), epoch: Instant::now() };
+        SimNetwork { shared: Arc::new(shared) }
+    }
+
+    /// Creates an endpoint with the given address.
+    ///
+    /// Fails with `ErrorKind::AddrInUse` if another endpoint has the same address.
+    pub fn bind(&self, addr: SocketAddr) -> Result<SimTransport> {
+        let mut state = self.shared.lock();
+        if state.endpoints.contains_key(&addr) {
+            return Err(Error::new(ErrorKind::AddrInUse, "Address already in use"));
+        }
+        state.endpoints.insert(addr, Endpoint { inbox: BTreeMap::new(), read_timeout: None });
+        Ok(SimTransport { shared: self.shared.clone(), addr })
+    }
+
+    /// Sets the impairments of the link carrying datagrams from `from` to `to`.
+    pub fn set_impairments(&self, from: SocketAddr, to: SocketAddr, impairments: Impairments) {
+        self.shared.lock().link(from, to).impairments = impairments;
+    }
+
+    /// Sets the impairments of every link not configured with `set_impairments`.
+    pub fn set_default_impairments(&self, impairments: Impairments) {
+        let mut state = self.shared.lock();
+        for link in state.links.values_mut() {
+            link.impairments = impairments.clone();
+        }
+        state.default_impairments = impairments;
+    }
+
+    /// Returns the virtual time elapsed since the network was created.
+    pub fn elapsed(&self) -> Duration {
+        self.shared.lock().now
+    }
+}
+
+/// An endpoint of a `SimNetwork`, created by `SimNetwork::bind`.
+///
+/// The endpoint is removed from the network when dropped.
+pub struct SimTransport {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+}
+
+impl SimTransport {
+    /// Waits for a datagram, then copies it into `buf`, removing it from the inbox if `remove`.
+    fn receive(&self, buf: &mut [u8], remove: bool) -> Result<(usize, SocketAddr)> {
+        let mut state = self.shared.lock();
+        let deadline = state.endpoints[&self.addr].read_timeout.map(|t| state.now + t);
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiters.insert(id, (self.addr, deadline));
+
+        loop {
+            let now = state.now;
+            let next = state.endpoints[&self.addr].inbox.keys().next().cloned();
+            if let Some(key) = next.and_then(|key| if key.0 <= now { Some(key) } else { None }) {
+                let src = {
+                    let inbox = &mut state.endpoints.get_mut(&self.addr).unwrap().inbox;
+                    let (len, src) = {
+                        let &(ref datagram, src) = &inbox[&key];
+                        let len = min(buf.len(), datagram.len());
+                        buf[..len].copy_from_slice(&datagram[..len]);
+                        (len, src)
+                    };
+                    if remove {
+                        inbox.remove(&key);
+                    }
+                    (len, src)
+                };
+                state.waiters.remove(&id);
+                self.shared.changed.notify_all();
+                return Ok(src);
+            }
+
+            if deadline.map(|d| d <= now).unwrap_or(false) {
+                state.waiters.remove(&id);
+                self.shared.changed.notify_all();
+                return Err(Error::new(ErrorKind::WouldBlock, "No datagram available"));
+            }
+
+            if state.advance() {
+                self.shared.changed.notify_all();
+            } else {
+                state = self.shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+        }
+    }
+}
+
+impl Transport for SimTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let mut state = self.shared.lock();
+
+        // Like UDP, datagrams to nowhere silently disappear
+        if !state.endpoints.contains_key(&addr) {
+            return Ok(buf.len());
+        }
+
+        let now = state.now;
+        let arrivals = state.link(self.addr, addr).transmit(now, buf.len());
+        for arrival in arrivals {
+            let id = state.next_id;
+            state.next_id += 1;
+            let endpoint = state.endpoints.get_mut(&addr).unwrap();
+            endpoint.inbox.insert((arrival, id), (buf.to_vec(), self.addr));
+        }
+        self.shared.changed.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.receive(buf, true)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.receive(buf, false)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Zero timeout"));
+        }
+        self.shared.lock().endpoints.get_mut(&self.addr).unwrap().read_timeout = timeout;
+        Ok(())
+    }
+
+    fn now(&self) -> Instant {
+        self.shared.epoch + self.shared.lock().now
+    }
+
+    fn now_microseconds(&self) -> u32 {
+        let now = self.shared.lock().now;
+        (now.as_secs() * 1_000_000 + now.subsec_nanos() as u64 / 1000) as u32
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        self.shared.lock().endpoints.remove(&self.addr);
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use socket::UtpSocket;
+    use transport::Transport;
+    use super::{SimNetwork, Impairments};
+
+    macro_rules! iotry {
+        ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{:?}", e) })
+    }
+
+    fn addrs() -> (SocketAddr, SocketAddr) {
+        ("10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap())
+    }
+
+    /// Sends `len` bytes from one socket to another, returning what was received, how long it took
+    /// and the longest one-way delay seen by the receiver.
+    fn transfer(network: &SimNetwork, len: usize) -> (Vec<u8>, Duration, Duration) {
+        let (server_addr, client_addr) = addrs();
+        let data = (0..len).map(|idx| idx as u8).collect::<Vec<u8>>();
+        let mut server = iotry!(UtpSocket::from_transport(iotry!(network.bind(server_addr))));
+        let client = iotry!(network.bind(client_addr));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect_with_transport(client, server_addr));
+            iotry!(client.send_to(&data[..]));
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; 1500];
+        let mut received = vec!();
+        let mut max_delay = 0;
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((read, _src)) => received.extend(buf[..read].to_vec()),
+                Err(e) => panic!("{:?}", e),
+            }
+            max_delay = max_delay.max(server.received_delay());
+        }
+        iotry!(server.close());
+        drop(server);
+        child.join().unwrap();
+
+        (received, network.elapsed(), Duration::from_micros(max_delay as u64))
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let network = SimNetwork::new(1);
+        let (addr, _) = addrs();
+        let endpoint = iotry!(network.bind(addr));
+        iotry!(endpoint.set_read_timeout(Some(Duration::from_secs(3600))));
+
+        // Nothing will ever arrive, so the clock jumps to the timeout
+        match endpoint.recv_from(&mut [0; 10]) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            x => panic!("Expected ErrorKind::WouldBlock, got {:?}", x),
+        }
+        assert_eq!(network.elapsed(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_bind_twice() {
+        let network = SimNetwork::new(1);
+        let (addr, _) = addrs();
+        let _endpoint = iotry!(network.bind(addr));
+        match network.bind(addr) {
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse => (),
+            x => panic!("Expected ErrorKind::AddrInUse, got {:?}", x.err()),
+        }
+    }
+
+    #[test]
+    fn test_bottleneck() {
+        let network = SimNetwork::new(1);
+        network.set_default_impairments(Impairments {
+            delay: Duration::from_millis(10),
+            bandwidth: Some(100_000),
+            ..Impairments::default()
+        });
+
+        const LEN: usize = 50_000;
+        let (received, elapsed, _) = transfer(&network, LEN);
+        assert_eq!(received.len(), LEN);
+        assert!(received.iter().enumerate().all(|(idx, &x)| x == idx as u8));
+
+        // Half a second at 100 kB/s, plus headers and the handshake
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_jitter_and_duplication() {
+        let network = SimNetwork::new(1);
+        network.set_default_impairments(Impairments {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            duplication: 0.1,
+            ..Impairments::default()
+        });
+
+        const LEN: usize = 50_000;
+        let (received, _, _) = transfer(&network, LEN);
+        assert_eq!(received.len(), LEN);
+        assert!(received.iter().enumerate().all(|(idx, &x)| x == idx as u8));
+    }
+
+    #[test]
+    fn test_same_seed_same_behavior() {
+        let run = |seed| {
+            let network = SimNetwork::new(seed);
+            network.set_default_impairments(Impairments {
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(5),
+                bandwidth: Some(1_000_000),
+                ..Impairments::default()
+            });
+            transfer(&network, 20_000).1
+        };
+
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn test_random_loss() {
+        // Without retransmission timeouts, losing the last packets in flight stalled the transfer
+        // for days of virtual time
+        for &(loss, limit) in &[(0.01, 1), (0.02, 2), (0.05, 5)] {
+            for seed in 1..5 {
+                let network = SimNetwork::new(seed);
+                network.set_default_impairments(Impairments {
+                    delay: Duration::from_millis(20),
+                    loss,
+                    ..Impairments::default()
+                });
+
+                const LEN: usize = 100_000;
+                let (received, elapsed, _) = transfer(&network, LEN);
+                assert_eq!(received.len(), LEN);
+                assert!(received.iter().enumerate().all(|(idx, &x)| x == idx as u8));
+                assert!(elapsed < Duration::from_secs(limit),
+                        "{} loss, seed {}: {:?}", loss, seed, elapsed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_deep_queue() {
+        // The bottleneck queue holds 8 seconds of data, but LEDBAT keeps it near its target delay
+        // while still using the whole link
+        let network = SimNetwork::new(1);
+        network.set_default_impairments(Impairments {
+            delay: Duration::from_millis(10),
+            bandwidth: Some(125_000),
+            queue_limit: Some(1_000_000),
+            ..Impairments::default()
+        });
+
+        const LEN: usize = 500_000;
+        let (received, elapsed, max_delay) = transfer(&network, LEN);
+        assert_eq!(received.len(), LEN);
+        assert!(max_delay < Duration::from_millis(250), "{:?}", max_delay);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_shallow_queue() {
+        // The bottleneck queue fills up long before LEDBAT's target delay, so drops are the only
+        // sign of congestion
+        let network = SimNetwork::new(1);
+        network.set_default_impairments(Impairments {
+            delay: Duration::from_millis(10),
+            bandwidth: Some(125_000),
+            queue_limit: Some(8_000),
+            ..Impairments::default()
+        });
+
+        const LEN: usize = 500_000;
+        let (received, elapsed, _) = transfer(&network, LEN);
+        assert_eq!(received.len(), LEN);
+        assert!(received.iter().enumerate().all(|(idx, &x)| x == idx as u8));
+        assert!(elapsed < Duration::from_secs(6), "{:?}", elapsed);
+    }
+}
@@ -1,9 +1,21 @@
 use std::cmp::{min, max};
 use std::collections::VecDeque;
-use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
-use std::io::{Result, Error, ErrorKind};
-use util::{now_microseconds, ewma};
-use packet::{Packet, PacketType, Encodable, Decodable, ExtensionType, HEADER_SIZE};
+use std::net::{ToSocketAddrs, SocketAddr, IpAddr, UdpSocket, Shutdown};
+use std::io::{self, ErrorKind, IoSlice, IoSliceMut};
+use std::iter;
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use util::ewma;
+use error::{self, Error, Result, Timeout};
+use transport::{self, Transport, RecvBatch};
+use extension::{ExtensionHandler, ExtensionRegistry};
+use rate::RateLimiter;
+use happy_eyeballs;
+use packet::{Packet, PacketRef, PacketType, Encodable, Extension, ExtensionType, HEADER_SIZE};
 use rand;
 
 // For simplicity's sake, let us assume no packet will ever exceed the
@@ -13,37 +25,71 @@ const GAIN: f64 = 1.0;
 const ALLOWED_INCREASE: u32 = 1;
 const TARGET: i64 = 100_000; // 100 milliseconds
 const MSS: u32 = 1400;
+const MAX_PAYLOAD: usize = MSS as usize - HEADER_SIZE;
 const MIN_CWND: u32 = 2;
 const INIT_CWND: u32 = 2;
 const INITIAL_CONGESTION_TIMEOUT: u64 = 1000; // one second
 const MIN_CONGESTION_TIMEOUT: u64 = 500; // 500 ms
+const MAX_RETRANSMISSIONS: u32 = 8; // retransmission timeouts in a row before giving up
+const LOSS_THRESHOLD: u32 = 3; // packets acknowledged past a missing one before it's deemed lost
 const MAX_CONGESTION_TIMEOUT: u64 = 60_000; // one minute
 const BASE_HISTORY: usize = 10; // base delays history size
+const MAX_SACK_LEN: usize = 32; // bytes, for packets up to 256 sequence numbers ahead
+const DEFAULT_LINGER: u64 = 30; // seconds
+const DEFAULT_KEEPALIVE: u64 = 29; // seconds, as in libutp
+const SYN_TIMEOUT: u64 = 1000; // milliseconds before sending the first SYN again, doubling after
+const SYN_ATTEMPTS: u32 = 5;
+const HANDSHAKE_POLL_INTERVAL: u64 = 100; // milliseconds between checks for an abandoned handshake
+const SIMULTANEOUS_OPEN_INTERVAL: u64 = 500; // milliseconds between SYNs
+const SIMULTANEOUS_OPEN_ATTEMPTS: u32 = 40;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20; // bytes
+const MESSAGE_HEADER_SIZE: usize = 4; // bytes, for the length of each message
+const DEFAULT_SEND_BUFFER_SIZE: usize = 1 << 20; // bytes
+const RECEIVE_BUFFER_SIZE: usize = 1 << 20; // bytes received but not read before the window closes
+const SEND_BATCH_LEN: usize = 8; // packets handed to the transport at once
+const RECV_BATCH_LEN: usize = 16; // datagrams taken from the transport at once
+const MAX_SPARE_PACKETS: usize = 32; // read packets kept to store incoming data into
+const ZERO_WINDOW_PROBE: u64 = 1000; // milliseconds of silence before probing a closed window
+
+/// Resolves an address, keeping only the first one.
+pub(crate) fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(Error::no_address),
+        Err(e) => Err(Error::AddressResolution(e)),
+    }
+}
 
-#[derive(Debug)]
-pub enum SocketError {
-    ConnectionClosed,
-    ConnectionReset,
-    InvalidPacket,
-    InvalidReply,
-    // PendingAcknowledgments,
+/// Resolves an address, keeping every result.
+pub(crate) fn resolve_all<A: ToSocketAddrs>(addr: A) -> Result<Vec<SocketAddr>> {
+    match addr.to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if addrs.is_empty() { Err(Error::no_address()) } else { Ok(addrs) }
+        },
+        Err(e) => Err(Error::AddressResolution(e)),
+    }
 }
 
-impl From<SocketError> for Error {
-    fn from(error: SocketError) -> Error {
-        use self::SocketError::*;
-        match error {
-            ConnectionClosed => Error::new(ErrorKind::NotConnected,
-                                           "The socket is closed"),
-            ConnectionReset => Error::new(ErrorKind::ConnectionReset,
-                                          "Connection reset by remote peer"),
-            InvalidPacket => Error::new(ErrorKind::Other,
-                                        "Error parsing packet"),
-            InvalidReply => Error::new(ErrorKind::ConnectionRefused,
-                                       "The remote peer sent an invalid reply"),
-            // PendingAcknowledgments => Error::new(ErrorKind::Other,
-            //                                      "Received FIN with pending unacknowledged packets")
-        }
+/// Returns the IPv4 address an IPv4-mapped IPv6 address (e.g., `::ffff:192.0.2.1`) stands for, or
+/// the address itself otherwise.
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Returns the address to reach `addr` at from a socket bound to `local`, which for an IPv4
+/// address and an IPv6 socket is the IPv4-mapped IPv6 address.
+fn mapped(local: SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        },
+        _ => addr,
     }
 }
 
@@ -70,7 +116,7 @@ struct DelayDifferenceSample {
 /// and a remote socket.
 ///
 /// The socket will be closed when the value is dropped (either explicitly or when it goes out of
-/// scope).
+/// scope), without blocking the current thread. See `set_linger`.
 ///
 /// # Examples
 ///
@@ -91,8 +137,8 @@ struct DelayDifferenceSample {
 /// socket.close();
 /// ```
 pub struct UtpSocket {
-    /// The wrapped UDP socket
-    socket: UdpSocket,
+    /// The underlying datagram transport, usually a UDP socket
+    socket: Arc<dyn Transport>,
 
     /// Remote peer
     connected_to: SocketAddr,
@@ -115,6 +161,9 @@ pub struct UtpSocket {
     /// Received but not acknowledged packets
     incoming_buffer: Vec<Packet>,
 
+    /// Packets already read, whose memory is reused for the next ones received
+    spare_packets: Vec<Packet>,
+
     /// Sent but not yet acknowledged packets
     send_window: Vec<Packet>,
 
@@ -133,14 +182,23 @@ pub struct UtpSocket {
     /// Sequence number of the last packet removed from the incoming buffer
     last_dropped: u16,
 
+    /// Sequence number of the FIN packet sent by the remote peer, marking the end of the stream
+    eof_pkt: Option<u16>,
+
+    /// Whether the remote peer acknowledged our FIN packet
+    fin_acked: bool,
+
+    /// Whether incoming data is discarded instead of delivered
+    read_shutdown: bool,
+
     /// Round-trip time to remote peer
     rtt: i32,
 
     /// Variance of the round-trip time to the remote peer
     rtt_variance: i32,
 
-    /// Data from the latest packet not yet returned in `recv_from`
-    pending_data: Vec<u8>,
+    /// Bytes of the first packet in the incoming buffer already returned in `recv_from`
+    read_offset: usize,
 
     /// Bytes in flight
     curr_window: u32,
@@ -163,8 +221,73 @@ pub struct UtpSocket {
     /// Current congestion timeout in milliseconds
     congestion_timeout: u64,
 
+    /// When the oldest unacknowledged packet (or our FIN) is sent again, unless acknowledged first
+    retransmit_at: Option<Instant>,
+
+    /// Retransmission timeouts in a row, without any acknowledgement in between
+    retransmissions: u32,
+
+    /// Latest packet sent again after being reported lost, as each one is only sent again once
+    /// until acknowledged or the retransmission timeout expires
+    fast_resent: Option<u16>,
+
+    /// Latest packet in flight when the congestion window was last cut, as losses among the
+    /// packets up to it only cut the window once
+    recovery_point: Option<u16>,
+
     /// Congestion window in bytes
     cwnd: u32,
+
+    /// How long a dropped socket keeps trying to deliver pending data (`None` for the default)
+    linger: Option<Duration>,
+
+    /// Instant after which waiting for packets fails, while lingering
+    deadline: Option<Instant>,
+
+    /// Interval between keep-alive packets sent while idle
+    keepalive: Option<Duration>,
+
+    /// How long the remote peer may stay silent before the connection fails
+    idle_timeout: Option<Duration>,
+
+    /// Maximum time spent blocked waiting for a single packet
+    poll_interval: Option<Duration>,
+
+    /// When the latest packet was sent to the remote peer
+    last_sent: Instant,
+
+    /// When the latest packet was received from the remote peer
+    last_received: Instant,
+
+    /// Whether both peers opened the connection at the same time (see `connect_simultaneous`)
+    simultaneous_open: bool,
+
+    /// Handlers of the extensions negotiated during the handshake
+    extensions: ExtensionRegistry,
+
+    /// Extensions agreed upon with the remote peer during the handshake
+    negotiated_extensions: Vec<Extension>,
+
+    /// Largest message sent or received with `send_message` and `recv_message`
+    max_message_size: usize,
+
+    /// Whether every write is sent right away, instead of coalescing small writes
+    nodelay: bool,
+
+    /// Most data kept until acknowledged, both in flight and waiting to be sent
+    send_buffer_size: usize,
+
+    /// Datagrams received from the transport but not handled yet
+    recv_batch: RecvBatch,
+
+    /// Bandwidth limits of this socket alone
+    rate_limits: RateLimiter,
+
+    /// Bandwidth limits shared with other sockets, if any
+    shared_rate_limiter: Option<RateLimiter>,
+
+    /// Receive window advertised in the latest packet sent
+    advertised_window: u32,
 }
 
 impl UtpSocket {
@@ -175,41 +298,137 @@ impl UtpSocket {
     ///
     /// If more than one valid address is specified, only the first will be used.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpSocket> {
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        let connection_id = rand::random::<u16>();
-        UdpSocket::bind(addr).map(|s|
-            UtpSocket {
-                socket: s,
-                connected_to: addr,
-                receiver_connection_id: connection_id,
-                sender_connection_id: connection_id + 1,
-                seq_nr: 1,
-                ack_nr: 0,
-                state: SocketState::New,
-                incoming_buffer: Vec::new(),
-                send_window: Vec::new(),
-                unsent_queue: VecDeque::new(),
-                duplicate_ack_count: 0,
-                last_acked: 0,
-                last_acked_timestamp: 0,
-                last_dropped: 0,
-                rtt: 0,
-                rtt_variance: 0,
-                pending_data: Vec::new(),
-                curr_window: 0,
-                remote_wnd_size: 0,
-                current_delays: Vec::new(),
-                base_delays: VecDeque::with_capacity(BASE_HISTORY),
-                their_delay: 0,
-                last_rollover: 0,
-                congestion_timeout: INITIAL_CONGESTION_TIMEOUT,
-                cwnd: INIT_CWND * MSS,
-            })
+        let addr = try!(resolve(addr));
+        let socket = try!(UdpSocket::bind(addr));
+        try!(transport::report_unreachable(&socket));
+        Ok(UtpSocket::unconnected(Arc::new(socket), addr))
+    }
+
+    /// Creates a uTP socket on top of the given datagram transport, instead of binding a new UDP
+    /// socket.
+    ///
+    /// Like a socket created with `bind`, it may then either wait for an incoming connection in
+    /// `recv_from` or connect to a remote peer with `connect_with_transport`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpSocket;
+    /// use std::net::UdpSocket;
+    ///
+    /// let udp = UdpSocket::bind("0.0.0.0:6881").unwrap();
+    /// udp.set_ttl(32).unwrap();
+    /// let socket = UtpSocket::from_transport(udp).unwrap();
+    /// ```
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Result<UtpSocket> {
+        let addr = try!(transport.local_addr());
+        Ok(UtpSocket::unconnected(Arc::new(transport), addr))
+    }
+
+    /// Creates a socket ready to start or accept a connection.
+    fn unconnected(transport: Arc<dyn Transport>, addr: SocketAddr) -> UtpSocket {
+        UtpSocket::with_connection_id(transport, addr, rand::random())
+    }
+
+    /// Creates a socket ready to start a connection, receiving packets with the given connection
+    /// id.
+    pub(crate) fn with_connection_id(transport: Arc<dyn Transport>, addr: SocketAddr,
+                                     connection_id: u16) -> UtpSocket {
+        let mut socket = UtpSocket::new(transport, addr);
+        socket.receiver_connection_id = connection_id;
+        socket.sender_connection_id = connection_id.wrapping_add(1);
+        socket
+    }
+
+    /// Accepts the connection requested by `syn` from `src`, replying over `transport`, with the
+    /// given extensions and shared rate limiter.
+    pub(crate) fn accept(transport: Arc<dyn Transport>, syn: &PacketRef, src: SocketAddr,
+                         extensions: &ExtensionRegistry, rate_limiter: Option<RateLimiter>)
+                         -> Result<UtpSocket> {
+        // Ignore non-SYN packets
+        if syn.get_type() != PacketType::Syn {
+            return Err(Error::ProtocolViolation(syn.get_type()));
+        }
+
+        let mut socket = UtpSocket::new(transport, src);
+        socket.extensions = extensions.clone();
+        socket.shared_rate_limiter = rate_limiter;
+
+        // Establish connection with remote peer
+        match socket.handle_packet(syn, src) {
+            Ok(Some(reply)) => try!(socket.send_reply(reply, src)),
+            Ok(None) => return Err(Error::ProtocolViolation(syn.get_type())),
+            Err(e) => return Err(e)
+        };
+
+        Ok(socket)
+    }
+
+    /// Wraps a transport in a new uTP socket.
+    fn new(socket: Arc<dyn Transport>, addr: SocketAddr) -> UtpSocket {
+        let now = socket.now();
+        UtpSocket {
+            socket,
+            connected_to: addr,
+            receiver_connection_id: 0,
+            sender_connection_id: 0,
+            seq_nr: 1,
+            ack_nr: 0,
+            state: SocketState::New,
+            incoming_buffer: Vec::new(),
+            spare_packets: Vec::new(),
+            send_window: Vec::new(),
+            unsent_queue: VecDeque::new(),
+            duplicate_ack_count: 0,
+            last_acked: 0,
+            last_acked_timestamp: 0,
+            last_dropped: 0,
+            eof_pkt: None,
+            fin_acked: false,
+            read_shutdown: false,
+            rtt: 0,
+            rtt_variance: 0,
+            read_offset: 0,
+            curr_window: 0,
+            remote_wnd_size: 0,
+            current_delays: Vec::new(),
+            base_delays: VecDeque::with_capacity(BASE_HISTORY),
+            their_delay: 0,
+            last_rollover: 0,
+            congestion_timeout: INITIAL_CONGESTION_TIMEOUT,
+            retransmit_at: None,
+            retransmissions: 0,
+            fast_resent: None,
+            recovery_point: None,
+            cwnd: INIT_CWND * MSS,
+            linger: None,
+            deadline: None,
+            keepalive: Some(Duration::from_secs(DEFAULT_KEEPALIVE)),
+            idle_timeout: None,
+            poll_interval: None,
+            last_sent: now,
+            last_received: now,
+            simultaneous_open: false,
+            extensions: ExtensionRegistry::default(),
+            negotiated_extensions: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            nodelay: false,
+            send_buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            recv_batch: RecvBatch::new(RECV_BATCH_LEN, BUF_SIZE + HEADER_SIZE),
+            rate_limits: RateLimiter::new(),
+            shared_rate_limiter: None,
+            advertised_window: RECEIVE_BUFFER_SIZE as u32,
+        }
     }
 
     /// Returns the socket address that this socket was created from.
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr()
+        self.socket.local_addr().map_err(Error::from)
+    }
+
+    /// Returns the underlying transport.
+    pub(crate) fn transport(&self) -> Arc<dyn Transport> {
+        self.socket.clone()
     }
 
     /// Opens a connection to a remote host by hostname or IP address.
@@ -217,56 +436,313 @@ impl UtpSocket {
     /// The address type can be any implementor of the `ToSocketAddr` trait. See its documentation
     /// for concrete examples.
     ///
-    /// If more than one valid address is specified, only the first will be used.
+    /// Every address the host resolves to is tried, alternating between IPv6 and IPv4, and the
+    /// attempts overlap as in Happy Eyeballs (RFC 8305): the next one starts as soon as the
+    /// previous one fails, or if it hasn't succeeded within 250 milliseconds. The first connection
+    /// established is returned, and the other attempts are abandoned.
+    ///
+    /// An attempt fails with `Error::TimedOut(Timeout::Connect)` if the remote peer doesn't answer
+    /// within about 30 seconds, or with `Error::Unreachable` as soon as the network reports it as
+    /// unreachable (e.g., nothing listens on its port). If every attempt fails, the error is
+    /// `Error::AllAttemptsFailed`, unless there was a single address, whose attempt's error is
+    /// returned as is.
     pub fn connect<A: ToSocketAddrs>(other: A) -> Result<UtpSocket> {
-        let addr = other.to_socket_addrs().unwrap().next().unwrap();
-        let my_addr = match addr {
-            SocketAddr::V4(_) => "0.0.0.0:0",
-            SocketAddr::V6(_) => ":::0",
+        let addrs = try!(resolve_all(other));
+        happy_eyeballs::connect(addrs, |addr, abandoned| {
+            let my_addr = match addr {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => ":::0",
+            };
+            let socket = try!(UtpSocket::bind(my_addr));
+            socket.handshake_unless(addr, abandoned)
+        })
+    }
+
+    /// Opens a connection to a remote host from the given local address, e.g., to pick the network
+    /// interface of a multihomed host that the connection goes through.
+    ///
+    /// The remote host's addresses of the same family as the local address are tried as described
+    /// in `connect`, except that attempts can't overlap if the local address has a port other than
+    /// 0, so each one only starts once the previous one has failed.
+    ///
+    /// If more than one valid local address is specified, only the first will be used.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpSocket;
+    ///
+    /// let socket = UtpSocket::connect_from("192.168.1.10:0", "example.org:6881").unwrap();
+    /// ```
+    pub fn connect_from<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, remote: B)
+                                                            -> Result<UtpSocket> {
+        let local = try!(resolve(local));
+        let addrs: Vec<SocketAddr> = try!(resolve_all(remote)).into_iter()
+            .filter(|addr| addr.is_ipv4() == local.is_ipv4())
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::AddressResolution(io::Error::new(
+                ErrorKind::InvalidInput, "No address of the same family as the local address")));
+        }
+
+        let attempt = move |addr, abandoned: &AtomicBool| {
+            let socket = try!(UtpSocket::bind(local));
+            socket.handshake_unless(addr, abandoned)
         };
-        let mut socket = UtpSocket::bind(my_addr).unwrap();
-        socket.connected_to = addr;
+        if local.port() == 0 {
+            happy_eyeballs::connect(addrs, attempt)
+        } else {
+            happy_eyeballs::connect_in_turn(addrs, attempt)
+        }
+    }
+
+    /// Opens a connection to a remote peer over the given datagram transport.
+    ///
+    /// If more than one valid address is specified, only the first will be used.
+    pub fn connect_with_transport<T, A>(transport: T, other: A) -> Result<UtpSocket>
+        where T: Transport + 'static, A: ToSocketAddrs {
+        let addr = try!(resolve(other));
+        let socket = try!(UtpSocket::from_transport(transport));
+        socket.handshake(addr)
+    }
 
+    /// Opens a connection to a remote peer from this socket, which must not be connected yet.
+    ///
+    /// Unlike `connect`, this lets the socket be set up beforehand (e.g., with
+    /// `register_extension`).
+    ///
+    /// If more than one valid address is specified, only the first will be used.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::{UtpSocket, ExtensionBits};
+    ///
+    /// let mut socket = UtpSocket::bind("0.0.0.0:0").unwrap();
+    /// socket.register_extension(ExtensionBits::new([0, 0, 0, 0, 0, 0, 0, 1]));
+    /// let socket = socket.connect_to("192.0.2.1:6881").unwrap();
+    /// println!("{:?}", socket.negotiated_extensions());
+    /// ```
+    pub fn connect_to<A: ToSocketAddrs>(self, other: A) -> Result<UtpSocket> {
+        let addr = try!(resolve(other));
+        self.connect_unless(addr, &AtomicBool::new(false))
+    }
+
+    /// Opens a connection to a remote peer from this socket, like `connect_to`, but gives up as
+    /// soon as `abandoned` is set.
+    pub(crate) fn connect_unless(self, addr: SocketAddr, abandoned: &AtomicBool)
+                                 -> Result<UtpSocket> {
+        if self.state != SocketState::New {
+            return Err(Error::AlreadyConnected);
+        }
+        self.handshake_unless(addr, abandoned)
+    }
+
+    /// Adds a handler for an extension to negotiate with the remote peer, replacing any previous
+    /// handler for the same extension type.
+    ///
+    /// The extension is offered when the socket opens a connection (see `connect_to` and
+    /// `connect_simultaneous`), and answered when it accepts one, so the handler has no effect
+    /// once the socket is connected.
+    pub fn register_extension<H: ExtensionHandler + 'static>(&mut self, handler: H) {
+        self.extensions.register(Arc::new(handler));
+    }
+
+    /// Replaces the extension handlers with those of `extensions`.
+    pub(crate) fn set_extensions(&mut self, extensions: ExtensionRegistry) {
+        self.extensions = extensions;
+    }
+
+    /// Returns the extensions agreed upon with the remote peer during the handshake, with the
+    /// values answered by the peer that accepted the connection.
+    ///
+    /// Extensions the remote peer doesn't know about or declined are left out.
+    pub fn negotiated_extensions(&self) -> &[Extension] {
+        &self.negotiated_extensions
+    }
+
+    /// Returns a SYN packet opening a connection, offering the registered extensions.
+    fn syn_packet(&self) -> Packet {
         let mut packet = Packet::new();
         packet.set_type(PacketType::Syn);
-        packet.set_connection_id(socket.receiver_connection_id);
-        packet.set_seq_nr(socket.seq_nr);
+        packet.set_connection_id(self.receiver_connection_id);
+        packet.set_seq_nr(self.seq_nr);
+        packet.set_wnd_size(self.receive_window());
+        packet.extensions = self.extensions.offers();
+        packet
+    }
+
+    /// Performs the handshake with the remote peer at `addr`.
+    fn handshake(self, addr: SocketAddr) -> Result<UtpSocket> {
+        self.handshake_unless(addr, &AtomicBool::new(false))
+    }
+
+    /// Performs the handshake with the remote peer at `addr`, sending the SYN again while it goes
+    /// unanswered, and giving up after the last attempt or as soon as `abandoned` is set.
+    fn handshake_unless(self, addr: SocketAddr, abandoned: &AtomicBool) -> Result<UtpSocket> {
+        let mut socket = self;
+        socket.connected_to = addr;
 
-        let mut len = 0;
+        let mut packet = socket.syn_packet();
         let mut buf = [0; BUF_SIZE];
+        let mut timeout = Duration::from_millis(SYN_TIMEOUT);
+        let mut reply = None;
 
-        // let syn_timeout = socket.congestion_timeout;
-        for _ in (0u8..5) {
-            packet.set_timestamp_microseconds(now_microseconds());
+        for _ in 0..SYN_ATTEMPTS {
+            packet.set_timestamp_microseconds(socket.socket.now_microseconds());
 
             // Send packet
             debug!("Connecting to {}", socket.connected_to);
-            try!(socket.socket.send_to(&packet.to_bytes()[..], socket.connected_to));
+            try!(socket.send_to_peer(&packet.to_bytes()[..]));
             socket.state = SocketState::SynSent;
             debug!("sent {:?}", packet);
 
-            // Validate response
-            // socket.socket.set_read_timeout(Some(syn_timeout));
-            match socket.socket.recv_from(&mut buf) {
-                // Ok((_read, src)) if src != socket.connected_to => continue,
-                Ok((read, src)) => { socket.connected_to = src; len = read; break; },
-                // Err(ref e) if e.kind == TimedOut => {
-                //     debug!("Timed out, retrying");
-                //     syn_timeout *= 2;
-                //     continue;
-                // },
-                Err(e) => return Err(e),
-            };
+            let deadline = socket.socket.now() + timeout;
+            reply = try!(socket.wait_for_reply(&mut buf, deadline, abandoned));
+            if reply.is_some() || abandoned.load(Ordering::SeqCst) {
+                break;
+            }
+            debug!("Timed out, retrying");
+            timeout *= 2;
         }
 
-        let addr = socket.connected_to;
-        let packet = try!(Packet::from_bytes(&buf[..len]).or(Err(SocketError::InvalidPacket)));
+        let (len, addr) = match reply {
+            Some(reply) => reply,
+            None => {
+                socket.state = SocketState::Closed;
+                return Err(Error::TimedOut(Timeout::Connect));
+            }
+        };
+        socket.connected_to = addr;
+        let packet = try!(PacketRef::from_bytes(&buf[..len]));
         debug!("received {:?}", packet);
         try!(socket.handle_packet(&packet, addr));
+        socket.last_received = socket.socket.now();
+        socket.last_sent = socket.last_received;
+        try!(socket.update_read_timeout());
 
         debug!("connected to: {}", socket.connected_to);
 
-        return Ok(socket);
+        Ok(socket)
+    }
+
+    /// Waits for the reply to a SYN until `deadline`, returning `None` if none arrives in time or
+    /// the handshake is abandoned first.
+    ///
+    /// Fails with `Error::Unreachable` as soon as the network reports the remote peer as
+    /// unreachable, rather than sending the SYN again.
+    fn wait_for_reply(&mut self, buf: &mut [u8], deadline: Instant, abandoned: &AtomicBool)
+                      -> Result<Option<(usize, SocketAddr)>> {
+        loop {
+            let now = self.socket.now();
+            if now >= deadline || abandoned.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            let wait = min(deadline - now, Duration::from_millis(HANDSHAKE_POLL_INTERVAL));
+            try!(self.socket.set_read_timeout(Some(max(wait, Duration::from_millis(1)))));
+            match self.socket.recv_from(buf) {
+                Ok(reply) => return Ok(Some(reply)),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                    e.kind() == ErrorKind::TimedOut => (),
+                Err(e) => try!(self.transport_error(e)),
+            }
+        }
+    }
+
+    /// Opens a uTP connection to a remote peer that is connecting back at the same time.
+    ///
+    /// Both peers must call this function with their own local address and the address of the
+    /// other peer, as exchanged out of band (e.g., through a rendezvous server). SYN packets are
+    /// sent repeatedly until the remote peer's arrive, which opens a path through NATs or
+    /// firewalls on either side. If both SYNs cross, they are resolved into a single connection.
+    ///
+    /// Fails with `Error::TimedOut(Timeout::Connect)` if the remote peer isn't reached within 20
+    /// seconds.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpSocket;
+    ///
+    /// // The remote peer calls connect_simultaneous("0.0.0.0:4000", "192.0.2.1:3000")
+    /// let socket = UtpSocket::connect_simultaneous("0.0.0.0:3000", "198.51.100.7:4000");
+    /// ```
+    pub fn connect_simultaneous<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, remote: B)
+                                                                    -> Result<UtpSocket> {
+        let addr = try!(resolve(remote));
+        let mut socket = try!(UtpSocket::bind(local));
+        socket.connected_to = addr;
+        socket.simultaneous_open = true;
+        socket.state = SocketState::SynSent;
+
+        let interval = Duration::from_millis(SIMULTANEOUS_OPEN_INTERVAL);
+        try!(socket.socket.set_read_timeout(Some(interval)));
+        let mut buf = [0; BUF_SIZE];
+
+        for _ in 0..SIMULTANEOUS_OPEN_ATTEMPTS {
+            let mut packet = socket.syn_packet();
+            packet.set_timestamp_microseconds(socket.socket.now_microseconds());
+
+            // The remote peer may not be listening yet, so it being unreachable isn't an error,
+            // though the errors reported by the network must still be taken
+            debug!("Connecting to {}", socket.connected_to);
+            match socket.socket.send_to(&packet.to_bytes()[..], socket.connected_to) {
+                Err(ref e) if error::is_unreachable(e) => {
+                    try!(socket.socket.take_unreachable());
+                },
+                Err(e) => return Err(Error::from(e)),
+                Ok(_) => debug!("sent {:?}", packet),
+            }
+
+            // Handle the remote peer's packets until it's time to send another SYN
+            let sent_at = socket.socket.now();
+            while socket.state == SocketState::SynSent &&
+                socket.socket.now().duration_since(sent_at) < interval {
+                let (read, src) = match socket.socket.recv_from(&mut buf) {
+                    Ok(x) => x,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                        e.kind() == ErrorKind::TimedOut => break,
+                    Err(ref e) if error::is_unreachable(e) => {
+                        try!(socket.socket.take_unreachable());
+                        continue;
+                    },
+                    Err(e) => return Err(Error::from(e)),
+                };
+                let packet = match PacketRef::from_bytes(&buf[..read]) {
+                    Ok(packet) if src == socket.connected_to => packet,
+                    _ => continue,
+                };
+                debug!("received {:?}", packet);
+
+                // Data may only arrive before the handshake's end if its reply was lost, in which
+                // case the remote peer will send it again
+                match packet.get_type() {
+                    PacketType::Syn | PacketType::State | PacketType::Reset => (),
+                    _ => continue,
+                }
+
+                if let Some(reply) = try!(socket.handle_packet(&packet, src)) {
+                    try!(socket.send_reply(reply, src));
+                }
+            }
+
+            if socket.state != SocketState::SynSent {
+                break;
+            }
+        }
+
+        if socket.state != SocketState::Connected {
+            // Don't let a late SYN from the remote peer open the connection
+            socket.state = SocketState::Closed;
+            return Err(Error::TimedOut(Timeout::Connect));
+        }
+
+        socket.last_received = socket.socket.now();
+        socket.last_sent = socket.last_received;
+        try!(socket.update_read_timeout());
+        debug!("connected to: {}", socket.connected_to);
+
+        Ok(socket)
     }
 
     /// Gracefully closes connection to peer.
@@ -274,33 +750,313 @@ impl UtpSocket {
     /// This method allows both peers to receive all packets still in
     /// flight.
     pub fn close(&mut self) -> Result<()> {
-        try!(self.flush());
-
         // Nothing to do if the socket's already closed or not connected
         if self.state == SocketState::Closed ||
             self.state == SocketState::New ||
-            self.state == SocketState::SynSent {
+            self.state == SocketState::SynSent ||
+            self.state == SocketState::ResetReceived {
             return Ok(());
         }
 
+        let peer_finished = self.state == SocketState::FinReceived;
+        try!(self.shutdown(Shutdown::Write));
+
+        // If the remote peer already sent its FIN it may be gone by now, so don't wait for an
+        // acknowledgement that might never come
+        if !peer_finished {
+            // Receive JAKE
+            while self.state != SocketState::Closed && !self.fin_acked {
+                try!(self.handle_next_packet());
+            }
+        }
+
+        self.state = SocketState::Closed;
+        Ok(())
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    ///
+    /// Shutting down the write half sends a FIN to the remote peer after every pending packet is
+    /// acknowledged, but the socket keeps receiving data until the remote peer's own FIN arrives.
+    /// Shutting down the read half discards any data received from then on, and further calls to
+    /// `recv_from` return 0 bytes read.
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        match how {
+            Shutdown::Read => {
+                self.shutdown_read();
+                Ok(())
+            },
+            Shutdown::Write => self.shutdown_write(),
+            Shutdown::Both => {
+                self.shutdown_read();
+                self.shutdown_write()
+            },
+        }
+    }
+
+    fn shutdown_read(&mut self) {
+        self.read_shutdown = true;
+        self.incoming_buffer.clear();
+        self.read_offset = 0;
+    }
+
+    fn shutdown_write(&mut self) -> Result<()> {
+        match self.state {
+            SocketState::Connected | SocketState::FinReceived => (),
+            SocketState::FinSent | SocketState::Closed => return Ok(()),
+            SocketState::ResetReceived => return Err(Error::ConnectionReset),
+            SocketState::New | SocketState::SynSent => {
+                return Err(Error::NotConnected)
+            },
+        }
+
+        try!(self.flush());
+
+        // Our FIN doubles as the acknowledgement of the remote peer's, if everything before it
+        // was received
+        if self.fin_reached() {
+            self.ack_nr = self.eof_pkt.unwrap();
+        }
+
+        // Send FIN
+        let packet = self.fin_packet();
+        try!(self.send_to_peer(&packet.to_bytes()[..]));
+        debug!("sent {:?}", packet);
+        self.state = SocketState::FinSent;
+        self.start_retransmit_timer();
+
+        Ok(())
+    }
+
+    /// Returns our FIN packet, which takes the sequence number following our last data packet.
+    fn fin_packet(&self) -> Packet {
         let mut packet = Packet::new();
         packet.set_connection_id(self.sender_connection_id);
         packet.set_seq_nr(self.seq_nr);
         packet.set_ack_nr(self.ack_nr);
-        packet.set_timestamp_microseconds(now_microseconds());
+        packet.set_timestamp_microseconds(self.socket.now_microseconds());
         packet.set_type(PacketType::Fin);
+        packet
+    }
 
-        // Send FIN
-        try!(self.socket.send_to(&packet.to_bytes()[..], self.connected_to));
-        self.state = SocketState::FinSent;
+    /// Closes the connection immediately, without waiting for pending data to be delivered.
+    ///
+    /// A RST packet is sent to the remote peer and every buffered packet, either incoming or
+    /// outgoing, is discarded.
+    pub fn abort(&mut self) -> Result<()> {
+        let result = match self.state {
+            SocketState::New | SocketState::Closed | SocketState::ResetReceived => Ok(()),
+            _ => {
+                let mut packet = Packet::new();
+                packet.set_connection_id(self.sender_connection_id);
+                packet.set_seq_nr(self.seq_nr);
+                packet.set_ack_nr(self.ack_nr);
+                packet.set_timestamp_microseconds(self.socket.now_microseconds());
+                packet.set_type(PacketType::Reset);
+
+                debug!("sending {:?}", packet);
+                self.send_to_peer(&packet.to_bytes()[..])
+            }
+        };
 
-        // Receive JAKE
-        let mut buf = [0; BUF_SIZE];
-        while self.state != SocketState::Closed {
-            try!(self.recv(&mut buf));
+        self.discard();
+        result
+    }
+
+    /// Closes the connection on this end only, discarding every buffered packet.
+    fn discard(&mut self) {
+        self.incoming_buffer.clear();
+        self.read_offset = 0;
+        self.send_window.clear();
+        self.unsent_queue.clear();
+        self.curr_window = 0;
+        self.state = SocketState::Closed;
+    }
+
+    /// Handles an error of the transport, closing the connection if it says the remote peer is
+    /// unreachable (e.g., it went away and its host answers with ICMP port unreachable).
+    ///
+    /// Errors about datagrams sent to other addresses are ignored, returning `Ok`, as anyone may
+    /// send packets that get answered with a reset.
+    pub(crate) fn transport_error(&mut self, e: io::Error) -> Result<()> {
+        if !error::is_unreachable(&e) {
+            return Err(Error::Io(e));
         }
+        let unreachable = try!(self.socket.take_unreachable());
+        let connected_to = self.connected_to;
+        if !unreachable.is_empty() && unreachable.iter().all(|&dst| unmapped(dst) != connected_to) {
+            debug!("Ignoring error about {:?}: {}", unreachable, e);
+            return Ok(());
+        }
+        debug!("{} is unreachable: {}", self.connected_to, e);
+        self.discard();
+        Err(Error::Unreachable(e))
+    }
 
-        Ok(())
+    /// Sends a datagram to the remote peer, again if an error about another address took the
+    /// place of the first attempt.
+    fn send_to_peer(&mut self, buf: &[u8]) -> Result<()> {
+        loop {
+            match self.socket.send_to(buf, self.connected_to) {
+                Ok(_) => return Ok(()),
+                Err(e) => try!(self.transport_error(e)),
+            }
+        }
+    }
+
+    /// Sets how long the connection may linger after the socket is dropped without being closed.
+    ///
+    /// Dropping a socket never blocks. Instead, any pending data is delivered and the connection
+    /// is closed by a background thread, which aborts the connection if that takes longer than
+    /// the linger timeout (30 seconds if `None`). A zero timeout aborts the connection as soon as
+    /// the socket is dropped.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
+    /// Returns the linger timeout of this socket. See `set_linger`.
+    pub fn linger(&self) -> Option<Duration> {
+        self.linger
+    }
+
+    /// Sets the interval between keep-alive packets sent while nothing else is being sent, or
+    /// disables them if `None`.
+    ///
+    /// Keep-alives prevent NAT bindings from expiring on idle connections. The default interval
+    /// is 29 seconds.
+    ///
+    /// There is no background thread driving the socket's timers: keep-alives, like
+    /// retransmissions and the idle timeout, are only handled while the application calls into
+    /// the socket (e.g., blocked on `recv_from` or `flush`, or with either half of a split stream
+    /// waiting). A connection left alone, without any call pending, sends no keep-alives, and
+    /// its NAT bindings may expire.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+    }
+
+    /// Returns the keep-alive interval of this socket. See `set_keepalive`.
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Sets how long the remote peer may stay silent before the connection fails, or disables
+    /// the timeout if `None` (the default).
+    ///
+    /// When the timeout expires the connection is aborted and the pending operation fails with
+    /// `Error::TimedOut(Timeout::Idle)`. The timeout should be comfortably larger than the remote
+    /// peer's keep-alive interval.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Returns the idle timeout of this socket. See `set_idle_timeout`.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Limits how long the socket blocks waiting for a single packet, so that other threads get
+    /// a chance to use it.
+    pub(crate) fn set_poll_interval(&mut self, interval: Option<Duration>) -> Result<()> {
+        self.poll_interval = interval;
+        self.update_read_timeout()
+    }
+
+    /// Sends a keep-alive, a window update or data held back by the rate limits, or fails the
+    /// connection, if any is due.
+    pub(crate) fn check_timers(&mut self) -> Result<()> {
+        let now = self.socket.now();
+        if let Some(deadline) = self.deadline {
+            if now >= deadline {
+                return Err(Error::TimedOut(Timeout::Linger));
+            }
+        }
+
+        match self.state {
+            SocketState::Connected | SocketState::FinSent | SocketState::FinReceived => (),
+            _ => return Ok(()),
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            if now.duration_since(self.last_received) >= timeout {
+                debug!("remote peer silent for {:?}, aborting", timeout);
+                let _ = self.abort();
+                return Err(Error::TimedOut(Timeout::Idle));
+            }
+        }
+
+        if self.retransmit_at.map(|at| now >= at).unwrap_or(false) {
+            try!(self.handle_retransmit_timeout());
+        }
+
+        // The remote peer stops sending while our receive window is closed, so tell it as soon as
+        // the download limits let the window open again
+        if self.advertised_window == 0 && self.receive_window() > 0 {
+            debug!("sending window update");
+            let packet = self.state_packet();
+            try!(self.send_reply(packet, self.connected_to));
+        }
+
+        if let Some(interval) = self.keepalive {
+            if now.duration_since(self.last_sent) >= interval {
+                debug!("sending keep-alive");
+                let packet = self.state_packet();
+                try!(self.send_reply(packet, self.connected_to));
+            }
+        }
+
+        self.send_ready()
+    }
+
+    /// Returns a `State` packet acknowledging everything received so far.
+    fn state_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::State);
+        packet.set_connection_id(self.sender_connection_id);
+        packet.set_seq_nr(self.seq_nr);
+        packet.set_ack_nr(self.ack_nr);
+        packet.set_timestamp_microseconds(self.socket.now_microseconds());
+        packet.set_timestamp_difference_microseconds(self.their_delay);
+        packet
+    }
+
+    /// Sets the read timeout of the UDP socket to wake up in time for the next timer.
+    fn update_read_timeout(&mut self) -> Result<()> {
+        let now = self.socket.now();
+        let deadline = self.deadline.map(|deadline| deadline.saturating_duration_since(now));
+        let mut timers = [self.poll_interval, deadline, None, None, None, None, None];
+
+        match self.state {
+            SocketState::Connected | SocketState::FinSent | SocketState::FinReceived => {
+                timers[2] = self.idle_timeout.map(|timeout| {
+                    (self.last_received + timeout).saturating_duration_since(now)
+                });
+                timers[3] = self.keepalive.map(|interval| {
+                    (self.last_sent + interval).saturating_duration_since(now)
+                });
+                timers[4] = self.rate_limit_timer();
+                timers[5] = self.retransmit_at.map(|at| at.saturating_duration_since(now));
+                if self.remote_wnd_size == 0 && self.curr_window == 0 &&
+                    !self.unsent_queue.is_empty() {
+                    let probe = self.last_received + Duration::from_millis(ZERO_WINDOW_PROBE);
+                    timers[6] = Some(probe.saturating_duration_since(now));
+                }
+            },
+            _ => (),
+        }
+
+        // A zero timeout isn't allowed, and would mean blocking forever anyway
+        let timeout = timers.iter().flatten().copied().min()
+            .map(|t| max(t, Duration::from_millis(1)));
+        self.socket.set_read_timeout(timeout).map_err(Error::from)
+    }
+
+    /// Gracefully closes the connection, aborting it if it takes longer than `timeout`.
+    fn close_with_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(self.socket.now() + timeout);
+        if let Err(e) = self.close() {
+            debug!("aborting connection: {}", e);
+            let _ = self.abort();
+        }
     }
 
     /// Receives data from socket.
@@ -309,39 +1065,141 @@ impl UtpSocket {
     /// Returns 0 bytes read after receiving a FIN packet when the remaining
     /// inflight packets are consumed.
     pub fn recv_from(&mut self, buf: &mut[u8]) -> Result<(usize,SocketAddr)> {
-        let read = self.flush_incoming_buffer(buf);
+        loop {
+            if let Some(read) = try!(self.recv_buffered(buf)) {
+                return Ok((read, self.connected_to));
+            }
 
-        if read > 0 {
-            return Ok((read, self.connected_to));
-        } else {
-            // If the socket received a reset packet and all data has been flushed, then it can't
-            // receive anything else
-            if self.state == SocketState::ResetReceived {
-                return Err(Error::from(SocketError::ConnectionReset));
+            match self.recv(buf) {
+                Ok((0, _src)) => continue,
+                Ok(x) => return Ok(x),
+                Err(e) => return Err(e)
             }
+        }
+    }
 
-            loop {
-                // A closed socket with no pending data can only "read" 0 new bytes.
-                if self.state == SocketState::Closed {
-                    return Ok((0, self.connected_to));
+    /// Receives data into several buffers, filling each in turn. Returns the number of bytes read,
+    /// like `recv_from`.
+    ///
+    /// Only the first buffer to receive data may wait for packets, and the others are filled
+    /// with data already received.
+    pub(crate) fn recv_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let len = if read == 0 {
+                    try!(self.recv_from(&mut buf[filled..])).0
+                } else {
+                    // Data already read must not be lost to a later error
+                    match self.recv_buffered(&mut buf[filled..]) {
+                        Ok(Some(len)) => len,
+                        _ => 0,
+                    }
+                };
+                if len == 0 {
+                    return Ok(read);
                 }
+                filled += len;
+                read += len;
+            }
+        }
+        Ok(read)
+    }
 
-                match self.recv(buf) {
-                    Ok((0, _src)) => continue,
-                    Ok(x) => return Ok(x),
-                    Err(e) => return Err(e)
-                }
+    /// Returns data already received, if any, without waiting for new packets.
+    ///
+    /// Returns `Ok(None)` if there's nothing to deliver yet, and `Ok(Some(0))` if there's nothing
+    /// left to deliver at all.
+    pub(crate) fn recv_buffered(&mut self, buf: &mut[u8]) -> Result<Option<usize>> {
+        if try!(self.needs_data()) {
+            return Ok(None);
+        }
+        Ok(Some(self.flush_incoming_buffer(buf)))
+    }
+
+    /// Returns the data available to read, waiting for packets until there's some. An empty slice
+    /// means the end of the stream.
+    ///
+    /// The data is borrowed from the received packets, and `consume` marks it as read.
+    pub(crate) fn fill_buf(&mut self) -> Result<&[u8]> {
+        while try!(self.needs_data()) {
+            try!(self.handle_next_packet());
+        }
+        Ok(self.buffered_data())
+    }
+
+    /// Marks the first `amt` bytes of the data available to read as read. See `fill_buf`.
+    pub(crate) fn consume(&mut self, amt: usize) {
+        let len = match self.readable_packet() {
+            Some(packet) => packet.payload.len(),
+            None => return,
+        };
+        self.read_offset = min(self.read_offset + amt, len);
+        if self.read_offset == len {
+            self.read_offset = 0;
+            if let Some(packet) = self.advance_incoming_buffer() {
+                self.recycle_packet(packet);
             }
         }
     }
 
-    fn recv(&mut self, buf: &mut[u8]) -> Result<(usize,SocketAddr)> {
+    /// Keeps a packet that's no longer needed to store a later one into, unless enough are kept.
+    fn recycle_packet(&mut self, packet: Packet) {
+        if self.spare_packets.len() < MAX_SPARE_PACKETS {
+            self.spare_packets.push(packet);
+        }
+    }
+
+    /// Returns whether reading has to wait for more packets, i.e., there's no data to read yet
+    /// but the stream hasn't ended.
+    fn needs_data(&mut self) -> Result<bool> {
+        if self.read_shutdown {
+            return Ok(false);
+        }
+
+        // Packets without data have nothing to deliver
+        while self.readable_packet().map(|p| p.payload.is_empty()).unwrap_or(false) {
+            self.consume(0);
+        }
+        if !self.buffered_data().is_empty() {
+            return Ok(false);
+        }
+
+        // If the socket received a reset packet and all data has been flushed, then it can't
+        // receive anything else
+        if self.state == SocketState::ResetReceived {
+            return Err(Error::ConnectionReset);
+        }
+
+        // A closed socket with no pending data can only "read" 0 new bytes, as does one whose
+        // remote peer already sent everything it had to send.
+        Ok(self.state != SocketState::Closed && !self.fin_reached())
+    }
+
+    /// Receives and handles a single packet, returning any data it made available.
+    pub(crate) fn recv(&mut self, buf: &mut[u8]) -> Result<(usize,SocketAddr)> {
+        let src = try!(self.handle_next_packet());
+
+        // Flush incoming buffer if possible
+        let read = self.flush_incoming_buffer(buf);
+
+        Ok((read, src))
+    }
+
+    /// Receives and handles a single packet, keeping any data it carries in the incoming buffer.
+    ///
+    /// Returns the source address of the packet, or the remote peer's address if none arrived.
+    pub(crate) fn handle_next_packet(&mut self) -> Result<SocketAddr> {
+        try!(self.check_timers());
+        try!(self.update_read_timeout());
+
         let mut b = [0; BUF_SIZE + HEADER_SIZE];
         // if self.state != SocketState::New {
         //     debug!("setting read timeout of {} ms", self.congestion_timeout);
         //     self.socket.set_read_timeout(Some(self.congestion_timeout));
         // }
-        let (read, src) = match self.socket.recv_from(&mut b) {
+        let (read, src) = match self.next_datagram(&mut b) {
             // Err(ref e) if e.kind == TimedOut => {
             //     debug!("recv_from timed out");
             //     self.congestion_timeout = self.congestion_timeout * 2;
@@ -350,191 +1208,682 @@ impl UtpSocket {
             //     return Ok((0, self.connected_to));
             // },
             Ok(x) => x,
-            Err(e) => return Err(e),
+            // A read timeout only means there was nothing to handle, as does an error about
+            // another address than the remote peer's
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(self.connected_to);
+            },
+            Err(e) => {
+                try!(self.transport_error(e));
+                return Ok(self.connected_to);
+            },
         };
-        let packet = match Packet::from_bytes(&b[..read]) {
+        let packet = match PacketRef::from_bytes(&b[..read]) {
             Ok(packet) => packet,
             Err(e) => {
                 debug!("{}", e);
                 debug!("Ignoring invalid packet");
-                return Ok((0, self.connected_to));
+                return Ok(self.connected_to);
             }
         };
         debug!("received {:?}", packet);
 
+        // Once connected, only the remote peer is answered, as replies to anyone else could only
+        // be resets, and these may be used to have the network report the connection as broken
+        if self.state != SocketState::New && src != self.connected_to {
+            debug!("Ignoring packet from {}", src);
+            return Ok(self.connected_to);
+        }
+        self.last_received = self.socket.now();
+
         if let Some(pkt) = try!(self.handle_packet(&packet, src)) {
-                let mut pkt = pkt;
-                pkt.set_wnd_size(BUF_SIZE as u32);
-                try!(self.socket.send_to(&pkt.to_bytes()[..], src));
-                debug!("sent {:?}", pkt);
+            try!(self.send_reply(pkt, src));
+        }
+
+        // Acknowledgements may have opened the congestion window
+        try!(self.send_ready());
+
+        // Only data kept for delivery is copied out of the receive buffer, into the memory of a
+        // packet already read if there's one
+        if packet.get_type() == PacketType::Data && !self.read_shutdown &&
+            self.accepts_data(packet.seq_nr()) {
+            let now = self.socket.now();
+            for limiter in self.rate_limiters() {
+                limiter.consume_download(now, packet.payload().len());
+            }
+            let mut stored = self.spare_packets.pop().unwrap_or_default();
+            packet.copy_into(&mut stored);
+            self.insert_into_buffer(stored);
+        }
+
+        Ok(src)
+    }
+
+    /// Handles a datagram that the transport was seen to hold (e.g., by a split half peeking at
+    /// it), waiting as little as possible in case another thread took it first.
+    pub(crate) fn handle_waiting_packet(&mut self) -> Result<SocketAddr> {
+        // Transports don't take a zero timeout, so wait for the shortest one instead
+        let poll_interval = self.poll_interval;
+        self.poll_interval = Some(Duration::from_millis(1));
+        let src = self.handle_next_packet();
+        self.poll_interval = poll_interval;
+        try!(self.update_read_timeout());
+        src
+    }
+
+    /// Receives the next datagram into `buf`, from the latest batch received from the transport
+    /// while any is left.
+    fn next_datagram(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if self.recv_batch.is_empty() {
+            try!(self.recv_batch.fill(&*self.socket));
+        }
+        // An empty batch can only come from a misbehaving transport, and yields an invalid packet
+        Ok(self.recv_batch.pop_into(buf).unwrap_or((0, self.connected_to)))
+    }
+
+    /// Returns whether datagrams were received from the transport but not handled yet.
+    pub(crate) fn has_pending_datagrams(&self) -> bool {
+        !self.recv_batch.is_empty()
+    }
+
+    /// Returns whether a data packet with the given sequence number should be stored for delivery.
+    ///
+    /// Packets that were already delivered or that come after the remote peer's FIN are discarded.
+    fn accepts_data(&self, seq_nr: u16) -> bool {
+        let expected = match self.state {
+            SocketState::Connected | SocketState::FinSent | SocketState::FinReceived => true,
+            SocketState::Closed => self.eof_pkt.is_some(),
+            _ => false,
+        };
+        expected && seq_nr.wrapping_sub(self.last_dropped) as i16 > 0 && !self.is_after_eof(seq_nr)
+    }
+
+    fn prepare_reply(&self, original: &PacketRef, t: PacketType) -> Packet {
+        let mut resp = Packet::new();
+        resp.set_type(t);
+        let self_t_micro: u32 = self.socket.now_microseconds();
+        let other_t_micro: u32 = original.timestamp_microseconds();
+        resp.set_timestamp_microseconds(self_t_micro);
+        resp.set_timestamp_difference_microseconds(self_t_micro.wrapping_sub(other_t_micro));
+        resp.set_connection_id(self.sender_connection_id);
+        resp.set_seq_nr(self.seq_nr);
+        resp.set_ack_nr(self.ack_nr);
+
+        resp
+    }
+
+    /// Removes a packet in the incoming buffer and updates the current acknowledgement number.
+    fn advance_incoming_buffer(&mut self) -> Option<Packet> {
+        if !self.incoming_buffer.is_empty() {
+            let packet = self.incoming_buffer.remove(0);
+            debug!("Removed packet from incoming buffer: {:?}", packet);
+            // Never move the acknowledgement number backwards
+            if packet.seq_nr().wrapping_sub(self.ack_nr) as i16 > 0 {
+                self.ack_nr = packet.seq_nr();
+            }
+            self.last_dropped = packet.seq_nr();
+            Some(packet)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the first packet in the incoming buffer if it's the next one to deliver, i.e., no
+    /// packet before it is missing.
+    fn readable_packet(&self) -> Option<&Packet> {
+        self.incoming_buffer.first()
+            .filter(|packet| packet.seq_nr() == self.last_dropped.wrapping_add(1))
+    }
+
+    /// Returns the unread data of the next packet to deliver, if any.
+    fn buffered_data(&self) -> &[u8] {
+        match self.readable_packet() {
+            Some(packet) => &packet.payload[self.read_offset..],
+            None => &[],
+        }
+    }
+
+    /// Copies data from the next packet to deliver into `buf`, returning the number of bytes
+    /// copied. A packet is discarded from the incoming buffer once completely read.
+    fn flush_incoming_buffer(&mut self, buf: &mut [u8]) -> usize {
+        let len = {
+            let data = self.buffered_data();
+            let len = min(data.len(), buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            len
+        };
+        self.consume(len);
+        len
+    }
+
+    /// Sends data on the socket to the remote peer. On success, returns the number of bytes written.
+    ///
+    /// Data is copied into the send buffer (see `set_send_buffer_size`) and sent as the congestion
+    /// window allows, so this only blocks while the buffer is full. As much of `buf` as fits is
+    /// written, which may be less than all of it. Acknowledgements are processed, and the rest of
+    /// the buffer sent, whenever the socket handles incoming packets (i.e., while reading, writing
+    /// or flushing). Use `flush` to wait until everything written is acknowledged.
+    //
+    // # Implementation details
+    //
+    // Note that the buffer passed to `send_to` might exceed the maximum packet
+    // size, which will result in the data being split over several packets.
+    pub fn send_to(&mut self, buf: &[u8]) -> Result<usize> {
+        self.send_bufs(&[buf])
+    }
+
+    /// Sends the concatenation of `bufs`, like `send_to`.
+    pub(crate) fn send_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        self.send_bufs(bufs)
+    }
+
+    /// Copies as much of the concatenation of `bufs` as fits into the send buffer, returning the
+    /// number of bytes written. See `send_to`.
+    fn send_bufs<B: Deref<Target = [u8]>>(&mut self, bufs: &[B]) -> Result<usize> {
+        loop {
+            if let Some(written) = try!(self.try_send(bufs)) {
+                return Ok(written);
+            }
+            try!(self.handle_next_packet());
+        }
+    }
+
+    /// Copies as much of the concatenation of `bufs` as fits into the send buffer, like
+    /// `send_bufs`, or returns `None` without waiting if the buffer is full.
+    pub(crate) fn try_send<B: Deref<Target = [u8]>>(&mut self, bufs: &[B])
+                                                    -> Result<Option<usize>> {
+        try!(self.check_sendable());
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if total == 0 {
+            return Ok(Some(0));
+        }
+        // A full buffer is only waited for while it holds data
+        if self.send_buffer_room() == 0 && self.send_buffer_len() > 0 {
+            return Ok(None);
+        }
+
+        let len = min(total, self.send_buffer_room());
+        self.queue_data(bufs, len);
+        try!(self.send_ready());
+
+        Ok(Some(len))
+    }
+
+    /// Sends a message, which the remote peer receives whole with `recv_message`.
+    ///
+    /// Messages are sent over the same reliable, ordered stream of bytes as `send_to`, each
+    /// preceded by its length as a 4-byte big-endian integer, so the message API shouldn't be
+    /// mixed with `send_to` and `recv_from` on the same connection.
+    ///
+    /// The whole message is written at once, so this blocks until the send buffer has room for it,
+    /// or is empty if the message is larger than the buffer.
+    ///
+    /// Fails with `Error::MessageTooLarge` without sending anything if the message is larger than
+    /// the maximum message size (see `set_max_message_size`).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpSocket;
+    ///
+    /// let mut socket = UtpSocket::connect("127.0.0.1:1234").unwrap();
+    /// socket.send_message(b"ping").unwrap();
+    /// let reply = socket.recv_message().unwrap();
+    /// assert_eq!(reply, Some(b"pong".to_vec()));
+    /// ```
+    pub fn send_message(&mut self, message: &[u8]) -> Result<()> {
+        if message.len() > self.max_message_size || message.len() > u32::MAX as usize {
+            return Err(Error::MessageTooLarge(message.len()));
+        }
+        try!(self.check_sendable());
+
+        try!(self.wait_for_send_buffer(MESSAGE_HEADER_SIZE + message.len()));
+        let header = (message.len() as u32).to_be_bytes();
+        self.queue_data(&[&header, message], MESSAGE_HEADER_SIZE + message.len());
+        self.send_ready()
+    }
+
+    /// Receives a whole message sent with `send_message`.
+    ///
+    /// Returns `Ok(None)` once the remote peer has shut down its write half (or closed the
+    /// connection) and every message was received.
+    ///
+    /// Fails with `Error::MessageTooLarge` if the remote peer announces a message larger than the
+    /// maximum message size, in which case the connection is reset, as the remaining data can't
+    /// be split into messages anymore. Fails with `Error::TruncatedMessage` if the stream ends in
+    /// the middle of a message.
+    pub fn recv_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        Ok(try!(self.recv_message_into(&mut message)).map(|_| message))
+    }
+
+    /// Receives a whole message sent with `send_message` into `message`, replacing its contents
+    /// and returning the message's length.
+    ///
+    /// This isn't zero-copy: the data is still copied once, from the received packets into
+    /// `message`. It only saves the allocation `recv_message` makes for every message, as the
+    /// capacity of `message` is reused across calls. Otherwise, this behaves like
+    /// `recv_message`.
+    pub fn recv_message_into(&mut self, message: &mut Vec<u8>) -> Result<Option<usize>> {
+        let mut header = [0; MESSAGE_HEADER_SIZE];
+        match try!(self.recv_exact(&mut header)) {
+            0 => return Ok(None),
+            MESSAGE_HEADER_SIZE => (),
+            _ => return Err(Error::TruncatedMessage),
+        }
+
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_message_size {
+            debug!("message of {} bytes is too large, aborting", len);
+            let _ = self.abort();
+            return Err(Error::MessageTooLarge(len));
+        }
+
+        message.clear();
+        message.reserve(len);
+        while message.len() < len {
+            let appended = {
+                let data = try!(self.fill_buf());
+                if data.is_empty() {
+                    return Err(Error::TruncatedMessage);
+                }
+                let appended = min(data.len(), len - message.len());
+                message.extend_from_slice(&data[..appended]);
+                appended
+            };
+            self.consume(appended);
+        }
+        Ok(Some(len))
+    }
+
+    /// Sets the largest message that can be sent or received with `send_message` and
+    /// `recv_message`, in bytes (1 MiB by default).
+    ///
+    /// The limit protects the receiving end from allocating arbitrary amounts of memory, so both
+    /// peers should agree on it.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Returns the largest message that can be sent or received. See `set_max_message_size`.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Fills `buf` with received data, returning how much was read before the end of the stream.
+    fn recv_exact(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match try!(self.recv_from(&mut buf[read..])) {
+                (0, _src) => break,
+                (len, _src) => read += len,
+            }
         }
+        Ok(read)
+    }
 
-        if packet.get_type() == PacketType::Data && packet.seq_nr().wrapping_sub(self.last_dropped) > 0 {
-            self.insert_into_buffer(packet);
+    /// Fails if data can't be sent in the socket's current state.
+    fn check_sendable(&self) -> Result<()> {
+        match self.state {
+            SocketState::ResetReceived => Err(Error::ConnectionReset),
+            // Our half of the connection was shut down
+            SocketState::Closed | SocketState::FinSent => Err(Error::NotConnected),
+            _ => Ok(()),
         }
+    }
 
-        // Flush incoming buffer if possible
-        let read = self.flush_incoming_buffer(buf);
+    /// Splits the first `len` bytes of the concatenation of `bufs` into data packets, appending
+    /// them to the unsent packet queue.
+    fn queue_data<B: Deref<Target = [u8]>>(&mut self, bufs: &[B], len: usize) {
+        // Unless every write must go out on its own, top up the last packet not sent yet
+        let mut top_up = !self.nodelay;
+        let mut left = len;
+        for buf in bufs {
+            let mut buf = &buf[..min(buf.len(), left)];
+            left -= buf.len();
+            while !buf.is_empty() {
+                let room = match self.unsent_queue.back() {
+                    Some(packet) if top_up => MAX_PAYLOAD - packet.payload.len(),
+                    _ => 0,
+                };
+                if room == 0 {
+                    self.queue_packet();
+                    top_up = true;
+                    continue;
+                }
 
-        Ok((read, src))
+                let len = min(room, buf.len());
+                self.unsent_queue.back_mut().unwrap().payload.extend_from_slice(&buf[..len]);
+                buf = &buf[len..];
+            }
+        }
     }
 
-    fn prepare_reply(&self, original: &Packet, t: PacketType) -> Packet {
-        let mut resp = Packet::new();
-        resp.set_type(t);
-        let self_t_micro: u32 = now_microseconds();
-        let other_t_micro: u32 = original.timestamp_microseconds();
-        resp.set_timestamp_microseconds(self_t_micro);
-        resp.set_timestamp_difference_microseconds((self_t_micro - other_t_micro));
-        resp.set_connection_id(self.sender_connection_id);
-        resp.set_seq_nr(self.seq_nr);
-        resp.set_ack_nr(self.ack_nr);
+    /// Appends an empty data packet to the unsent packet queue.
+    fn queue_packet(&mut self) {
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::Data);
+        packet.set_seq_nr(self.seq_nr);
+        packet.set_ack_nr(self.ack_nr);
+        packet.set_connection_id(self.sender_connection_id);
+        packet.payload = Vec::new();
 
-        resp
+        self.unsent_queue.push_back(packet);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
     }
 
-    /// Removes a packet in the incoming buffer and updates the current acknowledgement number.
-    fn advance_incoming_buffer(&mut self) -> Option<Packet> {
-        if !self.incoming_buffer.is_empty() {
-            let packet = self.incoming_buffer.remove(0);
-            debug!("Removed packet from incoming buffer: {:?}", packet);
-            self.ack_nr = packet.seq_nr();
-            self.last_dropped = self.ack_nr;
-            Some(packet)
-        } else {
-            None
+    /// Sends any data held back by write coalescing, then consumes acknowledgements for every
+    /// pending packet.
+    pub fn flush(&mut self) -> Result<()> {
+        while !try!(self.try_flush()) {
+            debug!("packets in send window: {}", self.send_window.len());
+            try!(self.handle_next_packet());
         }
+        Ok(())
     }
 
-    /// Discards sequential, ordered packets in incoming buffer, starting from
-    /// the most recently acknowledged to the most recent, as long as there are
-    /// no missing packets. The discarded packets' payload is written to the
-    /// slice `buf`, starting in position `start`.
-    /// Returns the last written index.
-    fn flush_incoming_buffer(&mut self, buf: &mut [u8]) -> usize {
-        fn unsafe_copy(src: &[u8], dst: &mut [u8]) -> usize {
-            let max_len = min(src.len(), dst.len());
-            unsafe {
-                use std::ptr::copy;
-                copy(src.as_ptr(), dst.as_mut_ptr(), max_len);
-            }
-            return max_len;
+    /// Sends as much data held back by write coalescing as the congestion window and the rate
+    /// limits allow, returning whether everything pending is acknowledged, without waiting.
+    pub(crate) fn try_flush(&mut self) -> Result<bool> {
+        let first = self.send_window.len();
+        while !self.unsent_queue.is_empty() && !self.window_is_full() &&
+            self.upload_delay() == Duration::from_secs(0) {
+            self.move_to_send_window();
         }
+        try!(self.send_packets(first));
 
-        // Return pending data from a partially read packet
-        if !self.pending_data.is_empty() {
-            let flushed = unsafe_copy(&self.pending_data[..], buf);
+        if self.unsent_queue.is_empty() && self.send_window.is_empty() {
+            return Ok(true);
+        }
+        if self.state == SocketState::ResetReceived {
+            return Err(Error::ConnectionReset);
+        }
+        Ok(false)
+    }
 
-            if flushed == self.pending_data.len() {
-                self.pending_data.clear();
-                self.advance_incoming_buffer();
-            } else {
-                self.pending_data = self.pending_data[flushed..].to_vec();
-            }
+    /// Sends packets from the unsent packet queue while the congestion window has room, except for
+    /// a trailing partial packet held back by write coalescing.
+    fn send_ready(&mut self) -> Result<()> {
+        match self.state {
+            SocketState::Connected | SocketState::FinReceived => (),
+            _ => return Ok(()),
+        }
+        let first = self.send_window.len();
+        while !self.unsent_queue.is_empty() && !self.holds_back() && !self.window_is_full() &&
+            self.upload_delay() == Duration::from_secs(0) {
+            self.move_to_send_window();
+        }
+        self.send_packets(first)
+    }
 
-            return flushed;
+    /// Moves the first packet in the unsent packet queue to the send window, accounting for it in
+    /// the congestion window and the rate limits.
+    fn move_to_send_window(&mut self) {
+        let packet = self.unsent_queue.pop_front().unwrap();
+        let now = self.socket.now();
+        for limiter in self.rate_limiters() {
+            limiter.consume_upload(now, packet.payload.len());
         }
+        self.curr_window += packet.len() as u32;
+        self.send_window.push(packet);
+    }
 
-        if !self.incoming_buffer.is_empty() &&
-            (self.ack_nr == self.incoming_buffer[0].seq_nr() ||
-             self.ack_nr + 1 == self.incoming_buffer[0].seq_nr())
-        {
-            let flushed = unsafe_copy(&self.incoming_buffer[0].payload[..], buf);
+    /// Returns whether the unsent packet queue is a single partial packet that should wait for
+    /// more data, as long as earlier data is in flight (i.e., Nagle's algorithm).
+    fn holds_back(&self) -> bool {
+        !self.nodelay && self.unsent_queue.len() == 1 &&
+            self.unsent_queue[0].payload.len() < MAX_PAYLOAD && !self.send_window.is_empty()
+    }
 
-            if flushed == self.incoming_buffer[0].payload.len() {
-                self.advance_incoming_buffer();
-            } else {
-                self.pending_data = self.incoming_buffer[0].payload[flushed..].to_vec();
-            }
+    /// Returns the number of bytes written but not acknowledged yet.
+    fn send_buffer_len(&self) -> usize {
+        self.send_window.iter().chain(self.unsent_queue.iter()).map(|p| p.payload.len()).sum()
+    }
+
+    /// Returns the number of bytes that can be written before the send buffer is full.
+    fn send_buffer_room(&self) -> usize {
+        self.send_buffer_size.saturating_sub(self.send_buffer_len())
+    }
 
-            return flushed;
+    /// Handles incoming packets until the send buffer has room for `len` more bytes, or is empty.
+    fn wait_for_send_buffer(&mut self, len: usize) -> Result<()> {
+        while self.send_buffer_room() < len && self.send_buffer_len() > 0 {
+            try!(self.check_sendable());
+            try!(self.handle_next_packet());
         }
+        Ok(())
+    }
 
-        return 0;
+    /// Sets how much written data is kept until acknowledged by the remote peer, in bytes (1 MiB
+    /// by default, and at least a packet's worth).
+    ///
+    /// Writes block while the buffer is full, so it bounds both memory use and how far the
+    /// application can get ahead of the network.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.send_buffer_size = max(size, MAX_PAYLOAD);
     }
 
-    /// Sends data on the socket to the remote peer. On success, returns the number of bytes written.
-    //
-    // # Implementation details
-    //
-    // This method inserts packets into the send buffer and keeps trying to
-    // advance the send window until an ACK corresponding to the last packet is
-    // received.
-    //
-    // Note that the buffer passed to `send_to` might exceed the maximum packet
-    // size, which will result in the data being split over several packets.
-    pub fn send_to(&mut self, buf: &[u8]) -> Result<usize> {
-        if self.state == SocketState::Closed {
-            return Err(Error::from(SocketError::ConnectionClosed));
+    /// Returns the size of the send buffer. See `set_send_buffer_size`.
+    pub fn send_buffer_size(&self) -> usize {
+        self.send_buffer_size
+    }
+
+    /// Disables or enables the coalescing of small writes.
+    ///
+    /// By default, data written while earlier data is still in flight is held back until either
+    /// a full packet's worth of data is pending or everything in flight is acknowledged (as in
+    /// Nagle's algorithm). Acknowledgements are processed while reading, writing or flushing, and
+    /// `flush` sends held data right away. With `nodelay`, every write is sent as soon as the
+    /// congestion window allows, in packets of its own.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.nodelay = nodelay;
+        if nodelay {
+            try!(self.send_ready());
         }
+        Ok(())
+    }
 
-        let total_length = buf.len();
+    /// Returns whether small writes are sent right away. See `set_nodelay`.
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Sets the most data this socket sends per second, in bytes, or removes the limit if `None`
+    /// (the default).
+    ///
+    /// The limit applies along with those of the shared rate limiter, if any (see
+    /// `set_rate_limiter`), and can be changed at any time. Data it holds back stays in the send
+    /// buffer, and is sent as the socket handles packets.
+    pub fn set_upload_limit(&mut self, limit: Option<u64>) {
+        self.rate_limits.set_upload_limit(limit)
+    }
 
-        for chunk in buf.chunks(MSS as usize - HEADER_SIZE) {
-            let mut packet = Packet::with_payload(chunk);
-            packet.set_seq_nr(self.seq_nr);
-            packet.set_ack_nr(self.ack_nr);
-            packet.set_connection_id(self.sender_connection_id);
+    /// Returns the most data this socket sends per second. See `set_upload_limit`.
+    pub fn upload_limit(&self) -> Option<u64> {
+        self.rate_limits.upload_limit()
+    }
 
-            self.unsent_queue.push_back(packet);
+    /// Sets the most data this socket receives per second, in bytes, or removes the limit if
+    /// `None` (the default).
+    ///
+    /// The limit is enforced through the receive window advertised to the remote peer, which
+    /// stops sending while the window is closed. Like the upload limit, it applies along with those
+    /// of the shared rate limiter, and can be changed at any time.
+    pub fn set_download_limit(&mut self, limit: Option<u64>) {
+        self.rate_limits.set_download_limit(limit)
+    }
 
-            // `OverflowingOps` is marked unstable, so we can't use `overflowing_add` here
-            if self.seq_nr == ::std::u16::MAX {
-                self.seq_nr = 0;
-            } else {
-                self.seq_nr += 1;
-            }
-        }
+    /// Returns the most data this socket receives per second. See `set_download_limit`.
+    pub fn download_limit(&self) -> Option<u64> {
+        self.rate_limits.download_limit()
+    }
 
-        // Send every packet in the queue
-        try!(self.send());
+    /// Makes the socket share the limits of `limiter` with every other socket using it, on top of
+    /// its own limits, or stops sharing any limits if `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::{RateLimiter, UtpSocket};
+    ///
+    /// // Both connections together upload at most 1 MB/s
+    /// let limiter = RateLimiter::new();
+    /// limiter.set_upload_limit(Some(1_000_000));
+    ///
+    /// let mut first = UtpSocket::connect("192.0.2.1:6881").unwrap();
+    /// first.set_rate_limiter(Some(limiter.clone()));
+    /// let mut second = UtpSocket::connect("198.51.100.7:6881").unwrap();
+    /// second.set_rate_limiter(Some(limiter));
+    /// ```
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.shared_rate_limiter = limiter;
+    }
 
-        Ok(total_length)
+    /// Returns the rate limiter shared with other sockets, if any. See `set_rate_limiter`.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.shared_rate_limiter.as_ref()
     }
 
-    /// Consumes acknowledgements for every pending packet.
-    pub fn flush(&mut self) -> Result<()> {
-        let mut buf = [0u8; BUF_SIZE];
-        while !self.send_window.is_empty() {
-            debug!("packets in send window: {}", self.send_window.len());
-            try!(self.recv(&mut buf));
-        }
+    /// Returns the rate limiters that apply to the socket.
+    fn rate_limiters(&self) -> impl Iterator<Item = &RateLimiter> {
+        iter::once(&self.rate_limits).chain(self.shared_rate_limiter.iter())
+    }
 
-        Ok(())
+    /// Returns how long the upload limits hold back the next data packet.
+    fn upload_delay(&self) -> Duration {
+        let now = self.socket.now();
+        self.rate_limiters().map(|limiter| limiter.upload_delay(now)).max()
+            .unwrap_or(Duration::from_secs(0))
     }
 
-    /// Sends every packet in the unsent packet queue.
-    fn send(&mut self) -> Result<()> {
-        while let Some(packet) = self.unsent_queue.pop_front() {
-            let mut packet = packet;
-            try!(self.send_packet(&mut packet));
-            self.curr_window += packet.len() as u32;
-            self.send_window.push(packet);
-        }
-        Ok(())
+    /// Returns how long until the rate limits let held back data through or reopen the receive
+    /// window, if either is waiting for them.
+    fn rate_limit_timer(&self) -> Option<Duration> {
+        let now = self.socket.now();
+        let upload = if !self.unsent_queue.is_empty() && !self.window_is_full() {
+            self.upload_delay()
+        } else {
+            Duration::from_secs(0)
+        };
+        let download = if self.advertised_window == 0 {
+            self.rate_limiters().map(|limiter| limiter.download_delay(now)).max()
+                .unwrap_or(Duration::from_secs(0))
+        } else {
+            Duration::from_secs(0)
+        };
+        [upload, download].iter().cloned().filter(|&t| t > Duration::from_secs(0)).min()
     }
 
-    /// Send one packet.
-    #[inline(always)]
-    fn send_packet(&mut self, packet: &mut Packet) -> Result<()> {
-        let dst = self.connected_to;
-        debug!("current window: {}", self.send_window.len());
+    /// Returns the receive window to advertise to the remote peer: the room left in the receive
+    /// buffer, shrunk to what the download limits allow right now.
+    fn receive_window(&self) -> u32 {
+        let now = self.socket.now();
+        let buffered = self.incoming_buffer.iter().map(|p| p.payload.len()).sum::<usize>()
+            .saturating_sub(self.read_offset);
+        let room = RECEIVE_BUFFER_SIZE.saturating_sub(buffered) as u32;
+        self.rate_limiters().filter_map(|limiter| limiter.download_allowance(now))
+            .fold(room, |wnd, allowance| min(wnd as u64, allowance) as u32)
+    }
+
+    /// Returns the receive window to put in a packet about to be sent, remembering it.
+    fn advertise_window(&mut self) -> u32 {
+        self.advertised_window = self.receive_window();
+        self.advertised_window
+    }
+
+    /// Returns whether the congestion window has no room for another packet.
+    fn window_is_full(&self) -> bool {
+        // A closed receive window stops the sender, except for a packet now and then probing it
+        // in case the update reopening it was lost
+        if self.remote_wnd_size == 0 {
+            let silence = self.socket.now().duration_since(self.last_received);
+            return self.curr_window > 0 || silence < Duration::from_millis(ZERO_WINDOW_PROBE);
+        }
+
         let max_inflight = min(self.cwnd, self.remote_wnd_size);
         let max_inflight = max(MIN_CWND * MSS, max_inflight);
-        while self.curr_window >= max_inflight && self.duplicate_ack_count < 3 {
-            debug!("self.curr_window: {}", self.curr_window);
-            debug!("max_inflight: {}", max_inflight);
-            debug!("self.duplicate_ack_count: {}", self.duplicate_ack_count);
-            let mut buf = [0; BUF_SIZE];
-            try!(self.recv(&mut buf));
+        self.curr_window >= max_inflight
+    }
+
+    /// Sends the packet in the given position of the send window, updating its timestamps.
+    fn send_packet(&mut self, position: usize) -> Result<()> {
+        let mut buf = [0; BUF_SIZE + HEADER_SIZE];
+        let wnd_size = self.advertise_window();
+        let len = {
+            let packet = &mut self.send_window[position];
+            packet.set_wnd_size(wnd_size);
+            packet.set_timestamp_microseconds(self.socket.now_microseconds());
+            packet.set_timestamp_difference_microseconds(self.their_delay);
+            packet.encode_into(&mut buf)
+        };
+        try!(self.send_to_peer(&buf[..len]));
+        self.last_sent = self.socket.now();
+        self.start_retransmit_timer();
+        debug!("sent {:?}", self.send_window[position]);
+
+        Ok(())
+    }
+
+    /// Sends the packets in the send window from the given position onwards, several at a time,
+    /// updating their timestamps.
+    fn send_packets(&mut self, first: usize) -> Result<()> {
+        const DATAGRAM_SIZE: usize = BUF_SIZE + HEADER_SIZE;
+        let mut buf = [0; SEND_BATCH_LEN * DATAGRAM_SIZE];
+        let wnd_size = self.advertise_window();
+        let mut start = first;
+        while start < self.send_window.len() {
+            let end = min(start + SEND_BATCH_LEN, self.send_window.len());
+            let now = self.socket.now_microseconds();
+            let mut lens = [0; SEND_BATCH_LEN];
+            for ((packet, chunk), len) in self.send_window[start..end].iter_mut()
+                .zip(buf.chunks_mut(DATAGRAM_SIZE))
+                .zip(lens.iter_mut())
+            {
+                packet.set_wnd_size(wnd_size);
+                packet.set_timestamp_microseconds(now);
+                packet.set_timestamp_difference_microseconds(self.their_delay);
+                *len = packet.encode_into(chunk);
+                debug!("sending {:?}", packet);
+            }
+            let batch_len = end - start;
+            start = end;
+
+            let mut datagrams: [&[u8]; SEND_BATCH_LEN] = [&[]; SEND_BATCH_LEN];
+            for ((datagram, chunk), &len) in datagrams.iter_mut()
+                .zip(buf.chunks(DATAGRAM_SIZE))
+                .zip(lens.iter())
+            {
+                *datagram = &chunk[..len];
+            }
+
+            let mut sent = 0;
+            while sent < batch_len {
+                match self.socket.send_batch(&datagrams[sent..batch_len], self.connected_to) {
+                    Ok(count) => sent += count,
+                    Err(e) => try!(self.transport_error(e)),
+                }
+            }
+            self.last_sent = self.socket.now();
+            self.start_retransmit_timer();
         }
 
-        // TODO: Check if it still makes to send packet --- we might be trying to resend a lost
-        // packet that was acknowledged in the previous recv loop
+        Ok(())
+    }
 
-        packet.set_timestamp_microseconds(now_microseconds());
-        packet.set_timestamp_difference_microseconds(self.their_delay);
-        try!(self.socket.send_to(&packet.to_bytes()[..], dst));
+    /// Sends a packet that isn't kept for retransmission (e.g., an acknowledgement), advertising
+    /// the receive window.
+    fn send_reply(&mut self, packet: Packet, dst: SocketAddr) -> Result<()> {
+        let mut packet = packet;
+        packet.set_wnd_size(self.advertise_window());
+        let mut buf = [0; BUF_SIZE + HEADER_SIZE];
+        let len = packet.encode_into(&mut buf);
+        loop {
+            match self.socket.send_to(&buf[..len], dst) {
+                Ok(_) => break,
+                Err(e) => try!(self.transport_error(e)),
+            }
+        }
+        self.last_sent = self.socket.now();
         debug!("sent {:?}", packet);
 
         Ok(())
@@ -611,12 +1960,15 @@ impl UtpSocket {
 
     /// Builds the selective acknowledgment extension data for usage in packets.
     fn build_selective_ack(&self) -> Vec<u8> {
+        // The first bit stands for `ack_nr + 2`, as `ack_nr + 1` is implicitly missing. Packets
+        // too far ahead to fit in the extension are left out.
         let stashed = self.incoming_buffer.iter()
-            .filter(|&pkt| pkt.seq_nr() > self.ack_nr);
+            .map(|pkt| pkt.seq_nr().wrapping_sub(self.ack_nr))
+            .filter(|&diff| diff >= 2 && ((diff - 2) as usize) < MAX_SACK_LEN * 8);
 
         let mut sack = Vec::new();
-        for packet in stashed {
-            let diff = packet.seq_nr() - self.ack_nr - 2;
+        for diff in stashed {
+            let diff = diff - 2;
             let byte = (diff / 8) as usize;
             let bit = (diff % 8) as usize;
 
@@ -629,25 +1981,86 @@ impl UtpSocket {
             sack[byte] |= 1 << bit;
         }
 
-        return sack;
+        sack
     }
 
-    fn resend_lost_packet(&mut self, lost_packet_nr: u16) {
+    fn resend_lost_packet(&mut self, lost_packet_nr: u16) -> Result<()> {
         debug!("---> resend_lost_packet({}) <---", lost_packet_nr);
+
+        // The retransmission takes the lost packet's place in the congestion window, so it doesn't
+        // wait for room (which, while handling a packet, could mean waiting forever). It counts
+        // towards the upload limits without waiting for them either.
         match self.send_window.iter().position(|pkt| pkt.seq_nr() == lost_packet_nr) {
             None => debug!("Packet {} not found", lost_packet_nr),
             Some(position) => {
                 debug!("self.send_window.len(): {}", self.send_window.len());
                 debug!("position: {}", position);
-                let mut packet = self.send_window[position].clone();
-                // FIXME: Unchecked result
-                let _ = self.send_packet(&mut packet);
+                let now = self.socket.now();
+                for limiter in self.rate_limiters() {
+                    limiter.consume_upload(now, self.send_window[position].payload.len());
+                }
+                try!(self.send_packet(position));
 
                 // We intentionally don't increase `curr_window` because otherwise a packet's length
                 // would be counted more than once
             }
         }
         debug!("---> END resend_lost_packet <---");
+        Ok(())
+    }
+
+    /// Starts the retransmission timer, unless it's already running.
+    fn start_retransmit_timer(&mut self) {
+        if self.retransmit_at.is_none() {
+            self.restart_retransmit_timer();
+        }
+    }
+
+    /// Sets the retransmission timer to expire one congestion timeout from now.
+    fn restart_retransmit_timer(&mut self) {
+        let timeout = Duration::from_millis(self.congestion_timeout);
+        self.retransmit_at = Some(self.socket.now() + timeout);
+    }
+
+    /// Sends the oldest unacknowledged packet again, or our FIN once every packet before it was
+    /// acknowledged, as its acknowledgement is overdue.
+    ///
+    /// The congestion window restarts from its minimum, and the congestion timeout doubles until
+    /// the next round-trip time measurement. After `MAX_RETRANSMISSIONS` timeouts in a row, the
+    /// connection fails with `Error::TimedOut(Timeout::Retransmit)`, unless only our FIN is left
+    /// unacknowledged, in which case it's closed as if it had been.
+    fn handle_retransmit_timeout(&mut self) -> Result<()> {
+        self.retransmit_at = None;
+        let outstanding = !self.send_window.is_empty() ||
+            (self.state == SocketState::FinSent && !self.fin_acked);
+        if outstanding && self.retransmissions >= MAX_RETRANSMISSIONS {
+            if self.send_window.is_empty() {
+                debug!("FIN unacknowledged, closing anyway");
+                self.state = SocketState::Closed;
+                return Ok(());
+            }
+            debug!("remote peer stopped acknowledging packets, aborting");
+            self.discard();
+            return Err(Error::TimedOut(Timeout::Retransmit));
+        }
+
+        if let Some(seq_nr) = self.send_window.first().map(|p| p.seq_nr()) {
+            debug!("retransmission timeout, sending packet {} again", seq_nr);
+            self.cwnd = MIN_CWND * MSS;
+            self.fast_resent = None;
+            self.recovery_point = self.send_window.last().map(|p| p.seq_nr());
+            try!(self.resend_lost_packet(seq_nr));
+        } else if self.state == SocketState::FinSent && !self.fin_acked {
+            debug!("retransmission timeout, sending FIN again");
+            let packet = self.fin_packet();
+            try!(self.send_to_peer(&packet.to_bytes()[..]));
+        } else {
+            return Ok(());
+        }
+        self.retransmissions += 1;
+        self.congestion_timeout = min(self.congestion_timeout * 2, MAX_CONGESTION_TIMEOUT);
+        self.restart_retransmit_timer();
+        Ok(())
     }
 
     /// Forgets sent packets that were acknowledged by the remote peer.
@@ -672,31 +2085,66 @@ impl UtpSocket {
         debug!("self.curr_window: {}", self.curr_window);
     }
 
+    /// Accepts a connection request from a remote peer, returning the reply to send.
+    fn accept_syn(&mut self, packet: &PacketRef, src: SocketAddr) -> Packet {
+        self.connected_to = src;
+        self.ack_nr = packet.seq_nr();
+        self.seq_nr = rand::random();
+        self.receiver_connection_id = packet.connection_id().wrapping_add(1);
+        self.sender_connection_id = packet.connection_id();
+        self.state = SocketState::Connected;
+        self.last_dropped = self.ack_nr;
+        self.negotiated_extensions = self.extensions.answer(packet);
+
+        self.syn_reply(packet)
+    }
+
+    /// Returns the reply to the remote peer's SYN, answering the extensions it offered.
+    fn syn_reply(&self, packet: &PacketRef) -> Packet {
+        let mut reply = self.prepare_reply(packet, PacketType::State);
+        reply.extensions = self.negotiated_extensions.clone();
+        reply
+    }
+
     /// Handles an incoming packet, updating socket state accordingly.
     ///
     /// Returns the appropriate reply packet, if needed.
-    fn handle_packet(&mut self, packet: &Packet, src: SocketAddr) -> Result<Option<Packet>> {
+    fn handle_packet(&mut self, packet: &PacketRef, src: SocketAddr) -> Result<Option<Packet>> {
         debug!("({:?}, {:?})", self.state, packet.get_type());
 
-        // Acknowledge only if the packet strictly follows the previous one
-        if packet.seq_nr().wrapping_sub(self.ack_nr) == 1 {
-            self.ack_nr = packet.seq_nr();
-        }
-
-        // Reset connection if connection id doesn't match and this isn't a SYN
+        // Reset connection if connection id doesn't match and this isn't a SYN (or a reset, which
+        // must never be answered)
         if packet.get_type() != PacketType::Syn &&
             self.state != SocketState::SynSent &&
             !(packet.connection_id() == self.sender_connection_id ||
               packet.connection_id() == self.receiver_connection_id) {
+            if packet.get_type() == PacketType::Reset {
+                return Ok(None);
+            }
             return Ok(Some(self.prepare_reply(packet, PacketType::Reset)));
         }
 
+        // Acknowledge only if the packet strictly follows the previous one, along with every
+        // buffered packet that it makes contiguous. Nothing past the end of the stream is
+        // acknowledged, and `State` packets don't take up a sequence number of their own.
+        let receiving = matches!(self.state,
+                                 SocketState::Connected | SocketState::FinSent |
+                                 SocketState::FinReceived);
+        if receiving && packet.get_type() != PacketType::State &&
+            packet.seq_nr().wrapping_sub(self.ack_nr) == 1 &&
+            !self.is_after_eof(packet.seq_nr()) {
+            self.ack_nr = packet.seq_nr();
+            while self.incoming_buffer.iter().any(|p| p.seq_nr() == self.ack_nr.wrapping_add(1)) {
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+        }
+
         // Update remote window size
         self.remote_wnd_size = packet.wnd_size();
         debug!("self.remote_wnd_size: {}", self.remote_wnd_size);
 
         // Update remote peer's delay between them sending the packet and us receiving it
-        let now = now_microseconds();
+        let now = self.socket.now_microseconds();
         self.their_delay = if now > packet.timestamp_microseconds() {
             now - packet.timestamp_microseconds()
         } else {
@@ -706,71 +2154,162 @@ impl UtpSocket {
 
         match (self.state, packet.get_type()) {
             (SocketState::New, PacketType::Syn) => {
-                self.connected_to = src;
-                self.ack_nr = packet.seq_nr();
-                self.seq_nr = rand::random();
-                self.receiver_connection_id = packet.connection_id() + 1;
-                self.sender_connection_id = packet.connection_id();
-                self.state = SocketState::Connected;
-                self.last_dropped = self.ack_nr;
-
-                Ok(Some(self.prepare_reply(packet, PacketType::State)))
+                Ok(Some(self.accept_syn(packet, src)))
+            },
+            // Both peers sent a SYN at the same time. Only one connection must come out of it, so
+            // the SYN with the highest connection id wins, and its sender becomes the initiator.
+            (SocketState::SynSent, PacketType::Syn)
+                if self.simultaneous_open && src == self.connected_to => {
+                let (ours, theirs) = (self.receiver_connection_id, packet.connection_id());
+                if theirs > ours {
+                    Ok(Some(self.accept_syn(packet, src)))
+                } else {
+                    if theirs == ours {
+                        // Neither wins, so pick another id and let the next SYNs decide
+                        self.receiver_connection_id = rand::random();
+                        self.sender_connection_id = self.receiver_connection_id.wrapping_add(1);
+                    }
+                    Ok(None)
+                }
+            },
+            // The remote peer didn't get our reply to its SYN and is retrying
+            (SocketState::Connected, PacketType::Syn) |
+            (SocketState::FinSent, PacketType::Syn) |
+            (SocketState::FinReceived, PacketType::Syn)
+                if packet.connection_id() == self.sender_connection_id && src == self.connected_to => {
+                Ok(Some(self.syn_reply(packet)))
             },
+            // A SYN that lost the simultaneous open, arriving late
+            (SocketState::Connected, PacketType::Syn) |
+            (SocketState::FinSent, PacketType::Syn) |
+            (SocketState::FinReceived, PacketType::Syn)
+                if self.simultaneous_open && src == self.connected_to => Ok(None),
             (_, PacketType::Syn) => {
                 Ok(Some(self.prepare_reply(packet, PacketType::Reset)))
-            }
+            },
+            (SocketState::New, PacketType::Reset) |
+            (SocketState::Closed, PacketType::Reset) |
+            (SocketState::ResetReceived, _) => Ok(None),
+            (SocketState::New, _) => {
+                // There is no connection to speak of
+                Ok(Some(self.prepare_reply(packet, PacketType::Reset)))
+            },
             (SocketState::SynSent, PacketType::State) => {
                 self.connected_to = src;
                 self.ack_nr = packet.seq_nr();
-                self.seq_nr += 1;
+                self.seq_nr = self.seq_nr.wrapping_add(1);
                 self.state = SocketState::Connected;
                 self.last_acked = packet.ack_nr();
-                self.last_acked_timestamp = now_microseconds();
+                self.last_acked_timestamp = self.socket.now_microseconds();
+                // The remote peer's first data packet reuses the sequence number of its reply
+                self.last_dropped = self.ack_nr.wrapping_sub(1);
+                self.negotiated_extensions = self.extensions.answered(packet);
                 Ok(None)
             },
-            (SocketState::SynSent, _) => {
-                Err(Error::from(SocketError::InvalidReply))
-            }
+            (SocketState::SynSent, PacketType::Reset) => Err(Error::ConnectionRefused),
+            (SocketState::SynSent, ty) => Err(Error::ProtocolViolation(ty)),
+            (_, PacketType::Reset) => {
+                self.state = SocketState::ResetReceived;
+                Err(Error::ConnectionReset)
+            },
             (SocketState::Connected, PacketType::Data) => {
                 Ok(self.handle_data_packet(packet))
             },
-            (SocketState::Connected, PacketType::State) => {
-                self.handle_state_packet(packet);
+            (SocketState::FinSent, PacketType::Data) |
+            (SocketState::FinReceived, PacketType::Data) => {
+                if self.is_after_eof(packet.seq_nr()) {
+                    debug!("Ignoring packet {} past the end of the stream", packet.seq_nr());
+                    return Ok(None);
+                }
+                self.handle_fin_progress();
+                Ok(self.handle_data_packet(packet))
+            },
+            (SocketState::Connected, PacketType::State) |
+            (SocketState::FinReceived, PacketType::State) => {
+                try!(self.handle_state_packet(packet));
                 Ok(None)
             },
             (SocketState::Connected, PacketType::Fin) |
-            (SocketState::FinSent,   PacketType::Fin) => {
-                // If all packets are received and handled
+            (SocketState::FinReceived, PacketType::Fin) => {
+                if self.eof_pkt.is_none() {
+                    self.eof_pkt = Some(packet.seq_nr());
+                }
+                try!(self.handle_state_packet(packet));
+
+                // The remote peer won't send anything else, but we may still do so
+                self.state = SocketState::FinReceived;
+                self.handle_fin_progress();
+                Ok(self.handle_data_packet(packet))
+            },
+            (SocketState::FinSent, PacketType::Fin) => {
+                if self.eof_pkt.is_none() {
+                    self.eof_pkt = Some(packet.seq_nr());
+                }
                 if packet.ack_nr() == self.seq_nr {
-                    self.state = SocketState::Closed;
-                    Ok(Some(self.prepare_reply(packet, PacketType::State)))
+                    self.fin_acked = true;
+                    self.retransmit_at = None;
+                    self.retransmissions = 0;
                 } else {
-                    debug!("FIN received but there are missing packets");
-                    self.handle_state_packet(packet);
-                    Ok(None)
+                    try!(self.handle_state_packet(packet));
                 }
-            }
+                self.handle_fin_progress();
+                Ok(self.handle_data_packet(packet))
+            },
             (SocketState::FinSent, PacketType::State) => {
                 if packet.ack_nr() == self.seq_nr {
-                    self.state = SocketState::Closed;
+                    self.fin_acked = true;
+                    self.retransmit_at = None;
+                    self.retransmissions = 0;
+                    self.handle_fin_progress();
                 } else {
-                    self.handle_state_packet(packet);
+                    try!(self.handle_state_packet(packet));
                 }
                 Ok(None)
-            }
-            (_, PacketType::Reset) => {
-                self.state = SocketState::ResetReceived;
-                Err(Error::from(SocketError::ConnectionReset))
             },
-            (state, ty) => {
-                let message = format!("Unimplemented handling for ({:?},{:?})", state, ty);
-                debug!("{}", message);
-                Err(Error::new(ErrorKind::Other, message))
+            (SocketState::Closed, PacketType::Fin) => {
+                // The remote peer didn't get our acknowledgement of its FIN
+                Ok(Some(self.prepare_reply(packet, PacketType::State)))
+            },
+            (SocketState::Closed, PacketType::Data) => {
+                if packet.seq_nr().wrapping_sub(self.ack_nr) as i16 > 0 {
+                    // New data, but there's no one left to deliver it to
+                    Ok(Some(self.prepare_reply(packet, PacketType::Reset)))
+                } else {
+                    Ok(Some(self.prepare_reply(packet, PacketType::State)))
+                }
+            },
+            (SocketState::Closed, PacketType::State) => Ok(None),
+        }
+    }
+
+    /// Returns whether the given sequence number comes after the remote peer's FIN packet.
+    fn is_after_eof(&self, seq_nr: u16) -> bool {
+        match self.eof_pkt {
+            Some(eof_pkt) => seq_nr.wrapping_sub(eof_pkt) as i16 > 0,
+            None => false,
+        }
+    }
+
+    /// Returns whether every packet preceding the remote peer's FIN packet was received.
+    fn fin_reached(&self) -> bool {
+        match self.eof_pkt {
+            Some(eof_pkt) => eof_pkt.wrapping_sub(self.ack_nr) <= 1,
+            None => false,
+        }
+    }
+
+    /// Acknowledges the remote peer's FIN once every packet preceding it was received, closing the
+    /// connection if our own FIN was acknowledged as well.
+    fn handle_fin_progress(&mut self) {
+        if self.fin_reached() {
+            self.ack_nr = self.eof_pkt.unwrap();
+            if self.fin_acked {
+                self.state = SocketState::Closed;
             }
         }
     }
 
-    fn handle_data_packet(&mut self, packet: &Packet) -> Option<Packet> {
+    fn handle_data_packet(&mut self, packet: &PacketRef) -> Option<Packet> {
         let mut reply = self.prepare_reply(packet, PacketType::State);
 
         if packet.seq_nr().wrapping_sub(self.ack_nr) > 1 {
@@ -834,17 +2373,38 @@ impl UtpSocket {
         debug!("max_allowed_cwnd: {}", max_allowed_cwnd);
     }
 
-    fn handle_state_packet(&mut self, packet: &Packet) {
+    fn handle_state_packet(&mut self, packet: &PacketRef) -> Result<()> {
         if packet.ack_nr() == self.last_acked {
-            self.duplicate_ack_count += 1;
+            // Only count duplicates while there are packets in flight, and shortly after the
+            // last new acknowledgement, so that keep-alives from an idle peer aren't mistaken
+            // for a sign of packet loss
+            let since_last_ack = self.socket.now_microseconds()
+                .wrapping_sub(self.last_acked_timestamp);
+            if !self.send_window.is_empty() &&
+                (since_last_ack as u64) < self.congestion_timeout * 1000 {
+                self.duplicate_ack_count += 1;
+            }
         } else {
             self.last_acked = packet.ack_nr();
-            self.last_acked_timestamp = now_microseconds();
+            self.last_acked_timestamp = self.socket.now_microseconds();
             self.duplicate_ack_count = 1;
+
+            // Packets acknowledged from now on were sent after the loss handling so far
+            let acked = |seq_nr: Option<u16>| {
+                seq_nr.map(|seq_nr| packet.ack_nr().wrapping_sub(seq_nr) as i16 >= 0)
+                    .unwrap_or(false)
+            };
+            if acked(self.fast_resent) {
+                self.fast_resent = None;
+            }
+            if acked(self.recovery_point) {
+                self.recovery_point = None;
+            }
         }
 
         // Update congestion window size
-        if let Some(index) = self.send_window.iter().position(|p| packet.ack_nr() == p.seq_nr()) {
+        let newly_acked = self.send_window.iter().position(|p| packet.ack_nr() == p.seq_nr());
+        if let Some(index) = newly_acked {
             // Calculate the sum of the size of every packet implicitly and explictly acknowledged
             // by the inbout packet (i.e., every packet whose sequence number precedes the inbound
             // packet's acknowledgement number, plus the packet whose sequence number matches)
@@ -853,7 +2413,7 @@ impl UtpSocket {
                 .fold(0, |acc, p| acc + p.len());
 
             // Update base and current delay
-            let now = now_microseconds() as i64;
+            let now = self.socket.now_microseconds() as i64;
             let our_delay = now - self.send_window[index].timestamp_microseconds() as i64;
             debug!("our_delay: {}", our_delay);
             self.update_base_delay(our_delay, now);
@@ -864,31 +2424,27 @@ impl UtpSocket {
 
             self.update_congestion_window(off_target, bytes_newly_acked as u32);
 
-            // Update congestion timeout
-            let rtt = (TARGET - off_target as i64) / 1000; // in milliseconds
-            self.update_congestion_timeout(rtt as i32);
+            // Update congestion timeout with the round-trip time
+            self.update_congestion_timeout((our_delay / 1000) as i32);
         }
 
-        let mut packet_loss_detected: bool = !self.send_window.is_empty() &&
-                                             self.duplicate_ack_count == 3;
-
-        // Process extensions, if any
-        for extension in packet.extensions.iter() {
+        // The packet following the acknowledged one is lost after three duplicate ACKs, as is any
+        // packet that three or more selectively acknowledged packets follow
+        let mut lost = Vec::new();
+        if self.duplicate_ack_count == LOSS_THRESHOLD {
+            lost.push(packet.ack_nr().wrapping_add(1));
+        }
+        for extension in packet.extensions() {
             if extension.get_type() == ExtensionType::SelectiveAck {
-                // If three or more packets are acknowledged past the implicit missing one,
-                // assume it was lost.
-                if extension.iter().count_ones() >= 3 {
-                    self.resend_lost_packet(packet.ack_nr() + 1);
-                    packet_loss_detected = true;
+                let mut acked_after = extension.iter().count_ones();
+                if acked_after >= LOSS_THRESHOLD && lost.is_empty() {
+                    lost.push(packet.ack_nr().wrapping_add(1));
                 }
-
-                for seq_nr in extension.iter().enumerate()
-                    .filter(|&(_idx, received)| !received)
-                    .map(|(idx, _received)| packet.ack_nr() + 2 + idx as u16) {
-                    if self.send_window.last().map(|p| seq_nr < p.seq_nr()).unwrap_or(false) {
-                        debug!("SACK: packet {} lost", seq_nr);
-                        self.resend_lost_packet(seq_nr);
-                        packet_loss_detected = true;
+                for (idx, received) in extension.iter().enumerate() {
+                    if received {
+                        acked_after -= 1;
+                    } else if acked_after >= LOSS_THRESHOLD {
+                        lost.push(packet.ack_nr().wrapping_add(2 + idx as u16));
                     } else {
                         break;
                     }
@@ -898,27 +2454,44 @@ impl UtpSocket {
             }
         }
 
-        // Three duplicate ACKs, must resend packets since `ack_nr + 1`
-        // TODO: checking if the send buffer isn't empty isn't a
-        // foolproof way to differentiate between triple-ACK and three
-        // keep alives spread in time
-        if !self.send_window.is_empty() && self.duplicate_ack_count == 3 {
-            for i in (0..self.send_window.len()) {
-                let seq_nr = self.send_window[i].seq_nr();
-                if seq_nr <= packet.ack_nr() { continue; }
-                self.resend_lost_packet(seq_nr);
+        // Every lost packet is sent again once, but the congestion window is only halved once
+        // per window of packets
+        let mut packet_loss_detected = false;
+        for seq_nr in lost {
+            let resent = self.fast_resent
+                .map(|last| seq_nr.wrapping_sub(last) as i16 <= 0)
+                .unwrap_or(false);
+            if resent || !self.send_window.iter().any(|p| p.seq_nr() == seq_nr) {
+                continue;
             }
+            debug!("packet {} lost", seq_nr);
+            try!(self.resend_lost_packet(seq_nr));
+            self.fast_resent = Some(seq_nr);
+            packet_loss_detected = true;
         }
 
-        // Packet lost, halve the congestion window
-        if packet_loss_detected {
+        if packet_loss_detected && self.recovery_point.is_none() {
             debug!("packet loss detected, halving congestion window");
             self.cwnd = max(self.cwnd / 2, MIN_CWND * MSS);
+            self.recovery_point = self.send_window.last().map(|p| p.seq_nr());
             debug!("cwnd: {}", self.cwnd);
         }
 
         // Success, advance send window
         self.advance_send_window();
+
+        // The retransmission timer restarts with every acknowledgement of new data, and stops
+        // once nothing is left to acknowledge
+        if newly_acked.is_some() {
+            self.retransmissions = 0;
+        }
+        if self.send_window.is_empty() &&
+            (self.state != SocketState::FinSent || self.fin_acked) {
+            self.retransmit_at = None;
+        } else if newly_acked.is_some() {
+            self.restart_retransmit_timer();
+        }
+        Ok(())
     }
 
     /// Inserts a packet into the socket's buffer.
@@ -930,17 +2503,28 @@ impl UtpSocket {
     /// Inserting a duplicate of a packet will replace the one in the buffer if
     /// it's more recent (larger timestamp).
     fn insert_into_buffer(&mut self, packet: Packet) {
+        // Sequence numbers wrap around, so order packets by their distance to the last one
+        // delivered instead
+        let last_dropped = self.last_dropped;
+        let position = |p: &Packet| p.seq_nr().wrapping_sub(last_dropped);
+
         // Immediately push to the end if the packet's sequence number comes after the last
         // packet's.
-        if self.incoming_buffer.last().map(|p| packet.seq_nr() > p.seq_nr()).unwrap_or(false) {
+        if self.incoming_buffer.last().map(|p| position(&packet) > position(p)).unwrap_or(false) {
             self.incoming_buffer.push(packet);
         } else {
             // Find index following the most recent packet before the one we wish to insert
-            let i = self.incoming_buffer.iter().filter(|p| p.seq_nr() < packet.seq_nr()).count();
+            let i = self.incoming_buffer.iter()
+                .filter(|p| position(p) < position(&packet)).count();
 
-            // Remove packet if it's a duplicate
+            // Remove packet if it's a duplicate, unless it's already being read
             if self.incoming_buffer.get(i).map(|p| p.seq_nr() == packet.seq_nr()).unwrap_or(false) {
-                self.incoming_buffer.remove(i);
+                if i == 0 && self.read_offset > 0 {
+                    self.recycle_packet(packet);
+                    return;
+                }
+                let duplicate = self.incoming_buffer.remove(i);
+                self.recycle_packet(duplicate);
             }
 
             self.incoming_buffer.insert(i, packet);
@@ -948,9 +2532,44 @@ impl UtpSocket {
     }
 }
 
+#[cfg(any(test, fuzzing))]
+impl UtpSocket {
+    /// Returns the next sequence number to send and the last one acknowledged.
+    pub(crate) fn sequence_numbers(&self) -> (u16, u16) {
+        (self.seq_nr, self.ack_nr)
+    }
+
+    /// Returns the one-way delay of the latest packet received from the remote peer, in
+    /// microseconds.
+    pub(crate) fn received_delay(&self) -> u32 {
+        self.their_delay
+    }
+
+    /// Returns the number of bytes in flight, checking that it matches the send window.
+    pub(crate) fn bytes_in_flight(&self) -> u32 {
+        let in_flight = self.send_window.iter().fold(0, |acc, p| acc + p.len() as u32);
+        assert_eq!(self.curr_window, in_flight);
+        self.curr_window
+    }
+}
+
 impl Drop for UtpSocket {
     fn drop(&mut self) {
-        let _ = self.close();
+        match self.state {
+            SocketState::Connected | SocketState::FinSent | SocketState::FinReceived => (),
+            _ => return,
+        }
+
+        let timeout = self.linger.unwrap_or(Duration::from_secs(DEFAULT_LINGER));
+        if timeout == Duration::from_secs(0) {
+            let _ = self.abort();
+            return;
+        }
+
+        // Hand the connection over to a background thread, leaving an inert socket behind
+        let mut lingering = UtpSocket::new(self.socket.clone(), self.connected_to);
+        mem::swap(self, &mut lingering);
+        thread::spawn(move || lingering.close_with_timeout(timeout));
     }
 }
 
@@ -983,20 +2602,113 @@ impl Drop for UtpSocket {
 pub struct UtpListener {
     /// The public facing UDP socket
     socket: UdpSocket,
+
+    /// Handler for datagrams of other protocols sharing the socket
+    handler: Mutex<Option<DatagramHandler>>,
+
+    /// Handlers of the extensions negotiated with incoming connections
+    extensions: ExtensionRegistry,
+
+    /// Bandwidth limits shared by the accepted connections, if any
+    rate_limiter: Option<RateLimiter>,
 }
 
+/// Handler for the datagrams of other protocols received by a `UtpListener`.
+type DatagramHandler = Box<dyn FnMut(&[u8], SocketAddr) + Send>;
+
 impl UtpListener {
     /// Creates a new `UtpListener` bound to a specific address.
     ///
-    /// The resulting listener is ready for accepting connections.
+    /// The resulting listener is ready for accepting connections.
+    ///
+    /// The address type can be any implementor of the `ToSocketAddr` trait. See its documentation
+    /// for concrete examples.
+    ///
+    /// If more than one valid address is specified, only the first will be used.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpListener> {
+        let addr = try!(resolve(addr));
+        let socket = try!(UdpSocket::bind(addr));
+        Ok(UtpListener::from_socket(socket))
+    }
+
+    /// Creates a new `UtpListener` accepting connections over both IPv6 and IPv4 on the given
+    /// port of every local address, or on a port chosen by the operating system if 0.
+    ///
+    /// The listener has a single IPv6 socket, which also receives IPv4 datagrams (on Linux, it
+    /// does so regardless of the `net.ipv6.bindv6only` setting, and elsewhere only if that's the
+    /// system's default). Remote peers connecting over IPv4 are still reported with their IPv4
+    /// address.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpListener;
+    ///
+    /// let listener = UtpListener::bind_dual_stack(6881).unwrap();
+    /// for connection in listener.incoming() {
+    ///     let (socket, src) = connection.unwrap();
+    ///     println!("Connection from {} (IPv{})", src, if src.is_ipv4() { 4 } else { 6 });
+    /// }
+    /// ```
+    pub fn bind_dual_stack(port: u16) -> Result<UtpListener> {
+        let socket = try!(transport::bind_dual_stack(port));
+        Ok(UtpListener::from_socket(socket))
+    }
+
+    fn from_socket(socket: UdpSocket) -> UtpListener {
+        UtpListener {
+            socket,
+            handler: Mutex::new(None),
+            extensions: ExtensionRegistry::default(),
+            rate_limiter: None,
+        }
+    }
+
+    /// Adds a handler for an extension to negotiate with incoming connections, replacing any
+    /// previous handler for the same extension type.
+    ///
+    /// Extensions offered by the remote peer are answered by the matching handler, and the
+    /// outcome is available from the accepted socket's `negotiated_extensions`.
+    pub fn register_extension<H: ExtensionHandler + 'static>(&mut self, handler: H) {
+        self.extensions.register(Arc::new(handler));
+    }
+
+    /// Makes the connections accepted from now on share the limits of `limiter` (see
+    /// `UtpSocket::set_rate_limiter`), or not share any limits if `None`.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// Sets a handler for datagrams that aren't uTP packets, which lets other protocols (e.g., the
+    /// BitTorrent DHT or UDP trackers) share the listener's port.
+    ///
+    /// The handler is called from `accept` with the datagram and its source address, and `accept`
+    /// keeps waiting for a connection afterwards. Without a handler, such datagrams make `accept`
+    /// fail.
     ///
-    /// The address type can be any implementor of the `ToSocketAddr` trait. See its documentation
-    /// for concrete examples.
+    /// # Examples
     ///
-    /// If more than one valid address is specified, only the first will be used.
-    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpListener> {
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        UdpSocket::bind(addr).and_then(|s| Ok(UtpListener { socket: s}))
+    /// ```no_run
+    /// use utp::UtpListener;
+    /// use std::sync::mpsc::channel;
+    ///
+    /// let mut listener = UtpListener::bind("0.0.0.0:6881").unwrap();
+    /// let (tx, rx) = channel();
+    /// listener.set_datagram_handler(move |datagram, src| {
+    ///     let _ = tx.send((datagram.to_vec(), src));
+    /// });
+    /// ```
+    pub fn set_datagram_handler<F>(&mut self, handler: F)
+        where F: FnMut(&[u8], SocketAddr) + Send + 'static {
+        *self.handler.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(handler));
+    }
+
+    /// Sends a datagram of another protocol through the listener's socket.
+    ///
+    /// The datagram is sent as is, so it must not be mistaken for a uTP packet by the remote peer.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize> {
+        let addr = mapped(try!(self.socket.local_addr()), try!(resolve(addr)));
+        self.socket.send_to(buf, addr).map_err(Error::from)
     }
 
     /// Accepts a new incoming connection from this listener.
@@ -1006,63 +2718,46 @@ impl UtpListener {
     ///
     /// Notice that the resulting `UtpSocket` is bound to a different local port than the public
     /// listening port (which `UtpListener` holds). This may confuse the remote peer!
+    ///
+    /// Datagrams that aren't uTP packets are passed to the datagram handler, if any (see
+    /// `set_datagram_handler`).
     pub fn accept(&self) -> Result<(UtpSocket, SocketAddr)> {
         let mut buf = [0; BUF_SIZE];
 
-        match self.socket.recv_from(&mut buf) {
-            Ok((nread, src)) => {
-                let packet = try!(Packet::from_bytes(&buf[..nread]).or(Err(SocketError::InvalidPacket)));
-
-                // Ignore non-SYN packets
-                if packet.get_type() != PacketType::Syn {
-                    return Err(Error::from(SocketError::InvalidPacket));
+        loop {
+            let (nread, src) = try!(self.socket.recv_from(&mut buf));
+            let src = unmapped(src);
+            match PacketRef::from_bytes(&buf[..nread]) {
+                Ok(packet) => return self.accept_packet(&packet, src),
+                Err(e) => {
+                    let mut handler = self.handler.lock().unwrap_or_else(|e| e.into_inner());
+                    match *handler {
+                        Some(ref mut handler) => handler(&buf[..nread], src),
+                        None => return Err(Error::InvalidPacket(e)),
+                    }
                 }
+            }
+        }
+    }
 
-                // The address of the new socket will depend on the type of the listener.
-                let inner_socket = match self.socket.local_addr().unwrap() {
-                    SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
-                    SocketAddr::V6(_) => UdpSocket::bind(":::0"),
-                };
-
-                let mut socket = UtpSocket {
-                    socket: inner_socket.unwrap(),
-                    connected_to: src,
-                    receiver_connection_id: 0,
-                    sender_connection_id: 0,
-                    seq_nr: 1,
-                    ack_nr: 0,
-                    state: SocketState::New,
-                    incoming_buffer: Vec::new(),
-                    send_window: Vec::new(),
-                    unsent_queue: VecDeque::new(),
-                    duplicate_ack_count: 0,
-                    last_acked: 0,
-                    last_acked_timestamp: 0,
-                    last_dropped: 0,
-                    rtt: 0,
-                    rtt_variance: 0,
-                    pending_data: Vec::new(),
-                    curr_window: 0,
-                    remote_wnd_size: 0,
-                    current_delays: Vec::new(),
-                    base_delays: VecDeque::with_capacity(BASE_HISTORY),
-                    their_delay: 0,
-                    last_rollover: 0,
-                    congestion_timeout: INITIAL_CONGESTION_TIMEOUT,
-                    cwnd: INIT_CWND * MSS,
-                };
+    /// Establishes a connection requested by `packet`.
+    fn accept_packet(&self, packet: &PacketRef, src: SocketAddr)
+                     -> Result<(UtpSocket, SocketAddr)> {
+        if packet.get_type() != PacketType::Syn {
+            return Err(Error::ProtocolViolation(packet.get_type()));
+        }
 
-                // Establish connection with remote peer
-                match socket.handle_packet(&packet, src) {
-                    Ok(Some(reply)) => { try!(socket.socket.send_to(&reply.to_bytes()[..], src)) },
-                    Ok(None) => return Err(Error::new(ErrorKind::Other, "Unexpected error handling packet")),
-                    Err(e) => return Err(e)
-                };
+        // The new socket has the remote peer's address family, which may not be the listener's
+        let inner_socket = match src {
+            SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
+            SocketAddr::V6(_) => UdpSocket::bind(":::0"),
+        };
 
-                Ok((socket, src))
-            },
-            Err(e) => Err(e)
-        }
+        let inner_socket = try!(inner_socket);
+        try!(transport::report_unreachable(&inner_socket));
+        let socket = try!(UtpSocket::accept(Arc::new(inner_socket), packet, src,
+                                            &self.extensions, self.rate_limiter.clone()));
+        Ok((socket, src))
     }
 
     /// Returns an iterator over the connections being received by this listener.
@@ -1074,7 +2769,7 @@ impl UtpListener {
 
     /// Returns the local socket address of this listener.
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr()
+        self.socket.local_addr().map_err(Error::from)
     }
 }
 
@@ -1091,10 +2786,13 @@ impl<'a> Iterator for Incoming<'a> {
 #[cfg(test)]
 mod test {
     use std::thread;
-    use std::net::ToSocketAddrs;
+    use std::net::{ToSocketAddrs, SocketAddr};
     use std::io::ErrorKind;
-    use super::{UtpSocket, UtpListener, SocketState, BUF_SIZE};
-    use packet::{Packet, PacketType, Encodable, Decodable};
+    use std::time::Duration;
+    use error::{Error, Result, Timeout};
+    use super::{UtpSocket, UtpListener, SocketState, BUF_SIZE, SYN_TIMEOUT, MIN_CWND, MSS,
+                MAX_CONGESTION_TIMEOUT, MAX_RETRANSMISSIONS};
+    use packet::{Packet, PacketRef, PacketType, Encodable, Decodable};
     use util::now_microseconds;
     use rand;
 
@@ -1117,6 +2815,13 @@ mod test {
         ("::1", next_test_port())
     }
 
+    /// Handles a packet as if it had just been received.
+    fn handle_packet(socket: &mut UtpSocket, packet: &Packet, src: SocketAddr)
+                     -> Result<Option<Packet>> {
+        let bytes = packet.to_bytes();
+        socket.handle_packet(&PacketRef::from_bytes(&bytes).unwrap(), src)
+    }
+
     #[test]
     fn test_socket_ipv4() {
         let server_addr = next_test_ip4();
@@ -1142,7 +2847,8 @@ mod test {
         // After establishing a new connection, the server's ids are a mirror of the client's.
         assert_eq!(server.receiver_connection_id, server.sender_connection_id + 1);
 
-        assert!(server.state == SocketState::Closed);
+        // The client is done sending, but the server may still do so
+        assert!(server.state == SocketState::FinReceived);
         drop(server);
     }
 
@@ -1171,7 +2877,8 @@ mod test {
         // After establishing a new connection, the server's ids are a mirror of the client's.
         assert_eq!(server.receiver_connection_id, server.sender_connection_id + 1);
 
-        assert!(server.state == SocketState::Closed);
+        // The client is done sending, but the server may still do so
+        assert!(server.state == SocketState::FinReceived);
         drop(server);
     }
 
@@ -1191,6 +2898,8 @@ mod test {
         // Make the server listen for incoming connections until the end of the input
         let mut buf = [0u8; BUF_SIZE];
         let _resp = server.recv_from(&mut buf);
+        assert!(server.state == SocketState::FinReceived);
+        iotry!(server.close());
         assert!(server.state == SocketState::Closed);
 
         // Trying to receive again returns Ok(0) [EndOfFile]
@@ -1201,6 +2910,32 @@ mod test {
         assert_eq!(server.state, SocketState::Closed);
     }
 
+    #[test]
+    fn test_recvfrom_after_shutdown_read() {
+        use std::net::Shutdown;
+        let server_addr = next_test_ip4();
+
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&[1, 2, 3]));
+            iotry!(client.close());
+        });
+
+        // Accept connection
+        let mut buf = [0u8; BUF_SIZE];
+        iotry!(server.recv(&mut buf));
+
+        // Received data is discarded from now on
+        iotry!(server.shutdown(Shutdown::Read));
+        match server.recv_from(&mut buf) {
+            Ok((0, _src)) => {},
+            e => panic!("Expected Ok(0), got {:?}", e),
+        }
+        assert!(server.incoming_buffer.is_empty());
+    }
+
     #[test]
     fn test_sendto_on_closed_socket() {
         let server_addr = next_test_ip4();
@@ -1218,6 +2953,8 @@ mod test {
         // Make the server listen for incoming connections
         let mut buf = [0u8; BUF_SIZE];
         let (_read, _src) = iotry!(server.recv_from(&mut buf));
+        assert_eq!(server.state, SocketState::FinReceived);
+        iotry!(server.close());
         assert_eq!(server.state, SocketState::Closed);
 
         // Trying to send to the socket after closing it raises an error
@@ -1278,7 +3015,7 @@ mod test {
         packet.set_connection_id(initial_connection_id);
 
         // Do we have a response?
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1309,7 +3046,7 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1342,7 +3079,7 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1363,6 +3100,168 @@ mod test {
         //}
     }
 
+    #[test]
+    fn test_state_transition_table() {
+        use super::SocketState::*;
+        use packet::PacketType::*;
+
+        #[derive(Debug, PartialEq)]
+        enum Reply {
+            Nothing,
+            Packet(PacketType),
+            Error(ErrorKind),
+        }
+
+        let table = [
+            // (current state, incoming packet, expected reply, next state)
+            (New, Syn, Reply::Packet(State), Connected),
+            (New, Data, Reply::Packet(Reset), New),
+            (New, State, Reply::Packet(Reset), New),
+            (New, Fin, Reply::Packet(Reset), New),
+            (New, Reset, Reply::Nothing, New),
+
+            (SynSent, Syn, Reply::Packet(Reset), SynSent),
+            (SynSent, Data, Reply::Error(ErrorKind::InvalidData), SynSent),
+            (SynSent, State, Reply::Nothing, Connected),
+            (SynSent, Fin, Reply::Error(ErrorKind::InvalidData), SynSent),
+            (SynSent, Reset, Reply::Error(ErrorKind::ConnectionRefused), SynSent),
+
+            (Connected, Syn, Reply::Packet(State), Connected),
+            (Connected, Data, Reply::Packet(State), Connected),
+            (Connected, State, Reply::Nothing, Connected),
+            (Connected, Fin, Reply::Packet(State), FinReceived),
+            (Connected, Reset, Reply::Error(ErrorKind::ConnectionReset), ResetReceived),
+
+            (FinSent, Syn, Reply::Packet(State), FinSent),
+            (FinSent, Data, Reply::Packet(State), FinSent),
+            (FinSent, State, Reply::Nothing, FinSent),
+            (FinSent, Fin, Reply::Packet(State), Closed),
+            (FinSent, Reset, Reply::Error(ErrorKind::ConnectionReset), ResetReceived),
+
+            // The remote peer's FIN is still a few packets ahead
+            (FinReceived, Syn, Reply::Packet(State), FinReceived),
+            (FinReceived, Data, Reply::Packet(State), FinReceived),
+            (FinReceived, State, Reply::Nothing, FinReceived),
+            (FinReceived, Fin, Reply::Packet(State), FinReceived),
+            (FinReceived, Reset, Reply::Error(ErrorKind::ConnectionReset), ResetReceived),
+
+            (ResetReceived, Syn, Reply::Packet(Reset), ResetReceived),
+            (ResetReceived, Data, Reply::Nothing, ResetReceived),
+            (ResetReceived, State, Reply::Nothing, ResetReceived),
+            (ResetReceived, Fin, Reply::Nothing, ResetReceived),
+            (ResetReceived, Reset, Reply::Nothing, ResetReceived),
+
+            (Closed, Syn, Reply::Packet(Reset), Closed),
+            (Closed, Data, Reply::Packet(Reset), Closed),
+            (Closed, State, Reply::Nothing, Closed),
+            (Closed, Fin, Reply::Packet(State), Closed),
+            (Closed, Reset, Reply::Nothing, Closed),
+        ];
+
+        for &(state, ty, ref expected_reply, expected_state) in table.iter() {
+            let initial_connection_id: u16 = rand::random();
+            let (server_addr, client_addr) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                              next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
+            let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+            let mut syn = Packet::new();
+            syn.set_type(PacketType::Syn);
+            syn.set_seq_nr(1);
+            syn.set_connection_id(initial_connection_id);
+
+            if state != New {
+                iotry!(handle_packet(&mut socket, &syn, client_addr));
+                socket.state = state;
+            }
+            if state == FinReceived {
+                socket.eof_pkt = Some(syn.seq_nr() + 5);
+            }
+
+            let mut packet = Packet::new();
+            packet.set_type(ty);
+            packet.set_connection_id(initial_connection_id);
+            packet.set_seq_nr(syn.seq_nr() + 1);
+            packet.set_ack_nr(socket.seq_nr);
+
+            let reply = match handle_packet(&mut socket, &packet, client_addr) {
+                Ok(None) => Reply::Nothing,
+                Ok(Some(reply)) => Reply::Packet(reply.get_type()),
+                Err(e) => Reply::Error(e.kind()),
+            };
+            assert!(reply == *expected_reply, "({:?}, {:?}): expected reply {:?}, got {:?}",
+                    state, ty, expected_reply, reply);
+            assert!(socket.state == expected_state, "({:?}, {:?}): expected state {:?}, got {:?}",
+                    state, ty, expected_state, socket.state);
+
+            // Avoid blocking on drop
+            socket.state = Closed;
+        }
+    }
+
+    #[test]
+    fn test_data_around_fin() {
+        let initial_connection_id: u16 = rand::random();
+        let (server_addr, client_addr) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                          next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::Syn);
+        packet.set_seq_nr(1);
+        packet.set_connection_id(initial_connection_id);
+        iotry!(handle_packet(&mut socket, &packet, client_addr));
+
+        let make_packet = |ty: PacketType, seq_nr: u16, payload: Vec<u8>| {
+            let mut packet = Packet::new();
+            packet.set_type(ty);
+            packet.set_connection_id(initial_connection_id);
+            packet.set_seq_nr(seq_nr);
+            packet.payload = payload;
+            packet
+        };
+
+        // The FIN overtakes the data packets preceding it
+        let fin = make_packet(PacketType::Fin, 4, vec!());
+        let response = iotry!(handle_packet(&mut socket, &fin, client_addr)).unwrap();
+        assert_eq!(response.get_type(), PacketType::State);
+        assert_eq!(response.ack_nr(), 1);
+        assert_eq!(socket.state, SocketState::FinReceived);
+        assert_eq!(socket.eof_pkt, Some(4));
+
+        // Anything past the end of the stream is ignored
+        let late = make_packet(PacketType::Data, 5, vec!(7, 8, 9));
+        assert!(iotry!(handle_packet(&mut socket, &late, client_addr)).is_none());
+        assert!(!socket.accepts_data(late.seq_nr()));
+        assert_eq!(socket.ack_nr, 1);
+
+        let second = make_packet(PacketType::Data, 3, vec!(4, 5, 6));
+        let response = iotry!(handle_packet(&mut socket, &second, client_addr)).unwrap();
+        assert_eq!(response.ack_nr(), 1);
+        assert!(socket.accepts_data(second.seq_nr()));
+        socket.insert_into_buffer(second);
+        assert_eq!(socket.state, SocketState::FinReceived);
+
+        // Once the gap is filled, the FIN is acknowledged
+        let first = make_packet(PacketType::Data, 2, vec!(1, 2, 3));
+        let response = iotry!(handle_packet(&mut socket, &first, client_addr)).unwrap();
+        assert_eq!(response.ack_nr(), 4);
+        assert!(socket.accepts_data(first.seq_nr()));
+        socket.insert_into_buffer(first);
+        assert_eq!(socket.state, SocketState::FinReceived);
+
+        // Every byte preceding the FIN is still delivered
+        let mut buf = [0; BUF_SIZE];
+        let mut received = vec!();
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert_eq!(received, vec!(1, 2, 3, 4, 5, 6));
+    }
+
     #[test]
     fn test_response_to_keepalive_ack() {
         // Boilerplate test setup
@@ -1377,7 +3276,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1395,13 +3294,13 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_none());
 
         // Send a second keepalive packet, identical to the previous one
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_none());
@@ -1410,6 +3309,91 @@ mod test {
         socket.state = SocketState::Closed;
     }
 
+    #[test]
+    fn test_keepalives_are_not_duplicate_acks() {
+        let initial_connection_id: u16 = rand::random();
+        let client_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
+        let mut socket = iotry!(UtpSocket::bind(next_test_ip4()));
+
+        // Establish connection
+        let mut packet = Packet::new();
+        packet.set_wnd_size(BUF_SIZE as u32);
+        packet.set_type(PacketType::Syn);
+        packet.set_connection_id(initial_connection_id);
+        let response = iotry!(handle_packet(&mut socket, &packet, client_addr)).unwrap();
+        let syn = packet;
+
+        // A packet is in flight, but the remote peer stopped acknowledging anything a while ago
+        let mut data = Packet::with_payload(&[1, 2, 3]);
+        data.set_seq_nr(response.seq_nr() + 1);
+        socket.curr_window += data.len() as u32;
+        socket.send_window.push(data);
+        socket.last_acked = response.seq_nr();
+        socket.last_acked_timestamp = now_microseconds().wrapping_sub(10_000_000);
+
+        // Keep-alives spread in time look just like duplicate acknowledgements
+        let mut packet = Packet::new();
+        packet.set_wnd_size(BUF_SIZE as u32);
+        packet.set_type(PacketType::State);
+        packet.set_connection_id(initial_connection_id);
+        packet.set_seq_nr(syn.seq_nr() + 1);
+        packet.set_ack_nr(response.seq_nr());
+        for _ in 0..3 {
+            assert!(iotry!(handle_packet(&mut socket, &packet, client_addr)).is_none());
+        }
+        assert!(socket.duplicate_ack_count < 3);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_keepalive_and_idle_timeout() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+        iotry!(server.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            client.set_keepalive(Some(Duration::from_millis(20)));
+            client.set_idle_timeout(Some(Duration::from_millis(200)));
+
+            let mut buf = [0; BUF_SIZE];
+            match client.recv_from(&mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => (),
+                x => panic!("Expected ErrorKind::TimedOut, got {:?}", x),
+            }
+        });
+
+        // Accept the connection, then go silent
+        let mut buf = [0; BUF_SIZE];
+        let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+        let syn = Packet::from_bytes(&buf[..len]).ok().unwrap();
+        let mut reply = Packet::new();
+        reply.set_type(PacketType::State);
+        reply.set_connection_id(syn.connection_id());
+        reply.set_seq_nr(rand::random());
+        reply.set_ack_nr(syn.seq_nr());
+        iotry!(server.send_to(&reply.to_bytes()[..], client_addr));
+
+        // Keep-alives arrive until the client gives up on us
+        let mut keepalives = 0;
+        loop {
+            let (len, _src) = iotry!(server.recv_from(&mut buf));
+            let packet = Packet::from_bytes(&buf[..len]).ok().unwrap();
+            match packet.get_type() {
+                PacketType::State => keepalives += 1,
+                PacketType::Reset => break,
+                t => panic!("Unexpected {:?}", t),
+            }
+        }
+        assert!(keepalives >= 2);
+        child.join().unwrap();
+    }
+
     #[test]
     fn test_response_to_wrong_connection_id() {
         // Boilerplate test setup
@@ -1424,7 +3408,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1438,7 +3422,7 @@ mod test {
         packet.set_type(PacketType::State);
         packet.set_connection_id(new_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1465,7 +3449,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = handle_packet(&mut socket, &packet, client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1497,14 +3481,14 @@ mod test {
         window.push(packet);
 
         // Send packets in reverse order
-        let response = socket.handle_packet(&window[1], client_addr);
+        let response = handle_packet(&mut socket, &window[1], client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
         let response = response.unwrap();
         assert!(response.ack_nr() != window[1].seq_nr());
 
-        let response = socket.handle_packet(&window[0], client_addr);
+        let response = handle_packet(&mut socket, &window[0], client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1515,7 +3499,7 @@ mod test {
 
     #[test]
     fn test_socket_unordered_packets() {
-        let server_addr = next_test_ip4();
+        let server_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
 
         let mut server = iotry!(UtpSocket::bind(server_addr));
         assert!(server.state == SocketState::New);
@@ -1525,7 +3509,7 @@ mod test {
             assert!(client.state == SocketState::Connected);
             // Check proper difference in client's send connection id and receive connection id
             assert_eq!(client.sender_connection_id, client.receiver_connection_id + 1);
-            let s = client.socket.try_clone().ok().expect("Error cloning internal UDP socket");
+            let s = client.socket.clone();
             let mut window: Vec<Packet> = Vec::new();
 
             for data in (1..13u8).collect::<Vec<u8>>()[..].chunks(3) {
@@ -1576,7 +3560,7 @@ mod test {
 
         // After establishing a new connection, the server's ids are a mirror of the client's.
         assert_eq!(server.receiver_connection_id, server.sender_connection_id + 1);
-        assert_eq!(server.state, SocketState::Closed);
+        assert_eq!(server.state, SocketState::FinReceived);
         assert_eq!(received.len(), expected.len());
         assert_eq!(received, expected);
     }
@@ -1632,7 +3616,7 @@ mod test {
                 assert_eq!(packet.get_type(), PacketType::Data);
                 assert_eq!(packet.seq_nr(), data_packet.seq_nr());
                 assert!(packet.payload == data_packet.payload);
-                let response = server.handle_packet(&packet, client_addr);
+                let response = handle_packet(&mut server, &packet, client_addr);
                 assert!(response.is_ok());
                 let response = response.unwrap();
                 assert!(response.is_some());
@@ -1742,9 +3726,47 @@ mod test {
         assert_eq!(socket.incoming_buffer[1].timestamp_microseconds(), 456);
     }
 
+    #[test]
+    fn test_partial_reads_from_buffer() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        let mut packet = Packet::with_payload(&[1, 2, 3, 4, 5]);
+        packet.set_seq_nr(1);
+        socket.insert_into_buffer(packet);
+        let mut packet = Packet::with_payload(&[6, 7]);
+        packet.set_seq_nr(2);
+        socket.insert_into_buffer(packet);
+
+        let mut buf = [0; 2];
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 2);
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(socket.buffered_data(), &[3, 4, 5]);
+
+        // A duplicate of the packet being read doesn't replace it
+        let mut packet = Packet::with_payload(&[9]);
+        packet.set_seq_nr(1);
+        socket.insert_into_buffer(packet);
+        assert_eq!(socket.buffered_data(), &[3, 4, 5]);
+
+        socket.consume(1);
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 2);
+        assert_eq!(buf, [4, 5]);
+        assert_eq!(socket.incoming_buffer.len(), 1);
+        assert_eq!(socket.buffered_data(), &[6, 7]);
+        socket.consume(2);
+        assert!(socket.incoming_buffer.is_empty());
+        assert_eq!(socket.read_offset, 0);
+
+        // Both packets read, and the duplicate, are kept to store later packets into
+        assert_eq!(socket.spare_packets.len(), 3);
+        assert!(socket.spare_packets.iter().all(|p| p.payload.capacity() > 0));
+    }
+
     #[test]
     fn test_duplicate_packet_handling() {
-        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+        let (server_addr, client_addr) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                          next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
 
         let client = iotry!(UtpSocket::bind(client_addr));
         let mut server = iotry!(UtpSocket::bind(server_addr));
@@ -1909,6 +3931,120 @@ mod test {
         assert_eq!(received, data);
     }
 
+    /// Returns a connected socket whose remote peer is a plain UDP socket, which never answers
+    /// unless told to.
+    fn connected_to_silent_peer() -> (UtpSocket, ::std::net::UdpSocket) {
+        let peer = iotry!(::std::net::UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_secs(5))));
+        let mut socket = iotry!(UtpSocket::bind(next_test_ip4()));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.receiver_connection_id = 100;
+        socket.sender_connection_id = 101;
+        socket.remote_wnd_size = BUF_SIZE as u32 * 16;
+        socket.state = SocketState::Connected;
+        (socket, peer)
+    }
+
+    /// Receives the next packet sent to `peer`, returning its type and sequence number.
+    fn next_sent(peer: &::std::net::UdpSocket) -> (PacketType, u16) {
+        let mut buf = [0; BUF_SIZE + ::packet::HEADER_SIZE];
+        let (len, _src) = iotry!(peer.recv_from(&mut buf));
+        let packet = PacketRef::from_bytes(&buf[..len]).unwrap();
+        (packet.get_type(), packet.seq_nr())
+    }
+
+    #[test]
+    fn test_retransmit_timeout_resends_oldest_packet() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        iotry!(socket.try_send(&[&[1, 2, 3][..]]));
+        let (ty, seq_nr) = next_sent(&peer);
+        assert_eq!(ty, PacketType::Data);
+        assert!(socket.retransmit_at.is_some());
+
+        let timeout = socket.congestion_timeout;
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        assert_eq!(next_sent(&peer), (PacketType::Data, seq_nr));
+        assert_eq!(socket.retransmissions, 1);
+        assert_eq!(socket.cwnd, MIN_CWND * MSS);
+        assert!(socket.retransmit_at.is_some());
+
+        // The timeout doubles with each attempt
+        assert_eq!(socket.congestion_timeout, timeout * 2);
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        assert_eq!(next_sent(&peer), (PacketType::Data, seq_nr));
+        assert_eq!(socket.retransmissions, 2);
+        assert_eq!(socket.congestion_timeout, timeout * 4);
+    }
+
+    #[test]
+    fn test_retransmit_timeout_backoff_is_capped() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        iotry!(socket.try_send(&[&[1, 2, 3][..]]));
+        next_sent(&peer);
+
+        socket.congestion_timeout = MAX_CONGESTION_TIMEOUT;
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        next_sent(&peer);
+        assert_eq!(socket.congestion_timeout, MAX_CONGESTION_TIMEOUT);
+    }
+
+    #[test]
+    fn test_retransmit_timeout_gives_up() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        iotry!(socket.try_send(&[&[1, 2, 3][..]]));
+        next_sent(&peer);
+
+        socket.retransmissions = MAX_RETRANSMISSIONS;
+        socket.retransmit_at = Some(socket.socket.now());
+        match socket.check_timers() {
+            Err(Error::TimedOut(Timeout::Retransmit)) => (),
+            other => panic!("Expected a retransmission timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retransmit_timeout_resends_fin() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        socket.state = SocketState::FinSent;
+        socket.restart_retransmit_timer();
+
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        assert_eq!(next_sent(&peer).0, PacketType::Fin);
+        assert_eq!(socket.retransmissions, 1);
+
+        // An unacknowledged FIN closes the connection instead of failing it
+        socket.retransmissions = MAX_RETRANSMISSIONS;
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        assert_eq!(socket.state, SocketState::Closed);
+    }
+
+    #[test]
+    fn test_acknowledgement_resets_retransmissions() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        iotry!(socket.try_send(&[&[1, 2, 3][..]]));
+        let (_, seq_nr) = next_sent(&peer);
+        socket.retransmit_at = Some(socket.socket.now());
+        iotry!(socket.check_timers());
+        next_sent(&peer);
+        assert_eq!(socket.retransmissions, 1);
+
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_connection_id(socket.receiver_connection_id);
+        ack.set_ack_nr(seq_nr);
+        ack.set_wnd_size(BUF_SIZE as u32 * 16);
+        let bytes = ack.to_bytes();
+        let src = socket.connected_to;
+        iotry!(socket.handle_packet(&PacketRef::from_bytes(&bytes).unwrap(), src));
+        assert_eq!(socket.retransmissions, 0);
+        assert!(socket.retransmit_at.is_none());
+    }
+
     #[test]
     fn test_tolerance_to_small_buffers() {
         let server_addr = next_test_ip4();
@@ -1933,55 +4069,259 @@ mod test {
             }
         }
 
-        assert_eq!(read.len(), data.len());
-        assert_eq!(read, data);
+        assert_eq!(read.len(), data.len());
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_sequence_number_rollover() {
+        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        const LEN: usize = BUF_SIZE * 4;
+        let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
+        let to_send = data.clone();
+
+        thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::bind(client_addr));
+
+            // Advance socket's sequence number
+            client.seq_nr = ::std::u16::MAX - (to_send.len() / (BUF_SIZE * 2)) as u16;
+
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            // Send enough data to rollover
+            iotry!(client.send_to(&to_send[..]));
+            // Check that the sequence number did rollover
+            assert!(client.seq_nr < 50);
+            // Close connection
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received: Vec<u8> = vec!();
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{}", e)
+            }
+        }
+        assert_eq!(received.len(), data.len());
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_drop_unused_socket() {
+        let server_addr = next_test_ip4();
+        let server = iotry!(UtpSocket::bind(server_addr));
+
+        // Explicitly dropping socket. This test should not hang.
+        drop(server);
+    }
+
+    #[test]
+    fn test_abort() {
+        let server_addr = next_test_ip4();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&[1, 2, 3]));
+            iotry!(client.flush());
+            iotry!(client.abort());
+            assert_eq!(client.state, SocketState::Closed);
+            assert!(client.send_to(&[4]).is_err());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received = vec!();
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => panic!("Should have received Reset"),
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("{:?}", e)
+            }
+        }
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_drop_with_unresponsive_peer() {
+        use std::net::UdpSocket;
+        use std::time::{Duration, Instant};
+
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+        iotry!(server.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            client.set_linger(Some(Duration::from_millis(100)));
+            iotry!(client.send_to(&[1, 2, 3]));
+
+            // The peer never acknowledges anything, but dropping must not block
+            let start = Instant::now();
+            drop(client);
+            assert!(start.elapsed() < Duration::from_millis(100));
+        });
+
+        // Accept the connection, then go silent
+        let mut buf = [0; BUF_SIZE];
+        let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+        let syn = Packet::from_bytes(&buf[..len]).ok().unwrap();
+        assert_eq!(syn.get_type(), PacketType::Syn);
+        let mut reply = Packet::new();
+        reply.set_type(PacketType::State);
+        reply.set_connection_id(syn.connection_id());
+        reply.set_seq_nr(rand::random());
+        reply.set_ack_nr(syn.seq_nr());
+        iotry!(server.send_to(&reply.to_bytes()[..], client_addr));
+
+        // The connection is aborted once the linger timeout expires
+        loop {
+            let (len, _src) = iotry!(server.recv_from(&mut buf));
+            let packet = Packet::from_bytes(&buf[..len]).ok().unwrap();
+            if packet.get_type() == PacketType::Reset {
+                break;
+            }
+        }
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_simultaneous_open() {
+        let (addr_a, addr_b) = (next_test_ip4(), next_test_ip4());
+
+        let child = thread::spawn(move || {
+            let mut socket = iotry!(UtpSocket::connect_simultaneous(addr_a, addr_b));
+            iotry!(socket.send_to(&[1, 2, 3]));
+            iotry!(socket.close());
+        });
+
+        // Give the other peer's first SYN time to get lost
+        thread::sleep(::std::time::Duration::from_millis(50));
+        let mut socket = iotry!(UtpSocket::connect_simultaneous(addr_b, addr_a));
+        assert_eq!(socket.state, SocketState::Connected);
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received = vec!();
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{:?}", e)
+            }
+        }
+        assert_eq!(received, vec![1, 2, 3]);
+        iotry!(socket.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_crossing_syns() {
+        let (addr_a, addr_b) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
+        fn rendezvous(local: SocketAddr, remote: SocketAddr, id: u16) -> UtpSocket {
+            let mut socket = iotry!(UtpSocket::bind(local));
+            socket.receiver_connection_id = id;
+            socket.sender_connection_id = id + 1;
+            socket.connected_to = remote;
+            socket.simultaneous_open = true;
+            socket.state = SocketState::SynSent;
+            socket
+        }
+        let mut a = rendezvous(addr_a, addr_b, 100);
+        let mut b = rendezvous(addr_b, addr_a, 200);
+
+        let mut syn_a = Packet::new();
+        syn_a.set_type(PacketType::Syn);
+        syn_a.set_connection_id(a.receiver_connection_id);
+        syn_a.set_seq_nr(a.seq_nr);
+        let mut syn_b = Packet::new();
+        syn_b.set_type(PacketType::Syn);
+        syn_b.set_connection_id(b.receiver_connection_id);
+        syn_b.set_seq_nr(b.seq_nr);
+
+        // The SYN with the lowest connection id loses, and its sender keeps waiting for a reply
+        assert!(iotry!(handle_packet(&mut b, &syn_a, addr_a)).is_none());
+        assert_eq!(b.state, SocketState::SynSent);
+
+        // The other peer accepts the winning SYN
+        let reply = iotry!(handle_packet(&mut a, &syn_b, addr_b)).unwrap();
+        assert_eq!(reply.get_type(), PacketType::State);
+        assert_eq!(a.state, SocketState::Connected);
+        assert_eq!(a.sender_connection_id, 200);
+
+        assert!(iotry!(handle_packet(&mut b, &reply, addr_a)).is_none());
+        assert_eq!(b.state, SocketState::Connected);
+
+        // The losing SYN may still arrive late
+        assert!(iotry!(handle_packet(&mut b, &syn_a, addr_a)).is_none());
+        assert_eq!(b.state, SocketState::Connected);
+
+        // Mark sockets as closed
+        a.state = SocketState::Closed;
+        b.state = SocketState::Closed;
     }
 
     #[test]
-    fn test_sequence_number_rollover() {
-        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+    fn test_socket_over_memory_transport() {
+        use transport::MemoryTransport;
 
-        let mut server = iotry!(UtpSocket::bind(server_addr));
+        let (server_addr, client_addr) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                          next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
+        let (server_end, client_end) = MemoryTransport::pair(server_addr, client_addr);
+        let mut server = iotry!(UtpSocket::from_transport(server_end));
+        assert_eq!(iotry!(server.local_addr()), server_addr);
 
-        const LEN: usize = BUF_SIZE * 4;
+        const LEN: usize = BUF_SIZE * 10;
         let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
         let to_send = data.clone();
 
-        thread::spawn(move || {
-            let mut client = iotry!(UtpSocket::bind(client_addr));
-
-            // Advance socket's sequence number
-            client.seq_nr = ::std::u16::MAX - (to_send.len() / (BUF_SIZE * 2)) as u16;
-
-            let mut client = iotry!(UtpSocket::connect(server_addr));
-            // Send enough data to rollover
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect_with_transport(client_end, server_addr));
             iotry!(client.send_to(&to_send[..]));
-            // Check that the sequence number did rollover
-            assert!(client.seq_nr < 50);
-            // Close connection
             iotry!(client.close());
         });
 
         let mut buf = [0; BUF_SIZE];
-        let mut received: Vec<u8> = vec!();
+        let mut received = vec!();
         loop {
             match server.recv_from(&mut buf) {
                 Ok((0, _src)) => break,
-                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
-                Err(e) => panic!("{}", e)
+                Ok((len, src)) => {
+                    assert_eq!(src, client_addr);
+                    received.extend(buf[..len].to_vec());
+                },
+                Err(e) => panic!("{:?}", e)
             }
         }
-        assert_eq!(received.len(), data.len());
         assert_eq!(received, data);
+        iotry!(server.close());
+        child.join().unwrap();
     }
 
     #[test]
-    fn test_drop_unused_socket() {
-        let server_addr = next_test_ip4();
-        let server = iotry!(UtpSocket::bind(server_addr));
+    fn test_handle_waiting_packet_after_it_was_taken() {
+        use std::time::Instant;
+        use transport::{MemoryTransport, Transport};
 
-        // Explicitly dropping socket. This test should not hang.
-        drop(server);
+        let (server_addr, client_addr) = (next_test_ip4().to_socket_addrs().unwrap().next().unwrap(),
+                                          next_test_ip4().to_socket_addrs().unwrap().next().unwrap());
+        let (server_end, client_end) = MemoryTransport::pair(server_addr, client_addr);
+        let mut server = iotry!(UtpSocket::from_transport(server_end));
+        iotry!(server.set_poll_interval(Some(Duration::from_secs(10))));
+
+        // The datagram was taken by someone else, so there's nothing left to wait for
+        iotry!(client_end.send_to(&[0; 4], server_addr));
+        let mut buf = [0; 4];
+        iotry!(server.socket.recv_from(&mut buf));
+        let start = Instant::now();
+        iotry!(server.handle_waiting_packet());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(server.poll_interval, Some(Duration::from_secs(10)));
     }
 
     #[test]
@@ -1999,8 +4339,8 @@ mod test {
         });
 
         match UtpSocket::connect(server_addr) {
-            Err(ref e) if e.kind() == ErrorKind::Other => (), // OK
-            Err(e) => panic!("Expected ErrorKind::Other, got {:?}", e),
+            Err(Error::InvalidPacket(_)) => (), // OK
+            Err(e) => panic!("Expected Error::InvalidPacket, got {:?}", e),
             Ok(_) => panic!("Expected Err, got Ok")
         }
     }
@@ -2025,8 +4365,8 @@ mod test {
         });
 
         match UtpSocket::connect(server_addr) {
-            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => (), // OK
-            Err(e) => panic!("Expected ErrorKind::ConnectionRefused, got {:?}", e),
+            Err(Error::ProtocolViolation(PacketType::Data)) => (), // OK
+            Err(e) => panic!("Expected Error::ProtocolViolation, got {:?}", e),
             Ok(_) => panic!("Expected Err, got Ok")
         }
     }
@@ -2034,7 +4374,7 @@ mod test {
     #[test]
     fn test_receiving_syn_on_established_connection() {
         // Establish connection
-        let server_addr = next_test_ip4();
+        let server_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
         let mut server = iotry!(UtpSocket::bind(server_addr));
 
         thread::spawn(move || {
@@ -2069,7 +4409,7 @@ mod test {
     #[test]
     fn test_receiving_reset_on_established_connection() {
         // Establish connection
-        let server_addr = next_test_ip4();
+        let server_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
         let mut server = iotry!(UtpSocket::bind(server_addr));
 
         thread::spawn(move || {
@@ -2102,7 +4442,7 @@ mod test {
 
     #[test]
     fn test_premature_fin() {
-        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+        let server_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
         let mut server = iotry!(UtpSocket::bind(server_addr));
 
         const LEN: usize = BUF_SIZE * 4;
@@ -2127,7 +4467,7 @@ mod test {
         packet.set_ack_nr(server.ack_nr);
         packet.set_timestamp_microseconds(now_microseconds());
         packet.set_type(PacketType::Fin);
-        iotry!(server.socket.send_to(&packet.to_bytes()[..], client_addr));
+        iotry!(server.socket.send_to(&packet.to_bytes()[..], server.connected_to));
 
         // Receive until end
         let mut received: Vec<u8> = vec!();
@@ -2181,4 +4521,598 @@ mod test {
         assert!(listener.local_addr().is_ok());
         assert_eq!(listener.local_addr().unwrap(), addr);
     }
+
+    #[test]
+    fn test_dual_stack_listener() {
+        let listener = iotry!(UtpListener::bind_dual_stack(0));
+        let port = iotry!(listener.local_addr()).port();
+
+        for &server_addr in ["127.0.0.1", "::1"].iter() {
+            let child = thread::spawn(move || {
+                let mut client = iotry!(UtpSocket::connect((server_addr, port)));
+                let client_addr = iotry!(client.local_addr());
+                iotry!(client.close());
+                client_addr
+            });
+
+            let (mut server, src) = iotry!(listener.accept());
+            let mut buf = [0; BUF_SIZE];
+            assert_eq!(iotry!(server.recv_from(&mut buf)).0, 0);
+            let client_addr = child.join().unwrap();
+            // IPv4 peers aren't seen as IPv4-mapped IPv6 addresses
+            assert_eq!(src.port(), client_addr.port());
+            assert_eq!(src.is_ipv4(), server_addr == "127.0.0.1");
+            assert_eq!(iotry!(server.local_addr()).is_ipv4(), src.is_ipv4());
+        }
+    }
+
+    #[test]
+    fn test_connect_from() {
+        let mut server = iotry!(UtpSocket::bind(next_test_ip4()));
+        let server_addr = iotry!(server.local_addr());
+        let local_addr = iotry!(next_test_ip4().to_socket_addrs()).next().unwrap();
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect_from(local_addr, server_addr));
+            assert_eq!(iotry!(client.local_addr()), local_addr);
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let (read, src) = iotry!(server.recv_from(&mut buf));
+        assert_eq!((read, src), (0, local_addr));
+        child.join().unwrap();
+
+        // Addresses of the other family can't be reached from the local address
+        match UtpSocket::connect_from("0.0.0.0:0", "[::1]:6881") {
+            Err(Error::AddressResolution(_)) => (),
+            x => panic!("Expected Error::AddressResolution, got {:?}", x.err()),
+        }
+    }
+
+    #[test]
+    fn test_listener_shared_with_other_protocols() {
+        use std::net::UdpSocket;
+        use std::sync::mpsc::channel;
+
+        let listener_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
+        let mut listener = iotry!(UtpListener::bind(listener_addr));
+        let (tx, rx) = channel();
+        listener.set_datagram_handler(move |datagram, src| {
+            tx.send((datagram.to_vec(), src)).unwrap();
+        });
+
+        // A DHT ping, then a uTP connection on the same port
+        let dht_addr = next_test_ip4();
+        let dht = iotry!(UdpSocket::bind(dht_addr));
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        iotry!(dht.send_to(&ping[..], listener_addr));
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(listener_addr));
+            iotry!(client.close());
+        });
+
+        let (mut server, _src) = iotry!(listener.accept());
+        let (datagram, src) = rx.recv().unwrap();
+        assert_eq!(&datagram[..], &ping[..]);
+        assert_eq!(src, dht_addr.to_socket_addrs().unwrap().next().unwrap());
+
+        // Replies go out from the shared port
+        iotry!(listener.send_to(b"pong", src));
+        let mut buf = [0; BUF_SIZE];
+        let (len, from) = iotry!(dht.recv_from(&mut buf));
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, listener_addr);
+
+        iotry!(server.recv_from(&mut buf));
+        iotry!(server.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_extension_negotiation() {
+        use extension::ExtensionBits;
+        use packet::ExtensionType;
+
+        let listener_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
+        let mut listener = iotry!(UtpListener::bind(listener_addr));
+        listener.register_extension(ExtensionBits::new([0x0f, 0, 0, 0, 0, 0, 0, 1]));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::bind(next_test_ip4()));
+            client.register_extension(ExtensionBits::new([0xff, 0, 0, 0, 0, 0, 0, 3]));
+            let mut client = iotry!(client.connect_to(listener_addr));
+            let negotiated = client.negotiated_extensions().to_vec();
+            iotry!(client.close());
+            negotiated
+        });
+
+        let (mut server, _src) = iotry!(listener.accept());
+        let mut buf = [0; BUF_SIZE];
+        iotry!(server.recv_from(&mut buf));
+        iotry!(server.close());
+
+        // Both ends agree on the features both of them support
+        let negotiated = child.join().unwrap();
+        assert_eq!(negotiated, server.negotiated_extensions());
+        assert_eq!(negotiated.len(), 1);
+        assert_eq!(negotiated[0].get_type(), ExtensionType::ExtensionBits);
+        assert_eq!(negotiated[0].data(), &[0x0f, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_unknown_extensions_are_not_negotiated() {
+        use extension::ExtensionBits;
+        use packet::{Extension, ExtensionType};
+
+        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+        let client_addr = client_addr.to_socket_addrs().unwrap().next().unwrap();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+        server.register_extension(ExtensionBits::new([0xff; 8]));
+
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(10);
+        syn.extensions.push(iotry!(Extension::new(ExtensionType::Other(0x7f), vec![1, 2, 3, 4])));
+        syn.extensions.push(iotry!(Extension::new(ExtensionType::ExtensionBits, vec![0x11; 8])));
+
+        let reply = iotry!(handle_packet(&mut server, &syn, client_addr)).unwrap();
+        assert_eq!(reply.extensions.len(), 1);
+        assert_eq!(reply.extension_bits(), Some(&[0x11; 8][..]));
+        assert_eq!(server.negotiated_extensions(), &reply.extensions[..]);
+
+        // A retransmitted SYN gets the same answer
+        let reply = iotry!(handle_packet(&mut server, &syn, client_addr)).unwrap();
+        assert_eq!(reply.extension_bits(), Some(&[0x11; 8][..]));
+
+        // Mark socket as closed
+        server.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_address_resolution_failure() {
+        match UtpSocket::bind("not an address") {
+            Err(Error::AddressResolution(_)) => (),
+            x => panic!("Expected Error::AddressResolution, got {:?}", x.err()),
+        }
+        match UtpSocket::connect(&[][..] as &[SocketAddr]) {
+            Err(Error::AddressResolution(_)) => (),
+            x => panic!("Expected Error::AddressResolution, got {:?}", x.err()),
+        }
+        match UtpListener::bind("256.0.0.1:0") {
+            Err(Error::AddressResolution(_)) => (),
+            x => panic!("Expected Error::AddressResolution, got {:?}", x.err()),
+        }
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        use std::net::UdpSocket;
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+
+        thread::spawn(move || {
+            let mut buf = [0; BUF_SIZE];
+            let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+            let syn = iotry!(PacketRef::from_bytes(&buf[..len]));
+            let mut packet = Packet::new();
+            packet.set_type(PacketType::Reset);
+            packet.set_connection_id(syn.connection_id());
+            iotry!(server.send_to(&packet.to_bytes()[..], client_addr));
+        });
+
+        match UtpSocket::connect(server_addr) {
+            Err(Error::ConnectionRefused) => (),
+            x => panic!("Expected Error::ConnectionRefused, got {:?}", x.err()),
+        }
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        use sim::SimNetwork;
+
+        // Nothing is bound to the remote peer's address, so SYNs go unanswered
+        let network = SimNetwork::new(1);
+        let server_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let client = iotry!(network.bind("10.0.0.2:6881".parse().unwrap()));
+
+        match UtpSocket::connect_with_transport(client, server_addr) {
+            Err(Error::TimedOut(Timeout::Connect)) => (),
+            x => panic!("Expected Error::TimedOut(Timeout::Connect), got {:?}", x.err()),
+        }
+        // The SYN was sent five times, waiting twice as long after each one
+        let elapsed = network.elapsed();
+        assert!(elapsed >= Duration::from_secs(31) && elapsed < Duration::from_secs(32),
+                "Gave up after {:?}", elapsed);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_connect_to_closed_port() {
+        use std::net::UdpSocket;
+        use std::time::Instant;
+
+        // Nothing listens on the port any more, so the SYN is answered with ICMP port unreachable
+        let server_addr = iotry!(iotry!(UdpSocket::bind(next_test_ip4())).local_addr());
+
+        let start = Instant::now();
+        match UtpSocket::connect(server_addr) {
+            Err(Error::Unreachable(ref e)) if e.kind() == ErrorKind::ConnectionRefused => (),
+            x => panic!("Expected Error::Unreachable, got {:?}", x.err()),
+        }
+        assert!(start.elapsed() < Duration::from_millis(SYN_TIMEOUT));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_peer_going_away() {
+        use std::net::UdpSocket;
+
+        // The remote peer accepts the connection, then closes its UDP socket without a word
+        let server = iotry!(UdpSocket::bind(next_test_ip4()));
+        let server_addr = iotry!(server.local_addr());
+        let child = thread::spawn(move || {
+            let mut buf = [0; BUF_SIZE];
+            let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+            let syn = iotry!(PacketRef::from_bytes(&buf[..len]));
+            let mut packet = Packet::new();
+            packet.set_type(PacketType::State);
+            packet.set_connection_id(syn.connection_id());
+            packet.set_seq_nr(rand::random());
+            packet.set_ack_nr(syn.seq_nr());
+            iotry!(server.send_to(&packet.to_bytes()[..], client_addr));
+        });
+
+        let mut client = iotry!(UtpSocket::connect(server_addr));
+        child.join().unwrap();
+
+        iotry!(client.send_to(b"hello"));
+        match client.flush() {
+            Err(Error::Unreachable(ref e)) if e.kind() == ErrorKind::ConnectionRefused => (),
+            x => panic!("Expected Error::Unreachable, got {:?}", x.err()),
+        }
+        assert_eq!(client.state, SocketState::Closed);
+        match client.send_to(b"world") {
+            Err(Error::NotConnected) => (),
+            x => panic!("Expected Error::NotConnected, got {:?}", x),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_errors_about_others_leave_connection_alone() {
+        use std::net::UdpSocket;
+
+        let mut server = iotry!(UtpSocket::bind(next_test_ip4()));
+        let server_addr = iotry!(server.local_addr());
+        let child = thread::spawn(move || {
+            let mut buf = [0; BUF_SIZE];
+            let mut received = Vec::new();
+            loop {
+                match iotry!(server.recv_from(&mut buf)) {
+                    (0, _src) => break,
+                    (read, _src) => received.extend_from_slice(&buf[..read]),
+                }
+            }
+            assert_eq!(received, b"hello world");
+            iotry!(server.close());
+        });
+
+        let mut client = iotry!(UtpSocket::connect(server_addr));
+        let client_addr = iotry!(client.local_addr());
+
+        // A stray packet from an address that goes away right after must not be answered, as the
+        // network would then report that address as unreachable
+        let stray = iotry!(UdpSocket::bind(next_test_ip4()));
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::Data);
+        packet.set_connection_id(client.receiver_connection_id.wrapping_add(7));
+        iotry!(stray.send_to(&packet.to_bytes()[..], client_addr));
+        drop(stray);
+        iotry!(client.send_to(b"hello"));
+        iotry!(client.flush());
+
+        // Nor may a datagram that the socket sent elsewhere break the connection
+        let dead_addr = iotry!(iotry!(UdpSocket::bind(next_test_ip4())).local_addr());
+        iotry!(client.socket.send_to(b"stray", dead_addr));
+        thread::sleep(Duration::from_millis(50));
+        iotry!(client.send_to(b" world"));
+        iotry!(client.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_tries_every_address() {
+        use std::net::UdpSocket;
+
+        // The first address never answers, so the connection is made to the second one
+        let silent = iotry!(UdpSocket::bind(next_test_ip4()));
+        let silent_addr = iotry!(silent.local_addr());
+        let mut server = iotry!(UtpSocket::bind(next_test_ip4()));
+        let server_addr = iotry!(server.local_addr());
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(&[silent_addr, server_addr][..]));
+            assert_eq!(client.connected_to, server_addr);
+            iotry!(client.send_to(b"hello"));
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let (read, _src) = iotry!(server.recv_from(&mut buf));
+        assert_eq!(&buf[..read], b"hello");
+        assert_eq!(iotry!(server.recv_from(&mut buf)).0, 0);
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_small_writes_are_coalesced() {
+        let server_addr = next_test_ip4();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+
+            // The first write goes out right away, and the next ones wait for it to be acknowledged
+            iotry!(client.send_to(&[0, 0, 0, 1]));
+            assert_eq!(client.send_window.len(), 1);
+            for idx in 2..6 {
+                iotry!(client.send_to(&[0, 0, 0, idx]));
+            }
+            assert_eq!(client.send_window.len(), 1);
+            assert_eq!(client.unsent_queue.len(), 1);
+            assert_eq!(client.unsent_queue[0].payload.len(), 16);
+
+            // Without coalescing, every write is a packet of its own
+            iotry!(client.set_nodelay(true));
+            assert!(client.unsent_queue.is_empty());
+            iotry!(client.send_to(&[0, 0, 0, 6]));
+            iotry!(client.send_to(&[0, 0, 0, 7]));
+            assert!(client.unsent_queue.is_empty());
+            assert_eq!(client.send_window.len(), 4);
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received = vec!();
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{:?}", e)
+            }
+        }
+        let expected: Vec<u8> = (1..8).flat_map(|idx| vec![0, 0, 0, idx]).collect();
+        assert_eq!(received, expected);
+        iotry!(server.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_held_data_is_sent_once_acknowledged() {
+        let server_addr = next_test_ip4();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(b"request "));
+            iotry!(client.send_to(b"in two parts"));
+            assert_eq!(client.unsent_queue.len(), 1);
+
+            // Waiting for the reply processes the acknowledgement of the first part, which sends
+            // the second one
+            let mut buf = [0; BUF_SIZE];
+            let (len, _src) = iotry!(client.recv_from(&mut buf));
+            assert_eq!(&buf[..len], b"reply");
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received = vec!();
+        while received.len() < 20 {
+            let (len, _src) = iotry!(server.recv_from(&mut buf));
+            received.extend(buf[..len].to_vec());
+        }
+        assert_eq!(&received[..], b"request in two parts");
+        iotry!(server.send_to(b"reply"));
+        iotry!(server.close());
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_writes_do_not_wait_for_acknowledgements() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+        use super::MAX_PAYLOAD;
+
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+        iotry!(server.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            client.set_linger(Some(Duration::from_secs(0)));
+            client.set_send_buffer_size(4 * MAX_PAYLOAD);
+
+            // The peer never acknowledges anything, so only the first packets are sent, and the
+            // write stops once the send buffer is full
+            let data = vec![0; 10 * MAX_PAYLOAD];
+            assert_eq!(iotry!(client.send_to(&data[..])), 4 * MAX_PAYLOAD);
+            assert_eq!(client.send_window.len(), 2);
+            assert_eq!(client.unsent_queue.len(), 2);
+        });
+
+        // Accept the connection, then go silent
+        let mut buf = [0; BUF_SIZE];
+        let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+        let syn = Packet::from_bytes(&buf[..len]).ok().unwrap();
+        let mut reply = Packet::new();
+        reply.set_type(PacketType::State);
+        reply.set_connection_id(syn.connection_id());
+        reply.set_seq_nr(rand::random());
+        reply.set_ack_nr(syn.seq_nr());
+        reply.set_wnd_size(BUF_SIZE as u32);
+        iotry!(server.send_to(&reply.to_bytes()[..], client_addr));
+
+        let mut data_packets = 0;
+        loop {
+            let (len, _src) = iotry!(server.recv_from(&mut buf));
+            match Packet::from_bytes(&buf[..len]).ok().unwrap().get_type() {
+                PacketType::Data => data_packets += 1,
+                PacketType::Reset => break,
+                _ => (),
+            }
+        }
+        assert_eq!(data_packets, 2);
+        child.join().unwrap();
+    }
+
+    /// Sends `len` bytes over a simulated network, with rate limits set up by `limit` on the
+    /// receiving and sending sockets, returning how long it took.
+    fn rate_limited_transfer<F>(len: usize, limit: F) -> Duration
+        where F: Fn(&mut UtpSocket, bool) + Send + Sync + 'static {
+        use std::sync::Arc;
+        use sim::{SimNetwork, Impairments};
+
+        let network = SimNetwork::new(1);
+        network.set_default_impairments(Impairments {
+            delay: Duration::from_millis(10),
+            ..Impairments::default()
+        });
+        let server_addr = "10.0.0.1:6881".parse().unwrap();
+        let client_addr = "10.0.0.2:6881".parse().unwrap();
+        let mut server = iotry!(UtpSocket::from_transport(iotry!(network.bind(server_addr))));
+        let client = iotry!(network.bind(client_addr));
+        let limit = Arc::new(limit);
+        limit(&mut server, false);
+
+        let client_limit = limit.clone();
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect_with_transport(client, server_addr));
+            client_limit(&mut client, true);
+            iotry!(client.send_to(&vec![0; len][..]));
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received = 0;
+        loop {
+            match iotry!(server.recv_from(&mut buf)) {
+                (0, _src) => break,
+                (read, _src) => received += read,
+            }
+        }
+        let elapsed = network.elapsed();
+        iotry!(server.close());
+        drop(server);
+        child.join().unwrap();
+
+        assert_eq!(received, len);
+        elapsed
+    }
+
+    #[test]
+    fn test_upload_limit() {
+        // 100 kB at 50 kB/s, less the initial burst
+        let elapsed = rate_limited_transfer(100_000, |socket, sender| {
+            if sender {
+                socket.set_upload_limit(Some(50_000));
+            }
+        });
+        assert!(elapsed >= Duration::from_millis(1900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_download_limit() {
+        // The receive window is closed while the limit runs out, and updates reopen it
+        let elapsed = rate_limited_transfer(100_000, |socket, sender| {
+            if !sender {
+                socket.set_download_limit(Some(50_000));
+            }
+        });
+        assert!(elapsed >= Duration::from_millis(1900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_zero_window_probe() {
+        use std::net::UdpSocket;
+        use std::time::{Duration, Instant};
+
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+        iotry!(server.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            client.set_linger(Some(Duration::from_secs(0)));
+            iotry!(client.send_to(&[1; 100]));
+            assert_eq!(client.send_window.len(), 0);
+            iotry!(client.flush());
+        });
+
+        // Accept the connection with a closed window, which is only probed after a while
+        let mut buf = [0; BUF_SIZE];
+        let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+        let syn = Packet::from_bytes(&buf[..len]).ok().unwrap();
+        let seq_nr = rand::random();
+        let mut reply = Packet::new();
+        reply.set_type(PacketType::State);
+        reply.set_connection_id(syn.connection_id());
+        reply.set_seq_nr(seq_nr);
+        reply.set_ack_nr(syn.seq_nr());
+        let start = Instant::now();
+        iotry!(server.send_to(&reply.to_bytes()[..], client_addr));
+
+        let (len, _src) = iotry!(server.recv_from(&mut buf));
+        let probe = Packet::from_bytes(&buf[..len]).ok().unwrap();
+        assert_eq!(probe.get_type(), PacketType::Data);
+        assert!(start.elapsed() >= Duration::from_millis(super::ZERO_WINDOW_PROBE));
+
+        reply.set_ack_nr(probe.seq_nr());
+        reply.set_wnd_size(BUF_SIZE as u32);
+        iotry!(server.send_to(&reply.to_bytes()[..], client_addr));
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_listener_rate_limiter() {
+        use std::time::Instant;
+        use rate::RateLimiter;
+
+        let listener_addr = next_test_ip4().to_socket_addrs().unwrap().next().unwrap();
+        let mut listener = iotry!(UtpListener::bind(listener_addr));
+        let limiter = RateLimiter::new();
+        limiter.set_download_limit(Some(100_000));
+        listener.set_rate_limiter(Some(limiter.clone()));
+
+        // Both connections download 50 kB, sharing 100 kB/s
+        let start = Instant::now();
+        let clients: Vec<_> = (0..2).map(|_| thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(listener_addr));
+            iotry!(client.send_to(&[0; 50_000]));
+            iotry!(client.close());
+        })).collect();
+
+        let servers: Vec<_> = (0..2).map(|_| {
+            let (mut server, _src) = iotry!(listener.accept());
+            assert!(server.rate_limiter().is_some());
+            thread::spawn(move || {
+                let mut buf = [0; BUF_SIZE];
+                let mut received = 0;
+                loop {
+                    match iotry!(server.recv_from(&mut buf)) {
+                        (0, _src) => break,
+                        (read, _src) => received += read,
+                    }
+                }
+                assert_eq!(received, 50_000);
+            })
+        }).collect();
+        for child in servers.into_iter().chain(clients) {
+            child.join().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(800), "{:?}", start.elapsed());
+    }
 }
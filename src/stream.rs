@@ -1,13 +1,21 @@
-use std::io::{Read, Write, Result};
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::io::{self, BufRead, Read, Write, ErrorKind, IoSlice, IoSliceMut};
+use std::net::{ToSocketAddrs, SocketAddr, Shutdown};
 use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use error::{Error, Result};
+use rate::RateLimiter;
 use socket::UtpSocket;
+use transport::Transport;
+
+/// How long a split stream waits for a packet before letting the other half use the socket.
+const SPLIT_POLL_INTERVAL: u64 = 100; // milliseconds
 
 /// A structure that represents a uTP (Micro Transport Protocol) stream between a local socket and a
 /// remote socket.
 ///
 /// The connection will be closed when the value is dropped (either explicitly or when it goes out of
-/// scope).
+/// scope), in the background. See `set_linger`.
 ///
 /// # Examples
 ///
@@ -31,7 +39,7 @@ impl UtpStream {
     ///
     /// If more than one valid address is specified, only the first will be used.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpStream> {
-        UtpSocket::bind(addr).and_then(|s| Ok(UtpStream { socket: s }))
+        UtpSocket::bind(addr).map(|s| UtpStream { socket: s })
     }
 
     /// Opens a uTP connection to a remote host by hostname or IP address.
@@ -39,11 +47,27 @@ impl UtpStream {
     /// The address type can be any implementor of the `ToSocketAddr` trait. See its documentation
     /// for concrete examples.
     ///
-    /// If more than one valid address is specified, only the first will be used.
+    /// Every address the host resolves to is tried, as described in `UtpSocket::connect`.
     pub fn connect<A: ToSocketAddrs>(dst: A) -> Result<UtpStream> {
         // Port 0 means the operating system gets to choose it
         UtpSocket::connect(dst)
-            .and_then(|s| Ok(UtpStream { socket: s }))
+            .map(|s| UtpStream { socket: s })
+    }
+
+    /// Opens a uTP connection to a remote host from the given local address. See
+    /// `UtpSocket::connect_from`.
+    pub fn connect_from<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, remote: B)
+                                                            -> Result<UtpStream> {
+        UtpSocket::connect_from(local, remote)
+            .map(|s| UtpStream { socket: s })
+    }
+
+    /// Opens a uTP connection to a remote peer that is connecting back at the same time, as
+    /// needed for NAT traversal. See `UtpSocket::connect_simultaneous`.
+    pub fn connect_simultaneous<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, remote: B)
+                                                                    -> Result<UtpStream> {
+        UtpSocket::connect_simultaneous(local, remote)
+            .map(|s| UtpStream { socket: s })
     }
 
     /// Gracefully closes connection to peer.
@@ -54,25 +78,297 @@ impl UtpStream {
         self.socket.close()
     }
 
+    /// Closes the connection immediately, discarding any pending data. See `UtpSocket::abort`.
+    pub fn abort(&mut self) -> Result<()> {
+        self.socket.abort()
+    }
+
+    /// Sets how long the connection may linger after the stream is dropped without being closed.
+    /// See `UtpSocket::set_linger`.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.socket.set_linger(linger)
+    }
+
+    /// Sets the interval between keep-alive packets, or disables them if `None`. See
+    /// `UtpSocket::set_keepalive`.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.socket.set_keepalive(interval)
+    }
+
+    /// Sets how long the remote peer may stay silent before the connection fails, or disables
+    /// the timeout if `None`. See `UtpSocket::set_idle_timeout`.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.socket.set_idle_timeout(timeout)
+    }
+
+    /// Disables or enables the coalescing of small writes. See `UtpSocket::set_nodelay`.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.socket.set_nodelay(nodelay)
+    }
+
+    /// Sets how much written data is kept until acknowledged by the remote peer. See
+    /// `UtpSocket::set_send_buffer_size`.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.socket.set_send_buffer_size(size)
+    }
+
+    /// Sets the most data sent per second, or removes the limit if `None`. See
+    /// `UtpSocket::set_upload_limit`.
+    pub fn set_upload_limit(&mut self, limit: Option<u64>) {
+        self.socket.set_upload_limit(limit)
+    }
+
+    /// Sets the most data received per second, or removes the limit if `None`. See
+    /// `UtpSocket::set_download_limit`.
+    pub fn set_download_limit(&mut self, limit: Option<u64>) {
+        self.socket.set_download_limit(limit)
+    }
+
+    /// Makes the stream share the limits of `limiter` with every other socket using it. See
+    /// `UtpSocket::set_rate_limiter`.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.socket.set_rate_limiter(limiter)
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    ///
+    /// Shutting down the write half signals the end of our data to the remote peer, while still
+    /// allowing to read whatever it sends until it closes its own half. See `UtpSocket::shutdown`.
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    /// Sends a message, which the remote peer receives whole with `recv_message`. See
+    /// `UtpSocket::send_message`.
+    pub fn send_message(&mut self, message: &[u8]) -> Result<()> {
+        self.socket.send_message(message)
+    }
+
+    /// Receives a whole message, or `None` at the end of the stream. See
+    /// `UtpSocket::recv_message`.
+    pub fn recv_message(&mut self) -> Result<Option<Vec<u8>>> {
+        self.socket.recv_message()
+    }
+
+    /// Receives a whole message into `message`, reusing its allocation. See
+    /// `UtpSocket::recv_message_into`.
+    pub fn recv_message_into(&mut self, message: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.socket.recv_message_into(message)
+    }
+
+    /// Sets the largest message that can be sent or received. See
+    /// `UtpSocket::set_max_message_size`.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.socket.set_max_message_size(size)
+    }
+
     /// Returns the socket address of the local half of this uTP connection.
     pub fn local_addr(&self) -> Result<SocketAddr> {
         self.socket.local_addr()
     }
+
+    /// Splits this stream into a read half and a write half, which can be moved to different
+    /// threads.
+    ///
+    /// Incoming packets are processed by whichever half is currently using the connection, so a
+    /// thread blocked writing keeps receiving data and a thread blocked reading keeps handling
+    /// acknowledgements. The connection is closed when both halves are dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use utp::UtpStream;
+    /// use std::io::{Read, Write};
+    /// use std::thread;
+    ///
+    /// let stream = UtpStream::connect("127.0.0.1:1234").unwrap();
+    /// let (mut reader, mut writer) = stream.split().unwrap();
+    ///
+    /// let child = thread::spawn(move || {
+    ///     let mut buf = Vec::new();
+    ///     reader.read_to_end(&mut buf).unwrap();
+    ///     buf
+    /// });
+    /// writer.write_all(b"hello").unwrap();
+    /// drop(writer);
+    /// let reply = child.join().unwrap();
+    /// ```
+    pub fn split(mut self) -> Result<(ReadHalf, WriteHalf)> {
+        let transport = self.socket.transport();
+        try!(self.socket.set_poll_interval(Some(Duration::from_millis(SPLIT_POLL_INTERVAL))));
+
+        let socket = Arc::new(Mutex::new(self.socket));
+        let read_half = ReadHalf { socket: socket.clone(), transport: transport.clone() };
+        let write_half = WriteHalf { socket, transport };
+        Ok((read_half, write_half))
+    }
+}
+
+fn lock<'a>(socket: &'a Mutex<UtpSocket>) -> MutexGuard<'a, UtpSocket> {
+    // A panic in the other half leaves the socket in a consistent enough state to keep using it
+    socket.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Waits for the next packet without holding the lock, so as not to block the other half, and
+/// returns whether one arrived. The socket's timers are checked if none did.
+fn wait_for_packet(socket: &Mutex<UtpSocket>, transport: &dyn Transport) -> Result<bool> {
+    match transport.peek_from(&mut [0; 1]) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            try!(lock(socket).check_timers());
+            Ok(false)
+        },
+        Err(e) => {
+            try!(lock(socket).transport_error(e));
+            Ok(false)
+        },
+    }
+}
+
+/// The reading half of a `UtpStream`, created by `UtpStream::split`.
+pub struct ReadHalf {
+    socket: Arc<Mutex<UtpSocket>>,
+    // Handle to the underlying transport used to wait for packets without holding the lock
+    transport: Arc<dyn Transport>,
+}
+
+impl ReadHalf {
+    /// Shuts down the read side of the connection. See `UtpSocket::shutdown`.
+    pub fn shutdown(&mut self) -> Result<()> {
+        lock(&self.socket).shutdown(Shutdown::Read)
+    }
+
+    /// Returns the socket address of the local half of this uTP connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.transport.local_addr().map_err(Error::from)
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pending = false;
+        loop {
+            {
+                let mut socket = lock(&self.socket);
+                if let Some(read) = try!(socket.recv_buffered(buf)) {
+                    return Ok(read);
+                }
+
+                if pending || socket.has_pending_datagrams() {
+                    pending = false;
+                    try!(socket.handle_waiting_packet());
+                    continue;
+                }
+            }
+
+            pending = try!(wait_for_packet(&self.socket, &*self.transport));
+        }
+    }
+}
+
+/// The writing half of a `UtpStream`, created by `UtpStream::split`.
+pub struct WriteHalf {
+    socket: Arc<Mutex<UtpSocket>>,
+    // Handle to the underlying transport used to wait for packets without holding the lock
+    transport: Arc<dyn Transport>,
+}
+
+impl WriteHalf {
+    /// Shuts down the write side of the connection, signalling the end of our data to the remote
+    /// peer. See `UtpSocket::shutdown`.
+    pub fn shutdown(&mut self) -> Result<()> {
+        lock(&self.socket).shutdown(Shutdown::Write)
+    }
+
+    /// Returns the socket address of the local half of this uTP connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.transport.local_addr().map_err(Error::from)
+    }
+
+    /// Runs `attempt` until it has an outcome, handling incoming packets in between, and only
+    /// holding the lock while either runs.
+    fn retry<T, F>(&mut self, mut attempt: F) -> Result<T>
+        where F: FnMut(&mut UtpSocket) -> Result<Option<T>> {
+        let mut pending = false;
+        loop {
+            {
+                let mut socket = lock(&self.socket);
+                if let Some(outcome) = try!(attempt(&mut socket)) {
+                    return Ok(outcome);
+                }
+
+                if pending || socket.has_pending_datagrams() {
+                    pending = false;
+                    try!(socket.handle_waiting_packet());
+                    continue;
+                }
+            }
+
+            pending = try!(wait_for_packet(&self.socket, &*self.transport));
+        }
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|socket| socket.try_send(&[buf])).map_err(io::Error::from)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.retry(|socket| socket.try_send(bufs)).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|socket| socket.try_flush().map(|done| if done { Some(()) } else { None }))
+            .map_err(io::Error::from)
+    }
 }
 
 impl Read for UtpStream {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.socket.recv_from(buf).map(|(read, _src)| read)
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv_from(buf).map(|(read, _src)| read).map_err(io::Error::from)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.socket.recv_vectored(bufs).map_err(io::Error::from)
+    }
+}
+
+/// Reads straight from the received packets, without copying their data into an intermediate
+/// buffer.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::UtpStream;
+/// use std::io::BufRead;
+///
+/// let stream = UtpStream::connect("127.0.0.1:1234").unwrap();
+/// for line in stream.lines() {
+///     println!("{}", line.unwrap());
+/// }
+/// ```
+impl BufRead for UtpStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.socket.fill_buf().map_err(io::Error::from)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.socket.consume(amt)
     }
 }
 
 impl Write for UtpStream {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.socket.send_to(buf)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf).map_err(io::Error::from)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.socket.send_vectored(bufs).map_err(io::Error::from)
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.socket.flush()
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().map_err(io::Error::from)
     }
 }
 
@@ -0,0 +1,665 @@
+use std::array;
+use std::collections::VecDeque;
+use std::io::{Result, Error, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::time::{Duration, Instant};
+use util;
+
+/// A datagram carrier for uTP packets.
+///
+/// uTP is usually run over UDP, but any unreliable datagram service with addressable endpoints
+/// will do (e.g., in-memory channels in tests, or a socket behind a proxy). `UdpSocket` implements
+/// this trait, so an application may also configure a UDP socket itself before handing it over.
+///
+/// Methods take `&self`, as a transport is shared between a socket and its clones (see
+/// `UtpStream::split`).
+///
+/// Sockets are created on top of a transport with `UtpSocket::from_transport` or
+/// `UtpSocket::connect_with_transport`. `UtpListener` still requires UDP, as it binds a new
+/// socket for every connection it accepts.
+pub trait Transport: Send + Sync {
+    /// Sends a datagram to the given address, returning the number of bytes written.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize>;
+
+    /// Receives a datagram, returning the number of bytes read and the source address.
+    ///
+    /// Must fail with `ErrorKind::WouldBlock` or `ErrorKind::TimedOut` if no datagram arrives
+    /// before the read timeout expires.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+
+    /// Receives a datagram like `recv_from`, but without removing it from the input queue.
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+
+    /// Sends several datagrams to the same address, returning how many were sent.
+    ///
+    /// At least one datagram is sent unless `bufs` is empty or an error is returned. The default
+    /// implementation calls `send_to` for each datagram.
+    fn send_batch(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize> {
+        for (sent, buf) in bufs.iter().enumerate() {
+            if let Err(e) = self.send_to(buf, addr) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(bufs.len())
+    }
+
+    /// Receives up to one datagram per buffer in `bufs`, returning how many were received.
+    ///
+    /// Blocks like `recv_from` until the first datagram arrives, then only takes those already
+    /// waiting. The length and source address of each datagram are stored in the matching
+    /// position of `received`, which is at least as long as `bufs`. The default implementation
+    /// receives a single datagram with `recv_from`.
+    fn recv_batch(&self, bufs: &mut [&mut [u8]], received: &mut [(usize, SocketAddr)])
+                  -> Result<usize> {
+        match bufs.first_mut() {
+            Some(buf) => {
+                received[0] = try!(self.recv_from(buf));
+                Ok(1)
+            },
+            None => Ok(0),
+        }
+    }
+
+    /// Takes the errors reported by the network about sent datagrams (e.g., ICMP port
+    /// unreachable), returning the destinations they were sent to.
+    ///
+    /// Called after an error that says a remote peer can't be reached, which a socket only treats
+    /// as fatal if it's about its own remote peer. The default implementation returns an empty
+    /// list, as it can't tell, which makes every such error fatal.
+    fn take_unreachable(&self) -> Result<Vec<SocketAddr>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the local address of this transport.
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// Sets how long receiving a datagram may block, or blocks indefinitely if `None`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+    /// Returns the current time. Simulated transports may run on a virtual clock instead of the
+    /// system's.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Returns the current time in microseconds, as used for packet timestamps. Only differences
+    /// between timestamps are meaningful, and they wrap around.
+    fn now_microseconds(&self) -> u32 {
+        util::now_microseconds()
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        UdpSocket::peek_from(self, buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_batch(&self, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize> {
+        mmsg::send_batch(self, bufs, addr)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&self, bufs: &mut [&mut [u8]], received: &mut [(usize, SocketAddr)])
+                  -> Result<usize> {
+        mmsg::recv_batch(self, bufs, received)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn take_unreachable(&self) -> Result<Vec<SocketAddr>> {
+        take_unreachable(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+/// Datagrams and their source addresses, in the order they were sent.
+type Datagrams = VecDeque<(Vec<u8>, SocketAddr)>;
+
+/// Datagrams in transit towards an in-memory endpoint (e.g., one end of a `MemoryTransport`
+/// pair), waited for by its receiver.
+pub(crate) struct Queue {
+    datagrams: Mutex<Datagrams>,
+    ready: Condvar,
+}
+
+impl Queue {
+    pub(crate) fn new() -> Queue {
+        Queue { datagrams: Mutex::new(VecDeque::new()), ready: Condvar::new() }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Datagrams> {
+        self.datagrams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the number of datagrams waiting to be received.
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Adds a datagram from `src` to the queue, waking up the receiver.
+    pub(crate) fn push(&self, datagram: Vec<u8>, src: SocketAddr) {
+        self.lock().push_back((datagram, src));
+        self.ready.notify_all();
+    }
+
+    /// Waits up to `timeout` for a datagram, then copies it into `buf`, removing it from the
+    /// queue if `remove`.
+    pub(crate) fn receive(&self, buf: &mut [u8], remove: bool, timeout: Option<Duration>)
+                          -> Result<(usize, SocketAddr)> {
+        let mut datagrams = try!(self.wait(timeout));
+        let (len, src) = {
+            let &(ref data, src) = datagrams.front().unwrap();
+            copy_datagram(data, src, buf)
+        };
+        if remove {
+            datagrams.pop_front();
+        }
+
+        Ok((len, src))
+    }
+
+    /// Waits up to `timeout` for a datagram, then receives up to one datagram per buffer, like
+    /// `Transport::recv_batch`.
+    pub(crate) fn receive_batch(&self, bufs: &mut [&mut [u8]],
+                                received: &mut [(usize, SocketAddr)], timeout: Option<Duration>)
+                                -> Result<usize> {
+        if bufs.is_empty() {
+            return Ok(0);
+        }
+        let mut datagrams = try!(self.wait(timeout));
+        let mut count = 0;
+        while count < bufs.len() {
+            match datagrams.pop_front() {
+                Some((data, src)) => received[count] = copy_datagram(&data, src, bufs[count]),
+                None => break,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Waits until a datagram arrives or the timeout expires, returning the queue.
+    fn wait(&self, timeout: Option<Duration>)
+            -> Result<MutexGuard<'_, Datagrams>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut datagrams = self.lock();
+
+        while datagrams.is_empty() {
+            datagrams = match deadline {
+                None => self.ready.wait(datagrams).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::new(ErrorKind::WouldBlock, "No datagram available"));
+                    }
+                    self.ready.wait_timeout(datagrams, deadline - now)
+                        .map(|(guard, _)| guard)
+                        .unwrap_or_else(|e| e.into_inner().0)
+                }
+            };
+        }
+
+        Ok(datagrams)
+    }
+}
+
+/// One end of an in-memory datagram link, mostly useful for testing.
+///
+/// Datagrams sent to the other end's address are delivered in order and are never lost;
+/// datagrams sent anywhere else are silently dropped, like UDP datagrams sent to an unreachable
+/// host.
+///
+/// # Examples
+///
+/// ```
+/// use utp::{MemoryTransport, Transport};
+///
+/// let a = "127.0.0.1:1000".parse().unwrap();
+/// let b = "127.0.0.1:2000".parse().unwrap();
+/// let (left, right) = MemoryTransport::pair(a, b);
+///
+/// left.send_to(b"hello", b).unwrap();
+/// let mut buf = [0; 5];
+/// assert_eq!(right.recv_from(&mut buf).unwrap(), (5, a));
+/// ```
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    incoming: Arc<Queue>,
+    outgoing: Arc<Queue>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryTransport {
+    /// Creates a pair of connected transports, with the given local addresses.
+    pub fn pair(a: SocketAddr, b: SocketAddr) -> (MemoryTransport, MemoryTransport) {
+        let (to_a, to_b) = (Arc::new(Queue::new()), Arc::new(Queue::new()));
+        let left = MemoryTransport {
+            addr: a,
+            peer: b,
+            incoming: to_a.clone(),
+            outgoing: to_b.clone(),
+            read_timeout: Mutex::new(None),
+        };
+        let right = MemoryTransport {
+            addr: b,
+            peer: a,
+            incoming: to_b,
+            outgoing: to_a,
+            read_timeout: Mutex::new(None),
+        };
+        (left, right)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Copies a datagram into `buf`, returning its length and source address.
+fn copy_datagram(data: &[u8], src: SocketAddr, buf: &mut [u8]) -> (usize, SocketAddr) {
+    // Like UDP, excess bytes are discarded if the buffer is too small
+    let len = ::std::cmp::min(buf.len(), data.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    (len, src)
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        if addr == self.peer {
+            self.outgoing.push(buf.to_vec(), self.addr);
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.incoming.receive(buf, true, self.read_timeout())
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.incoming.receive(buf, false, self.read_timeout())
+    }
+
+    fn recv_batch(&self, bufs: &mut [&mut [u8]], received: &mut [(usize, SocketAddr)])
+                  -> Result<usize> {
+        self.incoming.receive_batch(bufs, received, self.read_timeout())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Zero timeout"));
+        }
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+}
+
+/// Largest number of datagrams in a `RecvBatch`, whose buffers are handed to the transport from
+/// the stack.
+const MAX_RECV_BATCH: usize = 16;
+
+/// Datagrams received together with `Transport::recv_batch`, handed out one at a time.
+pub(crate) struct RecvBatch {
+    /// Room for every datagram, back to back
+    buf: Vec<u8>,
+    /// Largest datagram received
+    size: usize,
+    /// Largest number of datagrams received at once
+    capacity: usize,
+    /// Length and source address of each datagram received
+    received: Vec<(usize, SocketAddr)>,
+    /// Number of datagrams received
+    len: usize,
+    /// Index of the next datagram to hand out
+    next: usize,
+}
+
+impl RecvBatch {
+    /// Creates an empty batch of up to `capacity` datagrams (at most `MAX_RECV_BATCH`) of up to
+    /// `size` bytes. Memory is only allocated when the first batch is received.
+    pub(crate) fn new(capacity: usize, size: usize) -> RecvBatch {
+        RecvBatch {
+            buf: Vec::new(),
+            size,
+            capacity: ::std::cmp::min(capacity, MAX_RECV_BATCH),
+            received: Vec::new(),
+            len: 0,
+            next: 0,
+        }
+    }
+
+    /// Returns whether every datagram received was handed out.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.next == self.len
+    }
+
+    /// Copies the next datagram into `buf`, returning its length and source address, or `None` if
+    /// there's none left.
+    pub(crate) fn pop_into(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (len, src) = self.received[self.next];
+        let start = self.next * self.size;
+        self.next += 1;
+        Some(copy_datagram(&self.buf[start..start + len], src, buf))
+    }
+
+    /// Waits for the next datagrams, replacing those left in the batch.
+    pub(crate) fn fill(&mut self, transport: &dyn Transport) -> Result<()> {
+        if self.buf.is_empty() {
+            self.buf = vec![0; self.capacity * self.size];
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            self.received = vec![(0, unspecified); self.capacity];
+        }
+        self.len = 0;
+        self.next = 0;
+        let mut chunks = self.buf.chunks_mut(self.size);
+        let mut bufs: [&mut [u8]; MAX_RECV_BATCH] =
+            array::from_fn(|_| chunks.next().unwrap_or(&mut []));
+        self.len = try!(transport.recv_batch(&mut bufs[..self.capacity], &mut self.received));
+        Ok(())
+    }
+}
+
+/// Binds a UDP socket to the given port on every IPv6 and IPv4 address, receiving datagrams of
+/// both families. IPv4 addresses are seen as IPv4-mapped IPv6 addresses (e.g., `::ffff:192.0.2.1`).
+#[cfg(target_os = "linux")]
+pub(crate) fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+    use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn6};
+
+    let fd = try!(socket::socket(AddressFamily::Inet6, SockType::Datagram,
+                                 SockFlag::SOCK_CLOEXEC, None));
+    // Dual-stack sockets may be disabled by default (see the `net.ipv6.bindv6only` sysctl)
+    try!(socket::setsockopt(&fd, sockopt::Ipv6V6Only, &false));
+    let addr = SockaddrIn6::from(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0));
+    try!(socket::bind(fd.as_raw_fd(), &addr));
+    Ok(UdpSocket::from(fd))
+}
+
+/// Binds a UDP socket to the given port on every IPv6 and IPv4 address, receiving datagrams of
+/// both families if the system's IPv6 sockets accept IPv4 by default.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn bind_dual_stack(port: u16) -> Result<UdpSocket> {
+    UdpSocket::bind((::std::net::Ipv6Addr::UNSPECIFIED, port))
+}
+
+/// Makes a UDP socket report ICMP errors (e.g., port or host unreachable) about the datagrams it
+/// sends, as an error from its next `recv_from` or `send_to` call, even though it isn't connected.
+///
+/// The errors are also queued on the socket, telling which destination each one is about, and
+/// must be taken with `take_unreachable` to keep the queue from filling up.
+#[cfg(target_os = "linux")]
+pub(crate) fn report_unreachable(udp: &UdpSocket) -> Result<()> {
+    use nix::sys::socket::{self, sockopt};
+
+    match try!(udp.local_addr()) {
+        SocketAddr::V4(_) => try!(socket::setsockopt(udp, sockopt::Ipv4RecvErr, &true)),
+        SocketAddr::V6(_) => try!(socket::setsockopt(udp, sockopt::Ipv6RecvErr, &true)),
+    }
+    Ok(())
+}
+
+/// Makes a UDP socket report ICMP errors about the datagrams it sends, which only Linux supports
+/// for sockets that aren't connected.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn report_unreachable(_udp: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+/// Takes every error queued on a UDP socket set up by `report_unreachable`, returning the
+/// destinations of the datagrams that the network reported as unreachable.
+#[cfg(target_os = "linux")]
+fn take_unreachable(udp: &UdpSocket) -> Result<Vec<SocketAddr>> {
+    use std::io::IoSliceMut;
+    use std::os::unix::io::AsRawFd;
+    use nix::errno::Errno;
+    use nix::libc;
+    use nix::sys::socket::{self, ControlMessageOwned, MsgFlags, SockaddrStorage};
+    use error;
+
+    let mut unreachable = Vec::new();
+    // Only the destination and the error are needed, not the datagram itself
+    let mut buf = [0; 1];
+    let mut cmsg = nix::cmsg_space!(libc::sock_extended_err, libc::sockaddr_in6);
+    loop {
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let flags = MsgFlags::MSG_ERRQUEUE | MsgFlags::MSG_DONTWAIT;
+        let message = match socket::recvmsg::<SockaddrStorage>(udp.as_raw_fd(), &mut iov,
+                                                               Some(&mut cmsg), flags) {
+            Ok(message) => message,
+            Err(Errno::EAGAIN) => return Ok(unreachable),
+            Err(e) => return Err(Error::from(e)),
+        };
+        let errno = try!(message.cmsgs().map_err(Error::from)).filter_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4RecvErr(err, _) |
+            ControlMessageOwned::Ipv6RecvErr(err, _) => Some(err.ee_errno as i32),
+            _ => None,
+        }).next();
+        let dst = message.address.as_ref().and_then(mmsg::socket_addr);
+        if let (Some(errno), Some(dst)) = (errno, dst) {
+            if error::is_unreachable(&Error::from_raw_os_error(errno)) {
+                unreachable.push(dst);
+            }
+        }
+    }
+}
+
+/// Batched sending and receiving of UDP datagrams, with a single `sendmmsg` or `recvmmsg` system
+/// call per batch.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::array;
+    use std::cell::RefCell;
+    use std::cmp::min;
+    use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+    use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use nix::sys::socket::{self, MsgFlags, MultiHeaders, SockaddrStorage};
+
+    /// Largest number of datagrams sent or received with a single system call.
+    const MAX_BATCH: usize = 16;
+
+    /// Message headers last used to receive from the socket with the given file descriptor.
+    struct RecvHeaders {
+        fd: RawFd,
+        /// Whether IPv4 addresses were received, which leaves too little room in the headers for
+        /// IPv6 ones (the kernel shrinks the room for each address to the length of the last one)
+        ipv4: bool,
+        headers: MultiHeaders<SockaddrStorage>,
+    }
+
+    thread_local! {
+        // Message headers are allocated once per thread instead of once per system call. They
+        // hold raw pointers, so they can't be kept by the sockets, which must be `Send`.
+        static SEND_HEADERS: RefCell<Option<MultiHeaders<SockaddrStorage>>> =
+            const { RefCell::new(None) };
+        static RECV_HEADERS: RefCell<Option<RecvHeaders>> = const { RefCell::new(None) };
+    }
+
+    pub fn send_batch(udp: &UdpSocket, bufs: &[&[u8]], addr: SocketAddr) -> Result<usize> {
+        let count = min(bufs.len(), MAX_BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+        let iovs: [[IoSlice; 1]; MAX_BATCH] =
+            array::from_fn(|i| [IoSlice::new(bufs.get(i).cloned().unwrap_or(&[]))]);
+        let addrs = [Some(SockaddrStorage::from(addr)); MAX_BATCH];
+        SEND_HEADERS.with(|headers| {
+            let mut headers = headers.borrow_mut();
+            let headers = headers.get_or_insert_with(|| MultiHeaders::preallocate(MAX_BATCH, None));
+            let sent = try!(socket::sendmmsg(udp.as_raw_fd(), headers, &iovs[..count],
+                                             &addrs[..count], [], MsgFlags::empty()));
+            Ok(sent.count())
+        })
+    }
+
+    pub fn recv_batch(udp: &UdpSocket, bufs: &mut [&mut [u8]],
+                      received: &mut [(usize, SocketAddr)]) -> Result<usize> {
+        let count = min(bufs.len(), MAX_BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+        let fd = udp.as_raw_fd();
+        RECV_HEADERS.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if cache.as_ref().map(|cached| cached.fd != fd).unwrap_or(false) {
+                *cache = None;
+            }
+            let cached = cache.get_or_insert_with(|| RecvHeaders {
+                fd,
+                ipv4: false,
+                headers: MultiHeaders::preallocate(MAX_BATCH, None),
+            });
+
+            let mut bufs = bufs.iter_mut();
+            let mut iovs: [[IoSliceMut; 1]; MAX_BATCH] = array::from_fn(|_| {
+                [IoSliceMut::new(bufs.next().map(|buf| &mut buf[..]).unwrap_or(&mut []))]
+            });
+            // Only the first datagram is waited for (up to the read timeout)
+            let messages = try!(socket::recvmmsg(fd, &mut cached.headers, iovs[..count].iter_mut(),
+                                                 MsgFlags::MSG_WAITFORONE, None));
+            let mut len = 0;
+            for message in messages {
+                let src = message.address.as_ref().and_then(socket_addr);
+                received[len] = (message.bytes, src.unwrap_or_else(|| ([0, 0, 0, 0], 0).into()));
+                len += 1;
+            }
+
+            let ipv6 = received[..len].iter().any(|&(_, src)| src.is_ipv6());
+            if ipv6 && cached.ipv4 {
+                // The file descriptor was reused by an IPv6 socket, whose source addresses may
+                // have been cut short, so the datagrams are dropped as if they were lost
+                *cache = None;
+                return Err(Error::new(ErrorKind::WouldBlock, "Datagrams dropped"));
+            }
+            if len > 0 && !ipv6 {
+                cached.ipv4 = true;
+            }
+            Ok(len)
+        })
+    }
+
+    pub fn socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+        match addr.as_sockaddr_in() {
+            Some(addr) => Some(SocketAddr::V4(SocketAddrV4::from(*addr))),
+            None => addr.as_sockaddr_in6().map(|addr| SocketAddr::V6(SocketAddrV6::from(*addr))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+    use super::Transport;
+
+    #[test]
+    fn test_udp_batches() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let datagrams: [&[u8]; 3] = [b"one", b"two", b"three"];
+        let dst = receiver.local_addr().unwrap();
+        assert_eq!(Transport::send_batch(&sender, &datagrams, dst).unwrap(), 3);
+
+        // Datagrams are received in order, possibly over several batches
+        let mut storage = [[0; 8]; 4];
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|buf| &mut buf[..]).collect();
+            let mut meta = [(0, SocketAddr::from(([0, 0, 0, 0], 0))); 4];
+            let count = Transport::recv_batch(&receiver, &mut bufs, &mut meta).unwrap();
+            assert!(count > 0);
+            for (buf, &(len, src)) in bufs.iter().zip(meta[..count].iter()) {
+                assert_eq!(src, sender.local_addr().unwrap());
+                received.push(buf[..len].to_vec());
+            }
+        }
+        assert_eq!(received, datagrams);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_udp_batches_across_address_families() {
+        use std::io::{self, ErrorKind};
+        use std::os::unix::io::AsRawFd;
+
+        fn recv(receiver: &UdpSocket) -> io::Result<(Vec<u8>, SocketAddr)> {
+            let mut buf = [0; 8];
+            let mut meta = [(0, SocketAddr::from(([0, 0, 0, 0], 0)))];
+            try!(Transport::recv_batch(receiver, &mut [&mut buf[..]], &mut meta));
+            Ok((buf[..meta[0].0].to_vec(), meta[0].1))
+        }
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sender.send_to(b"four", receiver.local_addr().unwrap()).unwrap();
+        assert_eq!(recv(&receiver).unwrap(), (b"four".to_vec(), sender.local_addr().unwrap()));
+        let fd = receiver.as_raw_fd();
+        drop(receiver);
+
+        // An IPv6 socket may get the file descriptor the IPv4 one had, but never IPv6 source
+        // addresses cut short to the length of IPv4 ones
+        let receiver = UdpSocket::bind("[::1]:0").unwrap();
+        let sender = UdpSocket::bind("[::1]:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sender.send_to(b"six", receiver.local_addr().unwrap()).unwrap();
+        sender.send_to(b"six", receiver.local_addr().unwrap()).unwrap();
+        if receiver.as_raw_fd() == fd {
+            assert_eq!(recv(&receiver).unwrap_err().kind(), ErrorKind::WouldBlock);
+        }
+        assert_eq!(recv(&receiver).unwrap(), (b"six".to_vec(), sender.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn test_udp_send_batch_limit() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let datagrams = [&b"datagram"[..]; 40];
+        let sent = Transport::send_batch(&sender, &datagrams, receiver.local_addr().unwrap());
+        assert!(sent.unwrap() > 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_udp_unreachable_destinations() {
+        use std::io::ErrorKind;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        super::report_unreachable(&socket).unwrap();
+        assert!(Transport::take_unreachable(&socket).unwrap().is_empty());
+
+        // Nothing listens on the destination any more, so it's reported as unreachable
+        let dst = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        socket.send_to(b"hello", dst).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let e = socket.recv_from(&mut [0; 8]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(Transport::take_unreachable(&socket).unwrap(), vec![dst]);
+        assert!(Transport::take_unreachable(&socket).unwrap().is_empty());
+    }
+}
@@ -2,7 +2,8 @@ extern crate utp;
 
 use std::thread;
 use utp::UtpStream;
-use std::io::{Read, Write};
+use std::io::{BufRead, IoSlice, IoSliceMut, Read, Write};
+use std::net::Shutdown;
 
 macro_rules! iotry {
     ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{}", e) })
@@ -103,6 +104,32 @@ fn test_stream_large_data() {
     assert_eq!(received, data);
 }
 
+#[test]
+fn test_stream_small_send_buffer() {
+    const LEN: usize = 1024 * 100;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+
+    let d = data.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        client.set_send_buffer_size(4096);
+        assert_eq!(client.send_buffer_size(), 4096);
+
+        // Writes are partial, but the whole data gets through as acknowledgements free up space
+        let written = iotry!(client.write(&d[..]));
+        assert!(written <= 4096);
+        iotry!(client.write_all(&d[written..]));
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received, data);
+}
+
 #[test]
 fn test_stream_successive_reads() {
     const LEN: usize = 1024;
@@ -128,6 +155,251 @@ fn test_stream_successive_reads() {
     assert_eq!(server.read(&mut received).unwrap(), 0);
 }
 
+#[test]
+fn test_stream_buf_read() {
+    // Lines spanning several packets, some of them split across packets
+    let lines: Vec<String> = (0..1000).map(|idx| format!("line {}", idx)).collect();
+    let text = lines.join("\n");
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(text.as_bytes()));
+        iotry!(client.close());
+    });
+
+    // Data is only consumed as far as it's used
+    assert_eq!(&iotry!(server.fill_buf())[..4], b"line");
+    server.consume(2);
+    let mut first = String::new();
+    iotry!(server.read_line(&mut first));
+    assert_eq!(first, "ne 0\n");
+
+    let received: Vec<String> = server.lines().map(|line| iotry!(line)).collect();
+    assert_eq!(received, &lines[1..]);
+}
+
+#[test]
+fn test_stream_vectored_io() {
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        let bufs = [IoSlice::new(b"header:"), IoSlice::new(&[]), IoSlice::new(b"body")];
+        assert_eq!(iotry!(client.write_vectored(&bufs)), 11);
+        iotry!(client.close());
+    });
+
+    // Data is spread over the buffers in order
+    let mut header = [0; 7];
+    let mut body = [0; 16];
+    let mut read = 0;
+    while read < 11 {
+        let len = {
+            let (header, body) = if read < 7 {
+                (&mut header[read..], &mut body[..])
+            } else {
+                (&mut header[7..], &mut body[read - 7..])
+            };
+            iotry!(server.read_vectored(&mut [IoSliceMut::new(header), IoSliceMut::new(body)]))
+        };
+        assert!(len > 0);
+        read += len;
+    }
+    assert_eq!(&header, b"header:");
+    assert_eq!(&body[..4], b"body");
+}
+
+#[test]
+fn test_stream_half_close() {
+    const LEN: usize = 1024 * 10;
+    let request: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let response: Vec<u8> = request.iter().rev().copied().collect();
+
+    let (req, resp) = (request.clone(), response.clone());
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write(&req[..]));
+        iotry!(client.shutdown(Shutdown::Write));
+
+        // No more writing, but the response still arrives
+        assert!(client.write(&[0]).is_err());
+        let mut received = Vec::with_capacity(LEN);
+        iotry!(client.read_to_end(&mut received));
+        assert_eq!(received, resp);
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received, request);
+
+    iotry!(server.write(&response[..]));
+    iotry!(server.close());
+    child.join().unwrap();
+}
+
+#[test]
+fn test_stream_messages() {
+    // Messages of every size around packet boundaries, and larger than a packet
+    let messages: Vec<Vec<u8>> = (0..40).map(|len| vec![len as u8; len * 97]).collect();
+    let expected = messages.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        for message in &messages {
+            iotry!(client.send_message(&message[..]));
+        }
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::new();
+    while let Some(message) = iotry!(server.recv_message()) {
+        received.push(message);
+    }
+    assert_eq!(received, expected);
+    iotry!(server.close());
+    child.join().unwrap();
+}
+
+#[test]
+fn test_stream_message_too_large() {
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    server.set_max_message_size(1000);
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        client.set_max_message_size(1000);
+        match client.send_message(&[0; 1001]) {
+            Err(utp::Error::MessageTooLarge(1001)) => (),
+            x => panic!("Expected Error::MessageTooLarge, got {:?}", x),
+        }
+
+        // The receiving end enforces its own limit
+        client.set_max_message_size(2000);
+        iotry!(client.send_message(&[0; 1000]));
+        iotry!(client.send_message(&[0; 1001]));
+    });
+
+    let mut message = Vec::new();
+    assert_eq!(iotry!(server.recv_message_into(&mut message)), Some(1000));
+    match server.recv_message_into(&mut message) {
+        Err(utp::Error::MessageTooLarge(1001)) => (),
+        x => panic!("Expected Error::MessageTooLarge, got {:?}", x),
+    }
+    child.join().unwrap();
+}
+
+#[test]
+fn test_stream_truncated_message() {
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        // A 5000-byte message cut short after 3000 bytes, spanning several packets
+        iotry!(client.write_all(&5000u32.to_be_bytes()));
+        iotry!(client.write_all(&[7; 3000]));
+        iotry!(client.close());
+    });
+
+    let mut message = Vec::new();
+    match server.recv_message_into(&mut message) {
+        Err(utp::Error::TruncatedMessage) => (),
+        x => panic!("Expected Error::TruncatedMessage, got {:?}", x),
+    }
+    iotry!(server.close());
+    child.join().unwrap();
+}
+
+#[test]
+fn test_stream_split() {
+    const LEN: usize = 1024 * 100;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let expected = data.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let client = iotry!(UtpStream::connect(server_addr));
+        let (mut reader, mut writer) = iotry!(client.split());
+
+        let echo = thread::spawn(move || {
+            let mut received = Vec::with_capacity(LEN);
+            iotry!(reader.read_to_end(&mut received));
+            received
+        });
+
+        iotry!(writer.write_all(&data[..]));
+        iotry!(writer.shutdown());
+        assert_eq!(echo.join().unwrap(), expected);
+    });
+
+    // Echo everything back while the client is still sending
+    let mut buf = [0; 4096];
+    loop {
+        let read = iotry!(server.read(&mut buf));
+        if read == 0 {
+            break;
+        }
+        iotry!(server.write_all(&buf[..read]));
+    }
+    iotry!(server.close());
+    child.join().unwrap();
+}
+
+#[test]
+fn test_stream_split_read_during_blocked_write() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // More than both the remote peer's receive window and our send buffer hold
+    const LEN: usize = 4 * 1024 * 1024;
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    let (read_tx, read_rx) = mpsc::channel();
+
+    let child = thread::spawn(move || {
+        let client = iotry!(UtpStream::connect(server_addr));
+        let (mut reader, mut writer) = iotry!(client.split());
+
+        let write = thread::spawn(move || {
+            iotry!(writer.write_all(&vec![1; LEN]));
+            iotry!(writer.shutdown());
+        });
+
+        let mut buf = [0; 5];
+        iotry!(reader.read_exact(&mut buf));
+        read_tx.send(buf).unwrap();
+        write.join().unwrap();
+    });
+
+    // Once the client's send buffer is full, reply without acknowledging anything new, so that
+    // the write stays blocked until the server reads again
+    iotry!(server.read_exact(&mut [0; 1]));
+    thread::sleep(Duration::from_millis(200));
+    iotry!(server.write_all(b"hello"));
+    match read_rx.recv_timeout(Duration::from_secs(10)) {
+        Ok(buf) => assert_eq!(&buf, b"hello"),
+        Err(_) => panic!("The read half was blocked by the write half"),
+    }
+
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received.len(), LEN - 1);
+    iotry!(server.close());
+    child.join().unwrap();
+}
+
 #[test]
 fn test_local_addr() {
     use std::net::ToSocketAddrs;